@@ -0,0 +1,111 @@
+//! Exponential backoff with jitter for retrying connections.
+
+/// Delay between retries that doubles after each failed attempt, from
+/// `min_millis` up to `max_millis`. Half of the delay is random (jitter) so
+/// that many devices failing at the same time don't retry all together.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min_millis: u64,
+    max_millis: u64,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub const fn new(min_millis: u64, max_millis: u64) -> Self {
+        Backoff {
+            min_millis,
+            max_millis,
+            attempts: 0,
+        }
+    }
+
+    /// Number of failed attempts since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Returns the time to wait before the next attempt and counts a failed
+    /// attempt. `random` is any random number, used for the jitter.
+    pub fn next_delay(&mut self, random: u32) -> u64 {
+        let delay = self
+            .min_millis
+            .saturating_mul(1u64 << self.attempts.min(32))
+            .min(self.max_millis);
+        self.attempts = self.attempts.saturating_add(1);
+        let half = delay / 2;
+        delay - half + (random as u64) % (half + 1)
+    }
+
+    /// Starts again from the minimum delay, after a successful attempt
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Random numbers at both ends of the jitter and in between
+    const RANDOMS: [u32; 5] = [0, 1, 0x1234_5678, u32::MAX - 1, u32::MAX];
+
+    /// Delay of the next attempt, without counting it
+    fn peek(backoff: &Backoff, random: u32) -> u64 {
+        backoff.clone().next_delay(random)
+    }
+
+    #[test]
+    fn doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::new(1_000, 10_000);
+        let expected = [1_000, 2_000, 4_000, 8_000, 10_000, 10_000];
+        for (attempt, delay) in expected.into_iter().enumerate() {
+            assert_eq!(backoff.attempts(), attempt as u32);
+            // the whole delay with the biggest jitter
+            assert_eq!(peek(&backoff, delay as u32 / 2), delay);
+            assert_eq!(backoff.next_delay(0), delay - delay / 2);
+        }
+    }
+
+    #[test]
+    fn jitter_within_half_of_the_delay() {
+        let mut backoff = Backoff::new(1_000, 60_000);
+        for attempt in 0..10 {
+            let delay = (1_000 << attempt).min(60_000);
+            for random in RANDOMS {
+                let jittered = peek(&backoff, random);
+                assert!(
+                    (delay / 2..=delay).contains(&jittered),
+                    "{} not in {}..={}",
+                    jittered,
+                    delay / 2,
+                    delay
+                );
+            }
+            backoff.next_delay(0);
+        }
+    }
+
+    #[test]
+    fn many_attempts_dont_overflow() {
+        let mut backoff = Backoff::new(u64::MAX / 4, u64::MAX);
+        for _ in 0..100 {
+            for random in RANDOMS {
+                assert!(peek(&backoff, random) >= u64::MAX / 4 / 2);
+            }
+            backoff.next_delay(u32::MAX);
+        }
+        assert_eq!(backoff.attempts(), 100);
+    }
+
+    #[test]
+    fn reset_after_success() {
+        let mut backoff = Backoff::new(500, 8_000);
+        for _ in 0..3 {
+            backoff.next_delay(0);
+        }
+        assert_eq!(backoff.attempts(), 3);
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(250), 500);
+    }
+}
@@ -0,0 +1,232 @@
+//! Bounded buffer of timestamped readings for store-and-forward: it fills
+//! while the connection with the server is down and is drained in order once
+//! connected again.
+
+use heapless::Deque;
+
+/// What to do with a new reading when the buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest reading to make room for the new one
+    DropOldest,
+    /// Keep the buffer as it is and discard the new reading
+    DropNewest,
+    /// Discard every other reading in the buffer and, from then on, store
+    /// only one of every two new readings. The buffer covers twice the time
+    /// at half the resolution each time it fills.
+    Downsample,
+}
+
+/// Reading with the time it was taken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stamped<T> {
+    pub timestamp_millis: u64,
+    pub value: T,
+}
+
+/// Ring buffer with room for N readings
+pub struct ReadingBuffer<T, const N: usize> {
+    readings: Deque<Stamped<T>, N>,
+    policy: OverflowPolicy,
+    // With Downsample, only one of every `stride` readings is stored
+    stride: u32,
+    skipped: u32,
+    // Readings discarded since the buffer was created
+    dropped: u32,
+}
+
+impl<T, const N: usize> ReadingBuffer<T, N> {
+    pub const fn new(policy: OverflowPolicy) -> Self {
+        ReadingBuffer {
+            readings: Deque::new(),
+            policy,
+            stride: 1,
+            skipped: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Stores a reading, applying the overflow policy if the buffer is full.
+    /// Returns false if the reading was discarded.
+    pub fn push(&mut self, timestamp_millis: u64, value: T) -> bool {
+        let reading = Stamped {
+            timestamp_millis,
+            value,
+        };
+
+        if self.skipped + 1 < self.stride {
+            self.skipped += 1;
+            self.dropped += 1;
+            return false;
+        }
+        self.skipped = 0;
+
+        if self.readings.is_full() {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    self.readings.pop_front();
+                    self.dropped += 1;
+                }
+                OverflowPolicy::DropNewest => {
+                    self.dropped += 1;
+                    return false;
+                }
+                OverflowPolicy::Downsample => self.downsample(),
+            }
+        }
+        self.readings.push_back(reading).is_ok()
+    }
+
+    /// Discards the readings in even positions (oldest first) and doubles
+    /// the stride of the new readings
+    fn downsample(&mut self) {
+        let len = self.readings.len();
+        for i in 0..len {
+            if let Some(reading) = self.readings.pop_front() {
+                if i % 2 == 1 {
+                    self.readings.push_back(reading).ok();
+                } else {
+                    self.dropped += 1;
+                }
+            }
+        }
+        self.stride = self.stride.saturating_mul(2);
+    }
+
+    /// Oldest reading, the next one to be sent
+    pub fn front(&self) -> Option<&Stamped<T>> {
+        self.readings.front()
+    }
+
+    /// Removes the oldest reading, once it has been sent.
+    /// When the buffer becomes empty the downsampling is restarted.
+    pub fn pop(&mut self) -> Option<Stamped<T>> {
+        let reading = self.readings.pop_front();
+        if self.readings.is_empty() {
+            self.stride = 1;
+            self.skipped = 0;
+        }
+        reading
+    }
+
+    /// Readings from the oldest to the newest
+    pub fn iter(&self) -> impl Iterator<Item = &Stamped<T>> {
+        self.readings.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.readings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.readings.is_empty()
+    }
+
+    /// Number of readings discarded by the overflow policy
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Buffer = ReadingBuffer<u32, 4>;
+
+    /// Pushes the readings 0..n, taken at the time of their value
+    fn filled(policy: OverflowPolicy, n: u32) -> Buffer {
+        let mut buffer = Buffer::new(policy);
+        for value in 0..n {
+            buffer.push(value as u64, value);
+        }
+        buffer
+    }
+
+    fn values(buffer: &Buffer) -> heapless::Vec<u32, 4> {
+        buffer.iter().map(|reading| reading.value).collect()
+    }
+
+    #[test]
+    fn drained_in_order() {
+        let mut buffer = filled(OverflowPolicy::DropOldest, 3);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(
+            buffer.front(),
+            Some(&Stamped {
+                timestamp_millis: 0,
+                value: 0
+            })
+        );
+        for value in 0..3 {
+            assert_eq!(buffer.pop().map(|reading| reading.value), Some(value));
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.dropped(), 0);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut buffer = filled(OverflowPolicy::DropOldest, 4);
+        assert!(buffer.push(4, 4));
+        assert!(buffer.push(5, 5));
+        assert_eq!(values(&buffer), [2, 3, 4, 5]);
+        assert_eq!(buffer.dropped(), 2);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let mut buffer = filled(OverflowPolicy::DropNewest, 4);
+        assert!(!buffer.push(4, 4));
+        assert!(!buffer.push(5, 5));
+        assert_eq!(values(&buffer), [0, 1, 2, 3]);
+        assert_eq!(buffer.dropped(), 2);
+        // room again once one is sent
+        buffer.pop();
+        assert!(buffer.push(6, 6));
+        assert_eq!(values(&buffer), [1, 2, 3, 6]);
+    }
+
+    #[test]
+    fn downsample() {
+        let mut buffer = filled(OverflowPolicy::Downsample, 4);
+        // full: half of the readings are discarded, one of every two kept
+        assert!(buffer.push(4, 4));
+        assert_eq!(values(&buffer), [1, 3, 4]);
+        assert_eq!(buffer.dropped(), 2);
+        assert!(!buffer.push(5, 5));
+        assert!(buffer.push(6, 6));
+        assert_eq!(values(&buffer), [1, 3, 4, 6]);
+        assert_eq!(buffer.dropped(), 3);
+
+        // full again: one of every four
+        assert!(!buffer.push(7, 7));
+        assert!(buffer.push(8, 8));
+        assert_eq!(values(&buffer), [3, 6, 8]);
+        assert_eq!(buffer.dropped(), 6);
+        for value in 9..12 {
+            assert!(!buffer.push(value as u64, value));
+        }
+        assert!(buffer.push(12, 12));
+        assert_eq!(values(&buffer), [3, 6, 8, 12]);
+        assert_eq!(buffer.dropped(), 9);
+    }
+
+    #[test]
+    fn downsample_restarts_once_drained() {
+        let mut buffer = filled(OverflowPolicy::Downsample, 5);
+        assert_eq!(values(&buffer), [1, 3, 4]);
+        // partly sent: still one of every two
+        buffer.pop();
+        assert!(!buffer.push(5, 5));
+        assert!(buffer.push(6, 6));
+        assert_eq!(values(&buffer), [3, 4, 6]);
+
+        while buffer.pop().is_some() {}
+        for value in 7..11 {
+            assert!(buffer.push(value as u64, value));
+        }
+        assert_eq!(values(&buffer), [7, 8, 9, 10]);
+    }
+}
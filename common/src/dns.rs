@@ -0,0 +1,454 @@
+//! Minimal DNS client: the query for the IPv4 address (A record) of a name,
+//! the reading of the answer with its TTL, and a cache of the address that
+//! keeps it while the TTL lasts. Literal IPv4 addresses are accepted
+//! without asking the server.
+
+/// Port of the DNS servers
+pub const DNS_PORT: u16 = 53;
+/// Maximum size of a DNS message over UDP
+pub const MAX_MESSAGE_LEN: usize = 512;
+
+const HEADER_LEN: usize = 12;
+/// Flags of the query: recursion desired
+const FLAGS_RD: u16 = 0x0100;
+/// Flag of the responses
+const FLAG_QR: u16 = 0x8000;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+/// Maximum length of a name and of each of its labels
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    /// The name is not a valid host name
+    InvalidName,
+    BufferTooSmall,
+    /// The response is truncated or malformed
+    Malformed,
+    /// The response is not for the query sent
+    UnexpectedId,
+    /// The server answered with an error (RCODE): 3 is a name that doesn't
+    /// exist
+    Server(u8),
+    /// The name has no IPv4 address
+    NoAddress,
+}
+
+/// IPv4 address of a name and the seconds it can be kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Answer {
+    pub address: [u8; 4],
+    pub ttl: u32,
+}
+
+/// Parses a literal IPv4 address: 192.168.1.10
+pub fn parse_ipv4(text: &str) -> Option<[u8; 4]> {
+    let mut address = [0u8; 4];
+    let mut parts = text.split('.');
+    for byte in address.iter_mut() {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 3 || !part.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        *byte = part.parse().ok()?;
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some(address),
+    }
+}
+
+/// Writes in `buffer` the query for the A record of `name`, returning its
+/// length. `id` identifies the response.
+pub fn encode_query(id: u16, name: &str, buffer: &mut [u8]) -> Result<usize, DnsError> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(DnsError::InvalidName);
+    }
+    // header, labels with their length, the root and type and class
+    let len = HEADER_LEN + name.len() + 2 + 4;
+    if buffer.len() < len {
+        return Err(DnsError::BufferTooSmall);
+    }
+
+    buffer[..HEADER_LEN].copy_from_slice(&[0; HEADER_LEN]);
+    buffer[0..2].copy_from_slice(&id.to_be_bytes());
+    buffer[2..4].copy_from_slice(&FLAGS_RD.to_be_bytes());
+    // one question
+    buffer[4..6].copy_from_slice(&1u16.to_be_bytes());
+
+    let mut pos = HEADER_LEN;
+    for label in name.split('.') {
+        if label.is_empty()
+            || label.len() > MAX_LABEL_LEN
+            || !label
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'-')
+        {
+            return Err(DnsError::InvalidName);
+        }
+        buffer[pos] = label.len() as u8;
+        buffer[pos + 1..pos + 1 + label.len()].copy_from_slice(label.as_bytes());
+        pos += 1 + label.len();
+    }
+    buffer[pos] = 0;
+    buffer[pos + 1..pos + 3].copy_from_slice(&TYPE_A.to_be_bytes());
+    buffer[pos + 3..pos + 5].copy_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(pos + 5)
+}
+
+/// Reads the response to the query `id`: the first IPv4 address of the
+/// answer. With aliases (CNAME) the TTL is the shortest of the chain.
+pub fn parse_response(id: u16, message: &[u8]) -> Result<Answer, DnsError> {
+    if message.len() < HEADER_LEN {
+        return Err(DnsError::Malformed);
+    }
+    let word = |pos: usize| u16::from_be_bytes([message[pos], message[pos + 1]]);
+    if word(0) != id {
+        return Err(DnsError::UnexpectedId);
+    }
+    let flags = word(2);
+    if flags & FLAG_QR == 0 {
+        return Err(DnsError::Malformed);
+    }
+    let rcode = (flags & 0x000f) as u8;
+    if rcode != 0 {
+        return Err(DnsError::Server(rcode));
+    }
+    let questions = word(4);
+    let answers = word(6);
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        // name, type and class
+        pos = skip_name(message, pos)? + 4;
+    }
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = skip_name(message, pos)?;
+        // type, class, TTL and length of the data
+        let data = pos + 10;
+        if message.len() < data {
+            return Err(DnsError::Malformed);
+        }
+        let record_type = word(pos);
+        let class = word(pos + 2);
+        let record_ttl = u32::from_be_bytes([
+            message[pos + 4],
+            message[pos + 5],
+            message[pos + 6],
+            message[pos + 7],
+        ]);
+        let len = word(pos + 8) as usize;
+        if message.len() < data + len {
+            return Err(DnsError::Malformed);
+        }
+        if class == CLASS_IN {
+            ttl = ttl.min(record_ttl);
+            if record_type == TYPE_A && len == 4 {
+                let mut address = [0u8; 4];
+                address.copy_from_slice(&message[data..data + 4]);
+                return Ok(Answer { address, ttl });
+            }
+        }
+        pos = data + len;
+    }
+    Err(DnsError::NoAddress)
+}
+
+/// Position after the name that starts at `pos`: labels ended by the root,
+/// or by a pointer to a previous name (compression)
+fn skip_name(message: &[u8], mut pos: usize) -> Result<usize, DnsError> {
+    loop {
+        let len = *message.get(pos).ok_or(DnsError::Malformed)? as usize;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xc0 == 0xc0 => {
+                return if pos + 2 <= message.len() {
+                    Ok(pos + 2)
+                } else {
+                    Err(DnsError::Malformed)
+                };
+            }
+            len if len <= MAX_LABEL_LEN => pos += 1 + len,
+            _ => return Err(DnsError::Malformed),
+        }
+    }
+}
+
+/// Address of the last name resolved, kept until its TTL expires
+#[derive(Debug, Clone, Default)]
+pub struct DnsCache {
+    entry: Option<([u8; 4], u64)>,
+}
+
+impl DnsCache {
+    pub const fn new() -> Self {
+        DnsCache { entry: None }
+    }
+
+    /// The address, if it has not expired at `now_millis`
+    pub fn get(&self, now_millis: u64) -> Option<[u8; 4]> {
+        match self.entry {
+            Some((address, expires_millis)) if now_millis < expires_millis => Some(address),
+            _ => None,
+        }
+    }
+
+    /// Keeps the address of an answer received at `now_millis`
+    pub fn insert(&mut self, answer: &Answer, now_millis: u64) {
+        let expires_millis = now_millis.saturating_add(answer.ttl as u64 * 1000);
+        self.entry = Some((answer.address, expires_millis));
+    }
+
+    /// Forgets the address, so that it is resolved again
+    pub fn clear(&mut self) {
+        self.entry = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::Vec;
+
+    type Message = Vec<u8, MAX_MESSAGE_LEN>;
+
+    const ID: u16 = 0x1234;
+    const BROKER: [u8; 4] = [192, 168, 1, 10];
+    /// Pointer to the name of the question, right after the header
+    const QUESTION_NAME: [u8; 2] = [0xc0, HEADER_LEN as u8];
+
+    /// Response with the header, the question for broker.local and the
+    /// answers already encoded
+    fn response(id: u16, rcode: u16, answers: u16, records: &[u8]) -> Message {
+        let mut message = Message::new();
+        let mut query = [0u8; MAX_MESSAGE_LEN];
+        let len = encode_query(id, "broker.local", &mut query).unwrap();
+        message.extend_from_slice(&query[..len]).unwrap();
+        let flags = FLAG_QR | FLAGS_RD | 0x0080 | rcode;
+        message[2..4].copy_from_slice(&flags.to_be_bytes());
+        message[6..8].copy_from_slice(&answers.to_be_bytes());
+        message.extend_from_slice(records).unwrap();
+        message
+    }
+
+    /// Resource record with a name, type, TTL and data
+    fn record(name: &[u8], record_type: u16, ttl: u32, data: &[u8]) -> Message {
+        let mut record = Message::new();
+        record.extend_from_slice(name).unwrap();
+        record
+            .extend_from_slice(&record_type.to_be_bytes())
+            .unwrap();
+        record.extend_from_slice(&CLASS_IN.to_be_bytes()).unwrap();
+        record.extend_from_slice(&ttl.to_be_bytes()).unwrap();
+        record
+            .extend_from_slice(&(data.len() as u16).to_be_bytes())
+            .unwrap();
+        record.extend_from_slice(data).unwrap();
+        record
+    }
+
+    #[test]
+    fn literal_addresses() {
+        assert_eq!(parse_ipv4("192.168.1.10"), Some(BROKER));
+        assert_eq!(parse_ipv4("0.0.0.0"), Some([0; 4]));
+        for text in [
+            "192.168.1",
+            "192.168.1.10.1",
+            "192.168.1.256",
+            "192.168..10",
+            "192.168.1.+1",
+            "192.168.1.0010",
+            "broker.local",
+            "",
+        ] {
+            assert_eq!(parse_ipv4(text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn query() {
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let len = encode_query(ID, "broker.local.", &mut buffer).unwrap();
+        assert_eq!(&buffer[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&buffer[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&buffer[12..len], b"\x06broker\x05local\x00\x00\x01\x00\x01");
+
+        assert_eq!(
+            encode_query(ID, "broker.local", &mut buffer[..20]),
+            Err(DnsError::BufferTooSmall)
+        );
+        for name in ["", ".", "broker..local", "bro ker.local", "broker_1.local"] {
+            assert_eq!(
+                encode_query(ID, name, &mut buffer),
+                Err(DnsError::InvalidName),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn address_with_compressed_name() {
+        let answer = record(&QUESTION_NAME, TYPE_A, 300, &BROKER);
+        let message = response(ID, 0, 1, &answer);
+        assert_eq!(
+            parse_response(ID, &message),
+            Ok(Answer {
+                address: BROKER,
+                ttl: 300
+            })
+        );
+    }
+
+    #[test]
+    fn address_with_full_name() {
+        let answer = record(b"\x06broker\x05local\x00", TYPE_A, 60, &BROKER);
+        let message = response(ID, 0, 1, &answer);
+        assert_eq!(parse_response(ID, &message).unwrap().address, BROKER);
+    }
+
+    #[test]
+    fn pointer_loops_are_not_followed() {
+        // the names are skipped without following the pointers: a pointer
+        // to itself or to a later name ends the name all the same
+        let at = |pos: usize| [0xc0 | (pos >> 8) as u8, pos as u8];
+        let question_len = response(ID, 0, 0, &[]).len();
+        let mut records = record(&at(question_len), TYPE_A, 30, &BROKER);
+        let message = response(ID, 0, 1, &records);
+        assert_eq!(parse_response(ID, &message).unwrap().address, BROKER);
+
+        records = record(&at(MAX_MESSAGE_LEN - 1), TYPE_A, 30, &BROKER);
+        let message = response(ID, 0, 1, &records);
+        assert_eq!(parse_response(ID, &message).unwrap().address, BROKER);
+
+        // labels ended by a pointer
+        records = record(b"\x03www\xc0\x0c", TYPE_A, 30, &BROKER);
+        let message = response(ID, 0, 1, &records);
+        assert_eq!(parse_response(ID, &message).unwrap().address, BROKER);
+    }
+
+    #[test]
+    fn other_records_before_the_address() {
+        // an alias, a record of another class and an A record without 4
+        // bytes are skipped; the TTL is the shortest of the alias chain
+        let mut records = record(&QUESTION_NAME, 5, 120, b"\x03www\xc0\x0c");
+        let mut chaos = record(&QUESTION_NAME, TYPE_A, 1, &[10, 0, 0, 1]);
+        chaos[4..6].copy_from_slice(&3u16.to_be_bytes());
+        records.extend_from_slice(&chaos).unwrap();
+        let short = record(&QUESTION_NAME, TYPE_A, 500, &[10, 0, 0]);
+        records.extend_from_slice(&short).unwrap();
+        let answer = record(b"\x03www\xc0\x0c", TYPE_A, 3600, &BROKER);
+        records.extend_from_slice(&answer).unwrap();
+
+        let message = response(ID, 0, 4, &records);
+        assert_eq!(
+            parse_response(ID, &message),
+            Ok(Answer {
+                address: BROKER,
+                ttl: 120
+            })
+        );
+    }
+
+    #[test]
+    fn no_address() {
+        let message = response(ID, 0, 0, &[]);
+        assert_eq!(parse_response(ID, &message), Err(DnsError::NoAddress));
+        // only the alias: the address is not in the answer
+        let alias = record(&QUESTION_NAME, 5, 120, b"\x03www\xc0\x0c");
+        let message = response(ID, 0, 1, &alias);
+        assert_eq!(parse_response(ID, &message), Err(DnsError::NoAddress));
+        // AAAA record
+        let ipv6 = record(&QUESTION_NAME, 28, 120, &[0xfe; 16]);
+        let message = response(ID, 0, 1, &ipv6);
+        assert_eq!(parse_response(ID, &message), Err(DnsError::NoAddress));
+    }
+
+    #[test]
+    fn truncated_answer() {
+        let answer = record(b"\x03www\xc0\x0c", TYPE_A, 300, &BROKER);
+        let message = response(ID, 0, 1, &answer);
+        for len in 0..message.len() {
+            assert_eq!(
+                parse_response(ID, &message[..len]),
+                Err(DnsError::Malformed),
+                "truncated to {len}"
+            );
+        }
+        // the count of answers says there are more than in the message
+        let message = response(ID, 0, 2, &answer);
+        assert_eq!(parse_response(ID, &message).unwrap().address, BROKER);
+        let alias = record(&QUESTION_NAME, 5, 120, b"\x03www\xc0\x0c");
+        let message = response(ID, 0, 2, &alias);
+        assert_eq!(parse_response(ID, &message), Err(DnsError::Malformed));
+    }
+
+    #[test]
+    fn malformed_names() {
+        // label longer than allowed (the bits 0x40 and 0x80 are reserved)
+        for len in [0x40u8, 0x80] {
+            let answer = record(&[len, b'a'], TYPE_A, 300, &BROKER);
+            let message = response(ID, 0, 1, &answer);
+            assert_eq!(parse_response(ID, &message), Err(DnsError::Malformed));
+        }
+    }
+
+    #[test]
+    fn unexpected_id() {
+        let answer = record(&QUESTION_NAME, TYPE_A, 300, &BROKER);
+        let message = response(ID + 1, 0, 1, &answer);
+        assert_eq!(parse_response(ID, &message), Err(DnsError::UnexpectedId));
+    }
+
+    #[test]
+    fn query_instead_of_response() {
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let len = encode_query(ID, "broker.local", &mut buffer).unwrap();
+        assert_eq!(parse_response(ID, &buffer[..len]), Err(DnsError::Malformed));
+    }
+
+    #[test]
+    fn server_errors() {
+        // format error, server failure, name that doesn't exist, refused
+        for rcode in [1, 2, 3, 5] {
+            let message = response(ID, rcode, 0, &[]);
+            assert_eq!(
+                parse_response(ID, &message),
+                Err(DnsError::Server(rcode as u8))
+            );
+        }
+    }
+
+    #[test]
+    fn cache_expires_with_ttl() {
+        let mut cache = DnsCache::new();
+        assert_eq!(cache.get(0), None);
+        let answer = Answer {
+            address: BROKER,
+            ttl: 60,
+        };
+        cache.insert(&answer, 1_000);
+        assert_eq!(cache.get(1_000), Some(BROKER));
+        assert_eq!(cache.get(60_999), Some(BROKER));
+        assert_eq!(cache.get(61_000), None);
+
+        // a TTL of 0 is not kept
+        cache.insert(&Answer { ttl: 0, ..answer }, 1_000);
+        assert_eq!(cache.get(1_000), None);
+
+        // nor overflows
+        cache.insert(
+            &Answer {
+                ttl: u32::MAX,
+                ..answer
+            },
+            u64::MAX - 10,
+        );
+        assert_eq!(cache.get(u64::MAX - 1), Some(BROKER));
+
+        cache.clear();
+        assert_eq!(cache.get(0), None);
+    }
+}
@@ -0,0 +1,236 @@
+//! Protocol of the HTU21D and SI7021 temperature and humidity sensors,
+//! without the i2c access: commands, user register, CRC and conversions.
+//!
+//! A measurement is 3 bytes: the value (MSB first) and its CRC-8. The two
+//! lowest bits of the value are status bits, not part of the value.
+
+/// i2c address of the sensor
+pub const ADDRESS: u8 = 0x40;
+
+/// Commands of the sensor
+pub mod command {
+    /// Measurements holding the bus (clock stretching) until done
+    pub const MEASURE_TEMPERATURE_HOLD: u8 = 0xE3;
+    pub const MEASURE_HUMIDITY_HOLD: u8 = 0xE5;
+    /// Measurements releasing the bus: the sensor NACKs the reads until done
+    pub const MEASURE_TEMPERATURE: u8 = 0xF3;
+    pub const MEASURE_HUMIDITY: u8 = 0xF5;
+    pub const WRITE_USER_REGISTER: u8 = 0xE6;
+    pub const READ_USER_REGISTER: u8 = 0xE7;
+    pub const SOFT_RESET: u8 = 0xFE;
+}
+
+/// Time the sensor takes to restart after a soft reset, in ms
+pub const SOFT_RESET_MILLIS: u64 = 15;
+
+/// Polynomial of the CRC: x^8 + x^5 + x^4 + 1
+const CRC_POLYNOMIAL: u8 = 0x31;
+
+/// Status bits of a measurement
+const STATUS_MASK: u16 = 0x0003;
+
+/// Bits of the user register
+const RESOLUTION_MASK: u8 = 0b1000_0001;
+const END_OF_BATTERY: u8 = 0b0100_0000;
+const HEATER: u8 = 0b0000_0100;
+
+/// The CRC doesn't match the value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrcError;
+
+/// Resolution of the measurements, humidity and temperature bits. The
+/// lower the resolution, the faster the measurement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Rh12Temp14,
+    Rh8Temp12,
+    Rh10Temp13,
+    Rh11Temp11,
+}
+
+impl Resolution {
+    /// Bits 7 and 0 of the user register
+    fn bits(self) -> u8 {
+        match self {
+            Resolution::Rh12Temp14 => 0b0000_0000,
+            Resolution::Rh8Temp12 => 0b0000_0001,
+            Resolution::Rh10Temp13 => 0b1000_0000,
+            Resolution::Rh11Temp11 => 0b1000_0001,
+        }
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        match bits & RESOLUTION_MASK {
+            0b0000_0000 => Resolution::Rh12Temp14,
+            0b0000_0001 => Resolution::Rh8Temp12,
+            0b1000_0000 => Resolution::Rh10Temp13,
+            _ => Resolution::Rh11Temp11,
+        }
+    }
+
+    /// Maximum time of a temperature measurement, in ms
+    pub fn temperature_millis(self) -> u64 {
+        match self {
+            Resolution::Rh12Temp14 => 50,
+            Resolution::Rh10Temp13 => 25,
+            Resolution::Rh8Temp12 => 13,
+            Resolution::Rh11Temp11 => 7,
+        }
+    }
+
+    /// Maximum time of a humidity measurement, in ms
+    pub fn humidity_millis(self) -> u64 {
+        match self {
+            Resolution::Rh12Temp14 => 16,
+            Resolution::Rh11Temp11 => 8,
+            Resolution::Rh10Temp13 => 5,
+            Resolution::Rh8Temp12 => 3,
+        }
+    }
+}
+
+/// Content of the user register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserRegister(pub u8);
+
+impl UserRegister {
+    pub fn resolution(self) -> Resolution {
+        Resolution::from_bits(self.0)
+    }
+
+    /// The supply voltage is below 2.25 V
+    pub fn end_of_battery(self) -> bool {
+        self.0 & END_OF_BATTERY != 0
+    }
+
+    pub fn heater(self) -> bool {
+        self.0 & HEATER != 0
+    }
+
+    /// Same register with another resolution. The reserved bits are kept.
+    pub fn with_resolution(self, resolution: Resolution) -> Self {
+        UserRegister((self.0 & !RESOLUTION_MASK) | resolution.bits())
+    }
+
+    /// Same register with the heater on or off. The reserved bits are kept.
+    pub fn with_heater(self, on: bool) -> Self {
+        if on {
+            UserRegister(self.0 | HEATER)
+        } else {
+            UserRegister(self.0 & !HEATER)
+        }
+    }
+}
+
+/// CRC-8 of the data, polynomial 0x31 and initial value 0
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ CRC_POLYNOMIAL
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Checks the CRC of a measurement and returns its value without the
+/// status bits
+pub fn decode(bytes: [u8; 3]) -> Result<u16, CrcError> {
+    if crc8(&bytes[..2]) != bytes[2] {
+        return Err(CrcError);
+    }
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]) & !STATUS_MASK)
+}
+
+/// Temperature in °C of a measurement without the status bits
+pub fn temperature(raw: u16) -> f32 {
+    -46.85 + 175.72 * raw as f32 / 65536.0
+}
+
+/// Relative humidity in % of a measurement without the status bits. It is
+/// limited to 0-100 %: the sensor may give values a bit out of range.
+pub fn relative_humidity(raw: u16) -> f32 {
+    (-6.0 + 125.0 * raw as f32 / 65536.0).clamp(0.0, 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 0.01, "{value} != {expected}");
+    }
+
+    #[test]
+    fn crc_of_datasheet_examples() {
+        assert_eq!(crc8(&[0xDC]), 0x79);
+        assert_eq!(crc8(&[0x68, 0x3A]), 0x7C);
+        assert_eq!(crc8(&[0x4E, 0x85]), 0x6B);
+        assert_eq!(crc8(&[]), 0x00);
+    }
+
+    #[test]
+    fn decode_checks_crc() {
+        assert_eq!(decode([0x68, 0x3A, 0x7C]), Ok(0x6838));
+        assert_eq!(decode([0x68, 0x3A, 0x7D]), Err(CrcError));
+        assert_eq!(decode([0x69, 0x3A, 0x7C]), Err(CrcError));
+    }
+
+    #[test]
+    fn status_bits_masked() {
+        for status in 0..=3u8 {
+            let value = [0x4E, 0x84 | status];
+            let crc = crc8(&value);
+            assert_eq!(decode([value[0], value[1], crc]), Ok(0x4E84));
+        }
+        // the value is the same, whatever the status
+        assert_eq!(decode([0xFF, 0xFF, crc8(&[0xFF, 0xFF])]), Ok(0xFFFC));
+    }
+
+    #[test]
+    fn datasheet_conversions() {
+        assert_close(temperature(0x6838), 24.69);
+        assert_close(relative_humidity(0x4E84), 32.34);
+    }
+
+    #[test]
+    fn conversion_endpoints() {
+        assert_close(temperature(0x0000), -46.85);
+        assert_close(temperature(0xFFFC), 128.86);
+        // out of range values of the humidity are limited
+        assert_eq!(relative_humidity(0x0000), 0.0);
+        assert_close(relative_humidity(0x0C4C), 0.0);
+        assert_eq!(relative_humidity(0xFFFC), 100.0);
+        assert_close(relative_humidity(0xD916), 100.0);
+    }
+
+    #[test]
+    fn user_register() {
+        // default after reset: 12/14 bits, heater off, reserved bits set
+        let register = UserRegister(0b0000_0010);
+        assert_eq!(register.resolution(), Resolution::Rh12Temp14);
+        assert!(!register.heater());
+        assert!(!register.end_of_battery());
+
+        for resolution in [
+            Resolution::Rh12Temp14,
+            Resolution::Rh8Temp12,
+            Resolution::Rh10Temp13,
+            Resolution::Rh11Temp11,
+        ] {
+            let changed = register.with_resolution(resolution);
+            assert_eq!(changed.resolution(), resolution);
+            assert_eq!(changed.0 & !RESOLUTION_MASK, register.0);
+        }
+
+        let heated = register.with_heater(true);
+        assert!(heated.heater());
+        assert_eq!(heated.with_heater(false), register);
+        assert!(UserRegister(0b0100_0000).end_of_battery());
+    }
+}
@@ -0,0 +1,311 @@
+//! Aggregates of the samples of an accelerometer and gyroscope over a time
+//! window: mean, RMS and peak of each axis. Publishing these instead of the
+//! samples allows vibration monitoring at a high sample rate without
+//! flooding the broker.
+
+use crate::sensor::Axis;
+use core::fmt::{self, Write};
+
+/// Mean, RMS and peak of the samples of one axis
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    count: u32,
+    sum: f32,
+    sum_squares: f32,
+    peak: f32,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            count: 0,
+            sum: 0.0,
+            sum_squares: 0.0,
+            peak: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, value: f32) {
+        self.count += 1;
+        self.sum += value;
+        self.sum_squares += value * value;
+        // f32::abs is not in core
+        let magnitude = if value < 0.0 { -value } else { value };
+        if magnitude > self.peak {
+            self.peak = magnitude;
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Mean of the samples, 0 if there are none
+    pub fn mean(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum / self.count as f32
+    }
+
+    /// Root mean square of the samples, 0 if there are none
+    pub fn rms(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        sqrt(self.sum_squares / self.count as f32)
+    }
+
+    /// Largest absolute value of the samples
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+
+    /// Writes the stats as JSON: {"mean":..,"rms":..,"peak":..}
+    pub fn write_json<W: Write>(&self, w: &mut W) -> fmt::Result {
+        write!(
+            w,
+            "{{\"mean\":{},\"rms\":{},\"peak\":{}}}",
+            self.mean(),
+            self.rms(),
+            self.peak()
+        )
+    }
+}
+
+/// Square root, for no_std: Newton's method from an estimate taken from the
+/// exponent of the float. Four iterations are enough for f32 precision.
+fn sqrt(value: f32) -> f32 {
+    if value <= 0.0 {
+        return 0.0;
+    }
+    let mut root = f32::from_bits((value.to_bits() >> 1) + 0x1fc0_0000);
+    for _ in 0..4 {
+        root = 0.5 * (root + value / root);
+    }
+    root
+}
+
+/// Configuration of the sensor, published with the aggregates to interpret
+/// them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImuSettings {
+    /// Output data rate, in Hz
+    pub odr_hz: f32,
+    /// Full scale range of the accelerometer, in g
+    pub accel_range_g: u16,
+    /// Full scale range of the gyroscope, in °/s
+    pub gyro_range_dps: u16,
+}
+
+/// Aggregates of the samples of a window, for the three axes of the
+/// acceleration (g) and the angular rate (°/s)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImuWindow {
+    /// Name of the sensor that took the samples
+    pub sensor: &'static str,
+    pub settings: ImuSettings,
+    /// Time of the first sample, in ms
+    pub start_millis: u64,
+    /// Time of the last sample, in ms
+    pub end_millis: u64,
+    accel: [Stats; 3],
+    gyro: [Stats; 3],
+}
+
+impl ImuWindow {
+    /// Empty window starting at the time given
+    pub const fn new(sensor: &'static str, settings: ImuSettings, start_millis: u64) -> Self {
+        ImuWindow {
+            sensor,
+            settings,
+            start_millis,
+            end_millis: start_millis,
+            accel: [Stats::new(); 3],
+            gyro: [Stats::new(); 3],
+        }
+    }
+
+    /// Adds a sample of both sensors, taken at the time given
+    pub fn add(&mut self, timestamp_millis: u64, accel: [f32; 3], gyro: [f32; 3]) {
+        for (stats, value) in self.accel.iter_mut().zip(accel) {
+            stats.add(value);
+        }
+        for (stats, value) in self.gyro.iter_mut().zip(gyro) {
+            stats.add(value);
+        }
+        self.end_millis = timestamp_millis;
+    }
+
+    /// Number of samples in the window
+    pub fn samples(&self) -> u32 {
+        self.accel[0].count()
+    }
+
+    pub fn accel(&self, axis: Axis) -> &Stats {
+        &self.accel[axis as usize]
+    }
+
+    pub fn gyro(&self, axis: Axis) -> &Stats {
+        &self.gyro[axis as usize]
+    }
+
+    /// Writes the window as JSON:
+    /// {"sensor":..,"ts":..,"window_ms":..,"samples":..,"odr_hz":..,
+    /// "accel_range_g":..,"gyro_range_dps":..,
+    /// "accel":{"x":<stats>,"y":..,"z":..},"gyro":{..}}
+    /// where ts is the time of the last sample
+    pub fn write_json<W: Write>(&self, w: &mut W) -> fmt::Result {
+        write!(
+            w,
+            "{{\"sensor\":\"{}\",\"ts\":{},\"window_ms\":{},\"samples\":{},\
+             \"odr_hz\":{},\"accel_range_g\":{},\"gyro_range_dps\":{}",
+            self.sensor,
+            self.end_millis,
+            self.end_millis - self.start_millis,
+            self.samples(),
+            self.settings.odr_hz,
+            self.settings.accel_range_g,
+            self.settings.gyro_range_dps
+        )?;
+        w.write_str(",\"accel\":")?;
+        write_axes(w, &self.accel)?;
+        w.write_str(",\"gyro\":")?;
+        write_axes(w, &self.gyro)?;
+        w.write_str("}")
+    }
+}
+
+/// Writes the stats of the three axes as {"x":..,"y":..,"z":..}
+fn write_axes<W: Write>(w: &mut W, axes: &[Stats; 3]) -> fmt::Result {
+    for (i, (name, stats)) in ["x", "y", "z"].iter().zip(axes).enumerate() {
+        w.write_str(if i == 0 { "{" } else { "," })?;
+        write!(w, "\"{}\":", name)?;
+        stats.write_json(w)?;
+    }
+    w.write_str("}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::String;
+
+    fn assert_close(value: f32, expected: f32) {
+        let error = (value - expected) / expected;
+        assert!(-1e-6 < error && error < 1e-6, "{} not {}", value, expected);
+    }
+
+    const SETTINGS: ImuSettings = ImuSettings {
+        odr_hz: 104.0,
+        accel_range_g: 4,
+        gyro_range_dps: 500,
+    };
+
+    #[test]
+    fn stats_of_a_window() {
+        let mut stats = Stats::new();
+        assert_eq!((stats.mean(), stats.rms(), stats.peak()), (0.0, 0.0, 0.0));
+        for value in [1.0, -3.0, 2.0, 4.0] {
+            stats.add(value);
+        }
+        assert_eq!(stats.count(), 4);
+        assert_eq!(stats.mean(), 1.0);
+        // sqrt((1 + 9 + 4 + 16) / 4)
+        assert_close(stats.rms(), 2.738_613);
+        assert_eq!(stats.peak(), 4.0);
+
+        // the peak is the largest magnitude, also if negative
+        let mut stats = Stats::new();
+        for value in [-0.5, -2.5, 1.5] {
+            stats.add(value);
+        }
+        assert_eq!(stats.peak(), 2.5);
+        assert_close(stats.mean(), -0.5);
+    }
+
+    #[test]
+    fn square_root() {
+        assert_eq!(sqrt(0.0), 0.0);
+        assert_eq!(sqrt(-4.0), 0.0);
+        assert_eq!(sqrt(1.0), 1.0);
+        assert_eq!(sqrt(4.0), 2.0);
+        for value in [1e-20, 1e-6, 0.01, 0.5, 2.0, 10.0, 12_345.678, 1e20, 3e38] {
+            assert_close(sqrt(value), (value as f64).sqrt() as f32);
+        }
+    }
+
+    #[test]
+    fn stats_as_json() {
+        let mut stats = Stats::new();
+        for value in [-1.0, -3.0] {
+            stats.add(value);
+        }
+        let mut json: String<64> = String::new();
+        stats.write_json(&mut json).unwrap();
+        assert_eq!(json, "{\"mean\":-2,\"rms\":2.236068,\"peak\":3}");
+    }
+
+    #[test]
+    fn window_as_json() {
+        let mut window = ImuWindow::new("lsm6ds3", SETTINGS, 1_000);
+        window.add(1_010, [0.5, -0.25, 1.0], [-10.0, 0.0, 2.0]);
+        window.add(1_020, [-0.5, -0.25, 1.0], [10.0, 0.0, -2.0]);
+        assert_eq!(window.samples(), 2);
+        assert_eq!(window.accel(Axis::Y).mean(), -0.25);
+        assert_eq!(window.gyro(Axis::X).peak(), 10.0);
+
+        let mut json: String<512> = String::new();
+        window.write_json(&mut json).unwrap();
+        assert_eq!(
+            json,
+            "{\"sensor\":\"lsm6ds3\",\"ts\":1020,\"window_ms\":20,\"samples\":2,\
+             \"odr_hz\":104,\"accel_range_g\":4,\"gyro_range_dps\":500,\
+             \"accel\":{\"x\":{\"mean\":0,\"rms\":0.5,\"peak\":0.5},\
+             \"y\":{\"mean\":-0.25,\"rms\":0.25,\"peak\":0.25},\
+             \"z\":{\"mean\":1,\"rms\":1,\"peak\":1}},\
+             \"gyro\":{\"x\":{\"mean\":0,\"rms\":10,\"peak\":10},\
+             \"y\":{\"mean\":0,\"rms\":0,\"peak\":0},\
+             \"z\":{\"mean\":0,\"rms\":2,\"peak\":2}}}"
+        );
+    }
+
+    #[test]
+    fn json_in_a_full_buffer() {
+        let mut window = ImuWindow::new("lsm6ds3", SETTINGS, 0);
+        // negative values with many decimal digits
+        for i in 0..1_000u32 {
+            let value = -(i as f32) / 7.0;
+            window.add(i as u64, [value / 256.0; 3], [value; 3]);
+        }
+        // it fits in the payload of embsens
+        let mut json: String<768> = String::new();
+        window.write_json(&mut json).unwrap();
+
+        // an error, not a panic, if it doesn't fit
+        let mut fits = Limited::new(json.len());
+        window.write_json(&mut fits).unwrap();
+        let mut short = Limited::new(json.len() - 1);
+        assert_eq!(window.write_json(&mut short), Err(fmt::Error));
+        let mut short: String<128> = String::new();
+        assert_eq!(window.write_json(&mut short), Err(fmt::Error));
+    }
+
+    /// Writer with room for a number of bytes
+    struct Limited {
+        room: usize,
+    }
+
+    impl Limited {
+        fn new(room: usize) -> Self {
+            Limited { room }
+        }
+    }
+
+    impl Write for Limited {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.room = self.room.checked_sub(s.len()).ok_or(fmt::Error)?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,762 @@
+//! Key-value store in NOR flash for the configuration of the device.
+//!
+//! It uses two sectors. The active one is a log: each write appends a
+//! record after the previous ones, and a key has the value of its last
+//! record. When the sector is full the last values are copied to the other
+//! sector, which becomes the active one, and only then the old one is
+//! erased. So each sector is erased once per fill, alternately, and a power
+//! loss at any point keeps the previous values.
+//!
+//! Sector: | magic "KVS1" | generation (u32 LE) | records ... | erased (0xff) |
+//!
+//! Record: | key_len (u8) | 0x00 | value_len (u16 LE) | CRC-32 (u32 LE) | key | value | padding |
+//!
+//! A value_len of 0xffff removes the key. The CRC covers the lengths, the
+//! key and the value; a record with a wrong CRC is the end of the log (a
+//! write interrupted), and the store is compacted to discard it.
+//! Records are aligned to 4 bytes, the write unit of the flash.
+
+use crate::crc::Crc32;
+
+const MAGIC: &[u8; 4] = b"KVS1";
+const SECTOR_HEADER_LEN: u32 = 8;
+const RECORD_HEADER_LEN: usize = 8;
+/// Second byte of a record, so that a record is never all 0xff
+const RECORD_MARK: u8 = 0x00;
+/// value_len of a removed key
+const REMOVED: u16 = 0xffff;
+/// Byte of the erased flash
+const ERASED: u8 = 0xff;
+
+pub const MAX_KEY_LEN: usize = 16;
+pub const MAX_VALUE_LEN: usize = 256;
+const MAX_RECORD_LEN: usize = align(RECORD_HEADER_LEN + MAX_KEY_LEN + MAX_VALUE_LEN);
+
+/// NOR flash: erased bytes are 0xff and writes can only clear bits. The
+/// offsets and lengths of the writes are multiple of 4.
+pub trait Flash {
+    type Error;
+    /// Size of the erase unit
+    const SECTOR_SIZE: u32;
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error>;
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error>;
+    /// Erases the sector that starts at `offset`
+    fn erase(&mut self, offset: u32) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvError<E> {
+    Flash(E),
+    /// The key is empty or longer than MAX_KEY_LEN
+    InvalidKey,
+    ValueTooLong,
+    /// The buffer for the value is too small
+    BufferTooSmall,
+    /// The values don't fit in a sector
+    Full,
+}
+
+/// Header of a record, and where it is
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    offset: u32,
+    key_len: usize,
+    /// None if the key is removed
+    value_len: Option<usize>,
+}
+
+impl Record {
+    fn data_len(&self) -> usize {
+        self.key_len + self.value_len.unwrap_or(0)
+    }
+
+    /// Length in flash, with the header and the padding
+    fn len(&self) -> u32 {
+        align(RECORD_HEADER_LEN + self.data_len()) as u32
+    }
+}
+
+/// Store in the two sectors that start at `base`
+pub struct KvStore<F: Flash> {
+    flash: F,
+    base: u32,
+    /// Active sector: 0 or 1
+    active: u32,
+    generation: u32,
+    /// Offset, in the active sector, of the next record
+    end: u32,
+}
+
+impl<F: Flash> KvStore<F> {
+    /// Opens the store, formatting it if the sectors are empty or not valid
+    pub fn mount(flash: F, base: u32) -> Result<Self, KvError<F::Error>> {
+        let mut store = KvStore {
+            flash,
+            base,
+            active: 0,
+            generation: 0,
+            end: SECTOR_HEADER_LEN,
+        };
+        match [store.read_generation(0)?, store.read_generation(1)?] {
+            [None, None] => {
+                store.format(0, 1)?;
+                return Ok(store);
+            }
+            [Some(g0), Some(g1)] => {
+                // a compaction interrupted after writing the new sector: the
+                // newest one (with wrapping) is complete
+                let (active, generation) = if g1.wrapping_sub(g0) as i32 > 0 {
+                    (1, g1)
+                } else {
+                    (0, g0)
+                };
+                store.active = active;
+                store.generation = generation;
+                store.erase(1 - active)?;
+            }
+            [Some(g0), None] => store.generation = g0,
+            [None, Some(g1)] => {
+                store.active = 1;
+                store.generation = g1;
+            }
+        }
+
+        // find the end of the log
+        let mut offset = SECTOR_HEADER_LEN;
+        let mut interrupted = false;
+        loop {
+            match store.read_record(offset)? {
+                RecordRead::Valid(record) => offset += record.len(),
+                RecordRead::End => break,
+                RecordRead::Corrupted => {
+                    interrupted = true;
+                    break;
+                }
+            }
+        }
+        store.end = offset;
+        if interrupted {
+            // a write interrupted: copy the records before it to a clean
+            // sector
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    /// Reads the value of `key` into `value`, returning its length, or None
+    /// if the key is not in the store
+    pub fn get(&mut self, key: &str, value: &mut [u8]) -> Result<Option<usize>, KvError<F::Error>> {
+        let Some(record) = self.find(self.active, key.as_bytes())? else {
+            return Ok(None);
+        };
+        let Some(len) = record.value_len else {
+            return Ok(None);
+        };
+        if value.len() < len {
+            return Err(KvError::BufferTooSmall);
+        }
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+        let data = &mut buffer[..record.data_len()];
+        self.read(self.active, record.offset + RECORD_HEADER_LEN as u32, data)?;
+        value[..len].copy_from_slice(&data[record.key_len..]);
+        Ok(Some(len))
+    }
+
+    /// Sets the value of `key`. It is not written if it has not changed.
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), KvError<F::Error>> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(KvError::ValueTooLong);
+        }
+        let mut current = [0u8; MAX_VALUE_LEN];
+        if let Some(len) = self.get(key, &mut current)? {
+            if &current[..len] == value {
+                return Ok(());
+            }
+        }
+        self.append(key.as_bytes(), Some(value))
+    }
+
+    /// Removes `key` from the store
+    pub fn remove(&mut self, key: &str) -> Result<(), KvError<F::Error>> {
+        match self.find(self.active, key.as_bytes())? {
+            Some(record) if record.value_len.is_some() => self.append(key.as_bytes(), None),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes all the keys
+    pub fn clear(&mut self) -> Result<(), KvError<F::Error>> {
+        let (active, other) = (self.active, 1 - self.active);
+        self.format(other, self.generation.wrapping_add(1))?;
+        self.erase(active)
+    }
+
+    /// Writes a record at the end of the log, compacting it if it is full
+    fn append(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(), KvError<F::Error>> {
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return Err(KvError::InvalidKey);
+        }
+        let mut buffer = [ERASED; MAX_RECORD_LEN];
+        let len = encode_record(key, value, &mut buffer);
+        if self.end + len as u32 > F::SECTOR_SIZE {
+            self.compact()?;
+            if self.end + len as u32 > F::SECTOR_SIZE {
+                return Err(KvError::Full);
+            }
+        }
+        self.write(self.active, self.end, &buffer[..len])?;
+        self.end += len as u32;
+        Ok(())
+    }
+
+    /// Copies the last value of each key to the other sector, which becomes
+    /// the active one, and erases the old one
+    fn compact(&mut self) -> Result<(), KvError<F::Error>> {
+        let (old, new) = (self.active, 1 - self.active);
+        let generation = self.generation.wrapping_add(1);
+        self.erase(new)?;
+
+        let mut end = SECTOR_HEADER_LEN;
+        let mut offset = SECTOR_HEADER_LEN;
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+        while offset < self.end {
+            let RecordRead::Valid(record) = self.read_record_in(old, offset)? else {
+                break;
+            };
+            offset += record.len();
+            if record.value_len.is_none() {
+                continue;
+            }
+            let len = record.len() as usize;
+            self.read(old, record.offset, &mut buffer[..len])?;
+            let key = &buffer[RECORD_HEADER_LEN..RECORD_HEADER_LEN + record.key_len];
+            // copied only if it is the last record of the key
+            let last = self.find_from(old, key, offset, self.end)?.is_none();
+            if last {
+                self.write(new, end, &buffer[..len])?;
+                end += len as u32;
+            }
+        }
+
+        // the header last: until then the old sector is the valid one
+        self.write_header(new, generation)?;
+        self.erase(old)?;
+        self.active = new;
+        self.generation = generation;
+        self.end = end;
+        Ok(())
+    }
+
+    /// Erases `sector` and makes it the active one, empty
+    fn format(&mut self, sector: u32, generation: u32) -> Result<(), KvError<F::Error>> {
+        self.erase(sector)?;
+        self.write_header(sector, generation)?;
+        self.active = sector;
+        self.generation = generation;
+        self.end = SECTOR_HEADER_LEN;
+        Ok(())
+    }
+
+    fn write_header(&mut self, sector: u32, generation: u32) -> Result<(), KvError<F::Error>> {
+        let mut header = [0u8; SECTOR_HEADER_LEN as usize];
+        header[..4].copy_from_slice(MAGIC);
+        header[4..].copy_from_slice(&generation.to_le_bytes());
+        self.write(sector, 0, &header)
+    }
+
+    /// Generation of a sector, if it is formatted
+    fn read_generation(&mut self, sector: u32) -> Result<Option<u32>, KvError<F::Error>> {
+        let mut header = [0u8; SECTOR_HEADER_LEN as usize];
+        self.read(sector, 0, &mut header)?;
+        if &header[..4] != MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(u32::from_le_bytes([
+            header[4], header[5], header[6], header[7],
+        ])))
+    }
+
+    /// Last record of `key` in the log of `sector`
+    fn find(&mut self, sector: u32, key: &[u8]) -> Result<Option<Record>, KvError<F::Error>> {
+        self.find_from(sector, key, SECTOR_HEADER_LEN, self.end)
+    }
+
+    /// Last record of `key` between `start` and `end`
+    fn find_from(
+        &mut self,
+        sector: u32,
+        key: &[u8],
+        start: u32,
+        end: u32,
+    ) -> Result<Option<Record>, KvError<F::Error>> {
+        let mut found = None;
+        let mut offset = start;
+        let mut buffer = [0u8; MAX_KEY_LEN];
+        while offset < end {
+            let RecordRead::Valid(record) = self.read_record_in(sector, offset)? else {
+                break;
+            };
+            if record.key_len == key.len() {
+                let stored = &mut buffer[..record.key_len];
+                self.read(sector, offset + RECORD_HEADER_LEN as u32, stored)?;
+                if stored == key {
+                    found = Some(record);
+                }
+            }
+            offset += record.len();
+        }
+        Ok(found)
+    }
+
+    fn read_record(&mut self, offset: u32) -> Result<RecordRead, KvError<F::Error>> {
+        self.read_record_in(self.active, offset)
+    }
+
+    /// Reads and checks the record at `offset`
+    fn read_record_in(
+        &mut self,
+        sector: u32,
+        offset: u32,
+    ) -> Result<RecordRead, KvError<F::Error>> {
+        if offset + RECORD_HEADER_LEN as u32 > F::SECTOR_SIZE {
+            return Ok(RecordRead::End);
+        }
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+        self.read(sector, offset, &mut buffer[..RECORD_HEADER_LEN])?;
+        if buffer[..RECORD_HEADER_LEN].iter().all(|b| *b == ERASED) {
+            return Ok(RecordRead::End);
+        }
+        let key_len = buffer[0] as usize;
+        let value_len = match u16::from_le_bytes([buffer[2], buffer[3]]) {
+            REMOVED => None,
+            len => Some(len as usize),
+        };
+        let record = Record {
+            offset,
+            key_len,
+            value_len,
+        };
+        if buffer[1] != RECORD_MARK
+            || key_len == 0
+            || key_len > MAX_KEY_LEN
+            || value_len.unwrap_or(0) > MAX_VALUE_LEN
+            || offset + record.len() > F::SECTOR_SIZE
+        {
+            return Ok(RecordRead::Corrupted);
+        }
+        let crc = u32::from_le_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+        let data_end = RECORD_HEADER_LEN + record.data_len();
+        self.read(
+            sector,
+            offset + RECORD_HEADER_LEN as u32,
+            &mut buffer[RECORD_HEADER_LEN..data_end],
+        )?;
+        if record_crc(&buffer[..4], &buffer[RECORD_HEADER_LEN..data_end]) != crc {
+            return Ok(RecordRead::Corrupted);
+        }
+        Ok(RecordRead::Valid(record))
+    }
+
+    fn read(
+        &mut self,
+        sector: u32,
+        offset: u32,
+        bytes: &mut [u8],
+    ) -> Result<(), KvError<F::Error>> {
+        self.flash
+            .read(self.sector_offset(sector) + offset, bytes)
+            .map_err(KvError::Flash)
+    }
+
+    fn write(&mut self, sector: u32, offset: u32, bytes: &[u8]) -> Result<(), KvError<F::Error>> {
+        self.flash
+            .write(self.sector_offset(sector) + offset, bytes)
+            .map_err(KvError::Flash)
+    }
+
+    fn erase(&mut self, sector: u32) -> Result<(), KvError<F::Error>> {
+        self.flash
+            .erase(self.sector_offset(sector))
+            .map_err(KvError::Flash)
+    }
+
+    fn sector_offset(&self, sector: u32) -> u32 {
+        self.base + sector * F::SECTOR_SIZE
+    }
+}
+
+enum RecordRead {
+    Valid(Record),
+    /// Erased flash: there are no more records
+    End,
+    Corrupted,
+}
+
+/// Writes the record in `buffer`, returning its length with the padding
+fn encode_record(key: &[u8], value: Option<&[u8]>, buffer: &mut [u8]) -> usize {
+    let value_len = match value {
+        Some(value) => value.len() as u16,
+        None => REMOVED,
+    };
+    let value = value.unwrap_or(&[]);
+    let data_end = RECORD_HEADER_LEN + key.len() + value.len();
+    buffer[0] = key.len() as u8;
+    buffer[1] = RECORD_MARK;
+    buffer[2..4].copy_from_slice(&value_len.to_le_bytes());
+    buffer[RECORD_HEADER_LEN..RECORD_HEADER_LEN + key.len()].copy_from_slice(key);
+    buffer[RECORD_HEADER_LEN + key.len()..data_end].copy_from_slice(value);
+    let crc = record_crc(&buffer[..4], &buffer[RECORD_HEADER_LEN..data_end]);
+    buffer[4..8].copy_from_slice(&crc.to_le_bytes());
+    align(data_end)
+}
+
+/// CRC-32 of the lengths, the key and the value
+fn record_crc(lengths: &[u8], data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(lengths);
+    crc.update(data);
+    crc.finish()
+}
+
+/// Length rounded up to the write unit
+const fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+
+    const SECTOR: usize = 1024;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct PowerLoss;
+
+    /// Two sectors of NOR flash in RAM. The power can be cut after a number
+    /// of writes and erases: the one that is cut is done by half.
+    #[derive(Clone)]
+    struct Memory {
+        bytes: [u8; 2 * SECTOR],
+        operations_left: Option<usize>,
+        writes: usize,
+        erases: [usize; 2],
+    }
+
+    impl Memory {
+        fn new() -> RefCell<Self> {
+            RefCell::new(Memory {
+                bytes: [ERASED; 2 * SECTOR],
+                operations_left: None,
+                writes: 0,
+                erases: [0; 2],
+            })
+        }
+
+        /// It returns false if the power is cut in this operation
+        fn power(&mut self) -> Result<bool, PowerLoss> {
+            match &mut self.operations_left {
+                None => Ok(true),
+                Some(0) => Err(PowerLoss),
+                Some(left) => {
+                    *left -= 1;
+                    Ok(*left > 0)
+                }
+            }
+        }
+    }
+
+    struct RamFlash<'a>(&'a RefCell<Memory>);
+
+    impl Flash for RamFlash<'_> {
+        type Error = PowerLoss;
+        const SECTOR_SIZE: u32 = SECTOR as u32;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerLoss> {
+            let memory = self.0.borrow();
+            let offset = offset as usize;
+            bytes.copy_from_slice(&memory.bytes[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerLoss> {
+            assert_eq!(offset % 4, 0, "write not aligned");
+            assert_eq!(bytes.len() % 4, 0, "write not aligned");
+            let mut memory = self.0.borrow_mut();
+            let powered = memory.power()?;
+            let len = if powered {
+                bytes.len()
+            } else {
+                bytes.len() / 2
+            };
+            let offset = offset as usize;
+            for (stored, byte) in memory.bytes[offset..offset + len].iter_mut().zip(bytes) {
+                // only the bits at 1 can be cleared
+                *stored &= byte;
+            }
+            memory.writes += 1;
+            if powered {
+                Ok(())
+            } else {
+                Err(PowerLoss)
+            }
+        }
+
+        fn erase(&mut self, offset: u32) -> Result<(), PowerLoss> {
+            assert_eq!(offset as usize % SECTOR, 0, "erase not aligned");
+            let mut memory = self.0.borrow_mut();
+            let powered = memory.power()?;
+            let offset = offset as usize;
+            let len = if powered { SECTOR } else { SECTOR / 2 };
+            memory.bytes[offset..offset + len].fill(ERASED);
+            memory.erases[offset / SECTOR] += 1;
+            if powered {
+                Ok(())
+            } else {
+                Err(PowerLoss)
+            }
+        }
+    }
+
+    fn mount(memory: &RefCell<Memory>) -> KvStore<RamFlash<'_>> {
+        KvStore::mount(RamFlash(memory), 0).unwrap()
+    }
+
+    /// Value of the key, if any
+    fn get<'a>(
+        store: &mut KvStore<RamFlash<'_>>,
+        key: &str,
+        value: &'a mut [u8; MAX_VALUE_LEN],
+    ) -> Option<&'a [u8]> {
+        let len = store.get(key, value).unwrap()?;
+        Some(&value[..len])
+    }
+
+    /// Value written in the n-th overwrite of a key
+    fn numbered(n: usize) -> [u8; 40] {
+        let mut value = [0u8; 40];
+        value[..8].copy_from_slice(&(n as u64).to_le_bytes());
+        value
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let memory = Memory::new();
+        let mut store = mount(&memory);
+        let mut value = [0u8; MAX_VALUE_LEN];
+        assert_eq!(get(&mut store, "wifi_ssid", &mut value), None);
+
+        store.set("wifi_ssid", b"home").unwrap();
+        store.set("wifi_psk", b"secret").unwrap();
+        store.set("empty", b"").unwrap();
+        assert_eq!(get(&mut store, "wifi_ssid", &mut value), Some(&b"home"[..]));
+        assert_eq!(get(&mut store, "empty", &mut value), Some(&b""[..]));
+
+        store.set("wifi_ssid", b"office").unwrap();
+        store.remove("wifi_psk").unwrap();
+        store.remove("missing").unwrap();
+        assert_eq!(
+            get(&mut store, "wifi_ssid", &mut value),
+            Some(&b"office"[..])
+        );
+        assert_eq!(get(&mut store, "wifi_psk", &mut value), None);
+
+        // the same after mounting it again
+        let mut store = mount(&memory);
+        assert_eq!(
+            get(&mut store, "wifi_ssid", &mut value),
+            Some(&b"office"[..])
+        );
+        assert_eq!(get(&mut store, "wifi_psk", &mut value), None);
+        assert_eq!(get(&mut store, "empty", &mut value), Some(&b""[..]));
+
+        store.clear().unwrap();
+        assert_eq!(get(&mut store, "wifi_ssid", &mut value), None);
+        let mut store = mount(&memory);
+        assert_eq!(get(&mut store, "wifi_ssid", &mut value), None);
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let memory = Memory::new();
+        let mut store = mount(&memory);
+        assert_eq!(store.set("", b"value"), Err(KvError::InvalidKey));
+        assert_eq!(
+            store.set("a_key_too_long_17", b"value"),
+            Err(KvError::InvalidKey)
+        );
+        assert_eq!(
+            store.set("key", &[0; MAX_VALUE_LEN + 1]),
+            Err(KvError::ValueTooLong)
+        );
+        store.set("key", &[7; MAX_VALUE_LEN]).unwrap();
+        assert_eq!(store.get("key", &mut [0; 8]), Err(KvError::BufferTooSmall));
+    }
+
+    #[test]
+    fn same_value_not_written() {
+        let memory = Memory::new();
+        let mut store = mount(&memory);
+        store.set("mqtt_host", b"broker.local").unwrap();
+        let writes = memory.borrow().writes;
+        store.set("mqtt_host", b"broker.local").unwrap();
+        store.remove("missing").unwrap();
+        assert_eq!(memory.borrow().writes, writes);
+    }
+
+    #[test]
+    fn overwrites_until_compaction() {
+        let memory = Memory::new();
+        let mut store = mount(&memory);
+        store.set("wifi_ssid", b"home").unwrap();
+        store.remove("wifi_psk").unwrap();
+        store.set("removed", b"soon").unwrap();
+        store.remove("removed").unwrap();
+
+        let mut value = [0u8; MAX_VALUE_LEN];
+        for n in 0..200 {
+            store.set("counter", &numbered(n)).unwrap();
+            assert_eq!(
+                get(&mut store, "counter", &mut value),
+                Some(&numbered(n)[..])
+            );
+        }
+        // the sectors are erased by turns
+        let erases = memory.borrow().erases;
+        assert!(
+            erases[0] >= 3 && erases[0].abs_diff(erases[1]) <= 1,
+            "{erases:?}"
+        );
+
+        let mut store = mount(&memory);
+        assert_eq!(
+            get(&mut store, "counter", &mut value),
+            Some(&numbered(199)[..])
+        );
+        assert_eq!(get(&mut store, "wifi_ssid", &mut value), Some(&b"home"[..]));
+        assert_eq!(get(&mut store, "removed", &mut value), None);
+    }
+
+    #[test]
+    fn torn_write_discarded_on_mount() {
+        let memory = Memory::new();
+        let mut store = mount(&memory);
+        store.set("wifi_ssid", b"home").unwrap();
+        memory.borrow_mut().operations_left = Some(1);
+        assert_eq!(
+            store.set("wifi_ssid", b"office"),
+            Err(KvError::Flash(PowerLoss))
+        );
+
+        memory.borrow_mut().operations_left = None;
+        let mut store = mount(&memory);
+        let mut value = [0u8; MAX_VALUE_LEN];
+        assert_eq!(get(&mut store, "wifi_ssid", &mut value), Some(&b"home"[..]));
+        // compacted: the new records go after the valid ones
+        store.set("wifi_psk", b"secret").unwrap();
+        let mut store = mount(&memory);
+        assert_eq!(get(&mut store, "wifi_ssid", &mut value), Some(&b"home"[..]));
+        assert_eq!(
+            get(&mut store, "wifi_psk", &mut value),
+            Some(&b"secret"[..])
+        );
+    }
+
+    #[test]
+    fn bad_record_crc_ends_the_log() {
+        let memory = Memory::new();
+        let mut store = mount(&memory);
+        store.set("wifi_ssid", b"home").unwrap();
+        let second = store.end as usize;
+        store.set("wifi_psk", b"secret").unwrap();
+        store.set("mqtt_host", b"broker.local").unwrap();
+        // a bit of the value of the second record lost
+        let active = store.active as usize * SECTOR;
+        memory.borrow_mut().bytes[active + second + RECORD_HEADER_LEN + 8] &= 0xfe;
+
+        let mut store = mount(&memory);
+        let mut value = [0u8; MAX_VALUE_LEN];
+        assert_eq!(get(&mut store, "wifi_ssid", &mut value), Some(&b"home"[..]));
+        assert_eq!(get(&mut store, "wifi_psk", &mut value), None);
+        assert_eq!(get(&mut store, "mqtt_host", &mut value), None);
+    }
+
+    #[test]
+    fn power_loss_during_compaction() {
+        // the store with a sector full, so that the next write compacts it
+        let memory = Memory::new();
+        let mut store = mount(&memory);
+        store.set("wifi_ssid", b"home").unwrap();
+        let mut n = 0;
+        let record_len = align(RECORD_HEADER_LEN + "counter".len() + 40) as u32;
+        while store.end + record_len <= SECTOR as u32 {
+            store.set("counter", &numbered(n)).unwrap();
+            n += 1;
+        }
+        let full = memory.borrow().clone();
+
+        let mut value = [0u8; MAX_VALUE_LEN];
+        for cut in 1.. {
+            let memory = RefCell::new(full.clone());
+            let mut store = mount(&memory);
+            memory.borrow_mut().operations_left = Some(cut);
+            let done = store.set("counter", &numbered(n)).is_ok();
+
+            memory.borrow_mut().operations_left = None;
+            let mut store = mount(&memory);
+            assert_eq!(
+                get(&mut store, "wifi_ssid", &mut value),
+                Some(&b"home"[..]),
+                "power lost after {cut} operations"
+            );
+            let counter = get(&mut store, "counter", &mut value).unwrap();
+            if done {
+                assert_eq!(counter, &numbered(n)[..]);
+                break;
+            }
+            assert!(
+                counter == numbered(n - 1) || counter == numbered(n),
+                "power lost after {cut} operations"
+            );
+            // and it keeps working
+            store.set("counter", &numbered(n + 1)).unwrap();
+            let mut store = mount(&memory);
+            assert_eq!(
+                get(&mut store, "counter", &mut value),
+                Some(&numbered(n + 1)[..])
+            );
+        }
+    }
+
+    #[test]
+    fn full_store() {
+        let memory = Memory::new();
+        let mut store = mount(&memory);
+        let value = [0x5a; 200];
+        let mut keys = 0;
+        let result = loop {
+            let key = [b'k', b'0' + keys as u8];
+            let key = core::str::from_utf8(&key).unwrap();
+            match store.set(key, &value) {
+                Ok(()) => keys += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(result, KvError::Full);
+        let record_len = align(RECORD_HEADER_LEN + 2 + value.len());
+        assert_eq!(keys, (SECTOR - SECTOR_HEADER_LEN as usize) / record_len);
+
+        // the values are kept, and there is room after removing one
+        let mut read = [0u8; MAX_VALUE_LEN];
+        for n in 0..keys {
+            let key = [b'k', b'0' + n as u8];
+            let key = core::str::from_utf8(&key).unwrap();
+            assert_eq!(get(&mut store, key, &mut read), Some(&value[..]));
+        }
+        store.remove("k0").unwrap();
+        store.set("new", &value).unwrap();
+        let mut store = mount(&memory);
+        assert_eq!(get(&mut store, "k0", &mut read), None);
+        assert_eq!(get(&mut store, "new", &mut read), Some(&value[..]));
+    }
+}
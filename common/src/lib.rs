@@ -0,0 +1,18 @@
+//! Code shared by the embsens (no_std) and sensor (std) firmwares.
+//! Everything here is no_std and doesn't allocate, so it also builds and
+//! runs on the host.
+#![no_std]
+
+pub mod backoff;
+pub mod buffer;
+pub mod crc;
+pub mod dns;
+pub mod htu21d;
+pub mod imu;
+pub mod kvstore;
+pub mod mqtt;
+pub mod net;
+pub mod provisioning;
+pub mod sensor;
+pub mod topic;
+pub mod x509;
@@ -0,0 +1,691 @@
+//! Parts of the MQTT 3.1.1 client that don't depend on the transport: the
+//! framing of the bytes received in packets, and the table of the publishes
+//! waiting for their acknowledgement and their retransmission.
+
+use heapless::{LinearMap, Vec};
+
+/// DUP flag in the fixed header of a PUBLISH packet
+pub const DUP_FLAG: u8 = 0b0000_1000;
+/// Maximum number of bytes of the remaining length
+const MAX_LENGTH_BYTES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The remaining length has more than 4 bytes. The data received are
+    /// discarded because the packet boundaries are lost.
+    MalformedLength,
+    /// The packet, of this total length, doesn't fit in the buffer. It is
+    /// skipped.
+    TooLarge(usize),
+}
+
+/// Returns the total length (fixed header included) of the MQTT packet at the
+/// beginning of `buf`, or None if the fixed header is not complete yet.
+/// The remaining length is a variable length integer of 1 to 4 bytes, 7 bits
+/// per byte with the most significant bit as continuation flag.
+pub fn frame_len(buf: &[u8]) -> Result<Option<usize>, FrameError> {
+    let mut remaining_len: usize = 0;
+    for pos in 0..MAX_LENGTH_BYTES {
+        let byte = match buf.get(1 + pos) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        remaining_len += ((byte & 0x7f) as usize) << (7 * pos);
+        if byte & 0x80 == 0 {
+            return Ok(Some(1 + pos + 1 + remaining_len));
+        }
+    }
+    Err(FrameError::MalformedLength)
+}
+
+/// Bytes received from the server, with room for packets of up to N bytes.
+/// A packet can arrive split in several reads and a read can contain several
+/// packets, so the bytes of an incomplete packet are kept until the rest
+/// arrives.
+pub struct FrameBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+    // Bytes of a packet too large still to be received and discarded
+    skip: usize,
+}
+
+impl<const N: usize> FrameBuffer<N> {
+    pub const fn new() -> Self {
+        FrameBuffer {
+            bytes: [0u8; N],
+            len: 0,
+            skip: 0,
+        }
+    }
+
+    /// Discards the data received, after connecting again
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.skip = 0;
+    }
+
+    /// Free part of the buffer, where the next bytes are read
+    pub fn spare(&mut self) -> &mut [u8] {
+        &mut self.bytes[self.len..]
+    }
+
+    /// Adds the `len` bytes just read in `spare()`. Those of the tail of a
+    /// packet too large are dropped.
+    pub fn commit(&mut self, len: usize) {
+        let len = len.min(N - self.len);
+        let skipped = len.min(self.skip);
+        self.skip -= skipped;
+        self.bytes
+            .copy_within(self.len + skipped..self.len + len, self.len);
+        self.len += len - skipped;
+    }
+
+    /// Calls `handle` with each complete packet received, in order, and
+    /// keeps the bytes of the incomplete one.
+    pub fn frames(&mut self, mut handle: impl FnMut(&[u8])) -> Result<(), FrameError> {
+        let mut start = 0;
+        let result = loop {
+            let data = &self.bytes[start..self.len];
+            let frame_len = match frame_len(data) {
+                Ok(Some(frame_len)) => frame_len,
+                Ok(None) => break Ok(()),
+                Err(e) => {
+                    start = self.len;
+                    break Err(e);
+                }
+            };
+            if frame_len > N {
+                self.skip = frame_len - data.len();
+                start = self.len;
+                break Err(FrameError::TooLarge(frame_len));
+            }
+            if frame_len > data.len() {
+                // wait for the rest of the packet
+                break Ok(());
+            }
+            handle(&data[..frame_len]);
+            start += frame_len;
+        };
+
+        self.bytes.copy_within(start..self.len, 0);
+        self.len -= start;
+        result
+    }
+}
+
+impl<const N: usize> Default for FrameBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Acknowledgement expected from the server for an in-flight packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Awaiting {
+    /// QoS 1 publish
+    Puback,
+    /// QoS 2 publish
+    Pubrec,
+    /// QoS 2 pubrel, after the server has received the publish
+    Pubcomp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InFlightError {
+    /// There is no room for more packets
+    Full,
+    /// The packet doesn't fit in an entry of the table
+    TooLong,
+}
+
+/// Encoded packet sent with QoS 1 or 2 and waiting for its acknowledgement.
+/// For QoS 2 it holds the PUBLISH until PUBREC and then the PUBREL.
+struct Entry<const SIZE: usize> {
+    awaiting: Awaiting,
+    len: usize,
+    bytes: [u8; SIZE],
+    // None when it has to be sent again as a new packet, in a new session
+    sent_millis: Option<u64>,
+    retries: u8,
+}
+
+/// Packets not acknowledged yet by the server, by packet identifier `P`,
+/// with room for N packets of up to SIZE bytes. A packet is resent each
+/// time its acknowledgement doesn't arrive in `retransmit_millis`, up to
+/// `max_retries` times; then it is given up.
+pub struct InFlight<P, const N: usize, const SIZE: usize> {
+    entries: LinearMap<P, Entry<SIZE>, N>,
+    retransmit_millis: u64,
+    max_retries: u8,
+}
+
+impl<P, const N: usize, const SIZE: usize> InFlight<P, N, SIZE>
+where
+    P: Copy + Eq,
+{
+    pub const fn new(retransmit_millis: u64, max_retries: u8) -> Self {
+        InFlight {
+            entries: LinearMap::new(),
+            retransmit_millis,
+            max_retries,
+        }
+    }
+
+    /// Sets how long to wait for an acknowledgement before resending the
+    /// packet, and how many times to resend it before giving up.
+    pub fn set_retransmit(&mut self, retransmit_millis: u64, max_retries: u8) {
+        self.retransmit_millis = retransmit_millis;
+        self.max_retries = max_retries;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.entries.len() == N
+    }
+
+    pub fn contains(&self, pid: &P) -> bool {
+        self.entries.contains_key(pid)
+    }
+
+    /// Acknowledgement expected for the packet with this identifier
+    pub fn awaiting(&self, pid: &P) -> Option<Awaiting> {
+        self.entries.get(pid).map(|entry| entry.awaiting)
+    }
+
+    /// Adds a packet sent at `now_millis`. A packet with the same identifier
+    /// is replaced.
+    pub fn insert(
+        &mut self,
+        pid: P,
+        awaiting: Awaiting,
+        packet: &[u8],
+        now_millis: u64,
+    ) -> Result<(), InFlightError> {
+        if packet.len() > SIZE {
+            return Err(InFlightError::TooLong);
+        }
+        let mut bytes = [0u8; SIZE];
+        bytes[..packet.len()].copy_from_slice(packet);
+        let entry = Entry {
+            awaiting,
+            len: packet.len(),
+            bytes,
+            sent_millis: Some(now_millis),
+            retries: 0,
+        };
+        match self.entries.insert(pid, entry) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(InFlightError::Full),
+        }
+    }
+
+    /// The delivery is completed (PUBACK for QoS 1, PUBCOMP for QoS 2): the
+    /// packet is no longer resent. Returns false if it was not in flight.
+    pub fn complete(&mut self, pid: &P) -> bool {
+        self.entries.remove(pid).is_some()
+    }
+
+    /// The server has received the QoS 2 publish (PUBREC): from now on the
+    /// PUBREL sent at `now_millis` is resent instead, until PUBCOMP.
+    /// Returns false if the publish was not in flight.
+    pub fn release(&mut self, pid: &P, pubrel: &[u8], now_millis: u64) -> bool {
+        match self.entries.get_mut(pid) {
+            Some(entry) if pubrel.len() <= SIZE => {
+                entry.bytes[..pubrel.len()].copy_from_slice(pubrel);
+                entry.len = pubrel.len();
+                entry.awaiting = Awaiting::Pubcomp;
+                entry.sent_millis = Some(now_millis);
+                entry.retries = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// After connecting with a clean session the server has forgotten the
+    /// packet identifiers of the previous one. The publishes not
+    /// acknowledged are sent again as new ones in the next `retransmit`,
+    /// without the DUP flag and with all their retries. The PUBRELs are
+    /// dropped: the server got those publishes before PUBREC.
+    pub fn new_session(&mut self) {
+        let released: Vec<P, N> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.awaiting == Awaiting::Pubcomp)
+            .map(|(pid, _)| *pid)
+            .collect();
+        for pid in released {
+            self.entries.remove(&pid);
+        }
+        for (_, entry) in self.entries.iter_mut() {
+            entry.bytes[0] &= !DUP_FLAG;
+            entry.sent_millis = None;
+            entry.retries = 0;
+        }
+    }
+
+    /// Resends the packets whose acknowledgement has not arrived in time:
+    /// `resend` is called with each of them, with the DUP flag set for the
+    /// publishes already sent in this session, and returns false if it could
+    /// not be sent (it is tried again the next time). Every packet that has exhausted its retries is
+    /// removed and passed to `expired`.
+    pub fn retransmit(
+        &mut self,
+        now_millis: u64,
+        mut resend: impl FnMut(&P, &[u8]) -> bool,
+        mut expired: impl FnMut(P),
+    ) {
+        let mut given_up: Vec<P, N> = Vec::new();
+        for (pid, entry) in self.entries.iter_mut() {
+            let (due, resending) = match entry.sent_millis {
+                Some(sent_millis) => (now_millis >= sent_millis + self.retransmit_millis, true),
+                None => (true, false),
+            };
+            if !due {
+                continue;
+            }
+            if resending && entry.retries >= self.max_retries {
+                // it fits, there are at most N entries
+                given_up.push(*pid).ok();
+                continue;
+            }
+            if resending && entry.awaiting != Awaiting::Pubcomp {
+                entry.bytes[0] |= DUP_FLAG;
+            }
+            if resend(pid, &entry.bytes[..entry.len]) {
+                if resending {
+                    entry.retries += 1;
+                }
+                entry.sent_millis = Some(now_millis);
+            }
+        }
+        for pid in given_up {
+            self.entries.remove(&pid);
+            expired(pid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETRANSMIT: u64 = 1_000;
+    const RETRIES: u8 = 2;
+
+    type Table = InFlight<u16, 4, 64>;
+    type Frames = Vec<Vec<u8, 256>, 8>;
+
+    /// Packets received: CONNACK, SUBACK, a PUBLISH with a remaining length
+    /// of 2 bytes and PINGRESP
+    fn stream() -> (Vec<u8, 512>, Frames) {
+        let mut publish: Vec<u8, 256> = Vec::new();
+        publish
+            .extend_from_slice(&[0x30, 0x83, 0x01, 0, 1, b't'])
+            .unwrap();
+        publish.resize(3 + 0x83, b'x').unwrap();
+        let packets: [&[u8]; 4] = [
+            &[0x20, 0x02, 0x00, 0x00],
+            &[0x90, 0x03, 0x00, 0x01, 0x00],
+            &publish,
+            &[0xd0, 0x00],
+        ];
+        let mut stream = Vec::new();
+        let mut frames = Vec::new();
+        for packet in packets {
+            stream.extend_from_slice(packet).unwrap();
+            frames.push(Vec::from_slice(packet).unwrap()).unwrap();
+        }
+        (stream, frames)
+    }
+
+    /// Feeds the chunks to the buffer, as if read one after another, and
+    /// returns the packets and errors seen
+    fn receive<const N: usize>(
+        buffer: &mut FrameBuffer<N>,
+        chunks: &[&[u8]],
+    ) -> (Frames, Vec<FrameError, 4>) {
+        let mut frames = Vec::new();
+        let mut errors = Vec::new();
+        for chunk in chunks {
+            let mut chunk = *chunk;
+            while !chunk.is_empty() {
+                let spare = buffer.spare();
+                let len = spare.len().min(chunk.len());
+                spare[..len].copy_from_slice(&chunk[..len]);
+                buffer.commit(len);
+                chunk = &chunk[len..];
+                let result = buffer.frames(|frame| {
+                    frames.push(Vec::from_slice(frame).unwrap()).unwrap();
+                });
+                if let Err(e) = result {
+                    errors.push(e).unwrap();
+                }
+            }
+        }
+        (frames, errors)
+    }
+
+    /// PUBLISH with QoS 1 (or 2) of `payload` in topic "t"
+    fn publish(qos: u8, pid: u16, payload: &[u8]) -> Vec<u8, 64> {
+        let mut packet = Vec::new();
+        packet.push(0x30 | qos << 1).unwrap();
+        packet.push((2 + 1 + 2 + payload.len()) as u8).unwrap();
+        packet.extend_from_slice(&[0, 1, b't']).unwrap();
+        packet.extend_from_slice(&pid.to_be_bytes()).unwrap();
+        packet.extend_from_slice(payload).unwrap();
+        packet
+    }
+
+    fn pubrel(pid: u16) -> [u8; 4] {
+        let pid = pid.to_be_bytes();
+        [0x62, 2, pid[0], pid[1]]
+    }
+
+    /// Scripted broker: it records the packets resent and the ones given up
+    #[derive(Default)]
+    struct Broker {
+        resent: Vec<(u16, Vec<u8, 64>), 8>,
+        expired: Vec<u16, 8>,
+    }
+
+    impl Broker {
+        fn tick(&mut self, table: &mut Table, now_millis: u64) {
+            let resent = &mut self.resent;
+            let expired = &mut self.expired;
+            table.retransmit(
+                now_millis,
+                |pid, packet| {
+                    resent
+                        .push((*pid, Vec::from_slice(packet).unwrap()))
+                        .unwrap();
+                    true
+                },
+                |pid| expired.push(pid).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn remaining_length() {
+        assert_eq!(frame_len(&[0xd0, 0x00]), Ok(Some(2)));
+        assert_eq!(frame_len(&[0x30, 0x7f]), Ok(Some(2 + 127)));
+        assert_eq!(frame_len(&[0x30, 0x80, 0x01]), Ok(Some(3 + 128)));
+        assert_eq!(frame_len(&[0x30, 0xff, 0x7f]), Ok(Some(3 + 16_383)));
+        assert_eq!(frame_len(&[0x30, 0x80, 0x80, 0x01]), Ok(Some(4 + 16_384)));
+        assert_eq!(
+            frame_len(&[0x30, 0xff, 0xff, 0x7f]),
+            Ok(Some(4 + 2_097_151))
+        );
+        assert_eq!(
+            frame_len(&[0x30, 0x80, 0x80, 0x80, 0x01]),
+            Ok(Some(5 + 2_097_152))
+        );
+        assert_eq!(
+            frame_len(&[0x30, 0xff, 0xff, 0xff, 0x7f]),
+            Ok(Some(5 + 268_435_455))
+        );
+    }
+
+    #[test]
+    fn incomplete_fixed_header() {
+        assert_eq!(frame_len(&[]), Ok(None));
+        assert_eq!(frame_len(&[0x30]), Ok(None));
+        assert_eq!(frame_len(&[0x30, 0x80]), Ok(None));
+        assert_eq!(frame_len(&[0x30, 0xff, 0xff, 0xff]), Ok(None));
+    }
+
+    #[test]
+    fn malformed_remaining_length() {
+        assert_eq!(
+            frame_len(&[0x30, 0xff, 0xff, 0xff, 0xff]),
+            Err(FrameError::MalformedLength)
+        );
+        assert_eq!(
+            frame_len(&[0x30, 0x80, 0x80, 0x80, 0x80, 0x01]),
+            Err(FrameError::MalformedLength)
+        );
+    }
+
+    #[test]
+    fn stream_split_at_every_offset() {
+        let (stream, expected) = stream();
+        for split in 0..=stream.len() {
+            let mut buffer = FrameBuffer::<256>::new();
+            let (first, second) = stream.split_at(split);
+            let (frames, errors) = receive(&mut buffer, &[first, second]);
+            assert_eq!(frames, expected, "split at {}", split);
+            assert!(errors.is_empty());
+        }
+    }
+
+    #[test]
+    fn stream_split_at_every_pair_of_offsets() {
+        let (stream, expected) = stream();
+        for first in 0..=stream.len() {
+            for second in first..=stream.len() {
+                let mut buffer = FrameBuffer::<256>::new();
+                let chunks = [&stream[..first], &stream[first..second], &stream[second..]];
+                let (frames, errors) = receive(&mut buffer, &chunks);
+                assert_eq!(frames, expected, "split at {} and {}", first, second);
+                assert!(errors.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn stream_byte_by_byte() {
+        let (stream, expected) = stream();
+        let mut buffer = FrameBuffer::<256>::new();
+        let chunks: Vec<&[u8], 512> = stream.chunks(1).collect();
+        let (frames, errors) = receive(&mut buffer, &chunks);
+        assert_eq!(frames, expected);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn packet_too_large_is_skipped() {
+        // with room for 64 bytes the PUBLISH of 134 doesn't fit
+        let (stream, expected) = stream();
+        for split in 0..=stream.len() {
+            let mut buffer = FrameBuffer::<64>::new();
+            let (first, second) = stream.split_at(split);
+            let (frames, errors) = receive(&mut buffer, &[first, second]);
+            assert_eq!(frames[..], [&expected[..2], &expected[3..]].concat()[..]);
+            assert_eq!(
+                errors[..],
+                [FrameError::TooLarge(134)],
+                "split at {}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_length_discards_the_data() {
+        let malformed = [0x30, 0xff, 0xff, 0xff, 0xff];
+        let pingresp = [0xd0, 0x00];
+        for split in 0..=malformed.len() {
+            let mut buffer = FrameBuffer::<64>::new();
+            let (first, second) = malformed.split_at(split);
+            let (frames, errors) = receive(&mut buffer, &[first, second]);
+            assert!(frames.is_empty());
+            assert_eq!(errors[..], [FrameError::MalformedLength]);
+            // the packets after the lost bytes are framed again
+            let (frames, errors) = receive(&mut buffer, &[&pingresp]);
+            assert_eq!(frames[..], [pingresp]);
+            assert!(errors.is_empty());
+        }
+    }
+
+    #[test]
+    fn puback_completes_the_publish() {
+        let mut table = Table::new(RETRANSMIT, RETRIES);
+        let mut broker = Broker::default();
+        table
+            .insert(1, Awaiting::Puback, &publish(1, 1, b"20.5"), 0)
+            .unwrap();
+        assert!(table.complete(&1));
+        assert!(table.is_empty());
+        broker.tick(&mut table, 10 * RETRANSMIT);
+        assert!(broker.resent.is_empty());
+        assert!(broker.expired.is_empty());
+        // a duplicated PUBACK is ignored
+        assert!(!table.complete(&1));
+    }
+
+    #[test]
+    fn resends_with_dup_when_the_ack_is_late() {
+        let mut table = Table::new(RETRANSMIT, RETRIES);
+        let mut broker = Broker::default();
+        let packet = publish(1, 7, b"20.5");
+        table.insert(7, Awaiting::Puback, &packet, 0).unwrap();
+
+        broker.tick(&mut table, RETRANSMIT - 1);
+        assert!(broker.resent.is_empty());
+
+        broker.tick(&mut table, RETRANSMIT);
+        assert_eq!(broker.resent.len(), 1);
+        let (pid, resent) = &broker.resent[0];
+        assert_eq!(*pid, 7);
+        assert_eq!(resent[0], packet[0] | DUP_FLAG);
+        assert_eq!(resent[1..], packet[1..]);
+
+        // the timer starts again from the resend
+        broker.tick(&mut table, RETRANSMIT + 1);
+        assert_eq!(broker.resent.len(), 1);
+        table.complete(&7);
+        broker.tick(&mut table, 5 * RETRANSMIT);
+        assert_eq!(broker.resent.len(), 1);
+        assert!(broker.expired.is_empty());
+    }
+
+    #[test]
+    fn gives_up_every_expired_publish_at_once() {
+        let mut table = Table::new(RETRANSMIT, RETRIES);
+        let mut broker = Broker::default();
+        for pid in 1..=4 {
+            table
+                .insert(pid, Awaiting::Puback, &publish(1, pid, b"x"), 0)
+                .unwrap();
+        }
+        assert!(table.is_full());
+        assert_eq!(
+            table.insert(5, Awaiting::Puback, &publish(1, 5, b"x"), 0),
+            Err(InFlightError::Full)
+        );
+
+        for retry in 1..=RETRIES as u64 {
+            broker.tick(&mut table, retry * RETRANSMIT);
+        }
+        assert_eq!(broker.resent.len(), 4 * RETRIES as usize);
+        assert!(broker.expired.is_empty());
+
+        broker.tick(&mut table, (RETRIES as u64 + 1) * RETRANSMIT);
+        assert_eq!(broker.resent.len(), 4 * RETRIES as usize);
+        let mut expired = broker.expired.clone();
+        expired.sort_unstable();
+        assert_eq!(expired[..], [1, 2, 3, 4]);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn qos2_resends_pubrel_without_dup_after_pubrec() {
+        let mut table = Table::new(RETRANSMIT, RETRIES);
+        let mut broker = Broker::default();
+        table
+            .insert(3, Awaiting::Pubrec, &publish(2, 3, b"on"), 0)
+            .unwrap();
+
+        broker.tick(&mut table, RETRANSMIT);
+        assert_eq!(broker.resent[0].1[0], 0x34 | DUP_FLAG);
+
+        assert!(table.release(&3, &pubrel(3), RETRANSMIT + 10));
+        assert_eq!(table.awaiting(&3), Some(Awaiting::Pubcomp));
+        broker.tick(&mut table, 2 * RETRANSMIT);
+        assert_eq!(broker.resent.len(), 1);
+        broker.tick(&mut table, 2 * RETRANSMIT + 10);
+        assert_eq!(broker.resent.len(), 2);
+        assert_eq!(broker.resent[1].1[..], pubrel(3));
+
+        assert!(table.complete(&3));
+        assert!(!table.release(&3, &pubrel(3), 0));
+    }
+
+    #[test]
+    fn publishes_sent_again_in_a_new_session() {
+        let mut table = Table::new(RETRANSMIT, RETRIES);
+        let mut broker = Broker::default();
+        table
+            .insert(1, Awaiting::Puback, &publish(1, 1, b"a"), 0)
+            .unwrap();
+        table
+            .insert(2, Awaiting::Pubrec, &publish(2, 2, b"b"), 0)
+            .unwrap();
+        table
+            .insert(3, Awaiting::Pubrec, &publish(2, 3, b"c"), 0)
+            .unwrap();
+        assert!(table.release(&3, &pubrel(3), 0));
+        // resent once with DUP, then the connection is lost
+        broker.tick(&mut table, RETRANSMIT);
+        assert_eq!(broker.resent.len(), 3);
+
+        table.new_session();
+        assert_eq!(table.len(), 2);
+        assert!(!table.contains(&3));
+        broker.resent.clear();
+        broker.tick(&mut table, RETRANSMIT + 1);
+        assert_eq!(broker.resent.len(), 2);
+        assert_eq!(broker.resent[0].1[..], publish(1, 1, b"a")[..]);
+        assert_eq!(broker.resent[1].1[..], publish(2, 2, b"b")[..]);
+
+        // and then resent with DUP, with all the retries
+        for retry in 1..=RETRIES as u64 {
+            broker.tick(&mut table, RETRANSMIT + 1 + retry * RETRANSMIT);
+        }
+        assert_eq!(broker.resent.len(), 2 + 2 * RETRIES as usize);
+        assert_eq!(broker.resent[2].1[0], 0x32 | DUP_FLAG);
+        assert!(broker.expired.is_empty());
+    }
+
+    #[test]
+    fn a_resend_not_sent_is_tried_again() {
+        let mut table = Table::new(RETRANSMIT, RETRIES);
+        table
+            .insert(1, Awaiting::Puback, &publish(1, 1, b"a"), 0)
+            .unwrap();
+        let mut expired = 0;
+        // the queue of the client is full: the retry is not counted
+        for _ in 0..5 {
+            table.retransmit(RETRANSMIT, |_, _| false, |_| expired += 1);
+        }
+        assert_eq!(expired, 0);
+        let mut resent = 0;
+        table.retransmit(
+            RETRANSMIT,
+            |_, _| {
+                resent += 1;
+                true
+            },
+            |_| expired += 1,
+        );
+        assert_eq!((resent, expired), (1, 0));
+    }
+
+    #[test]
+    fn too_long_packet() {
+        let mut table = InFlight::<u16, 4, 8>::new(RETRANSMIT, RETRIES);
+        assert_eq!(
+            table.insert(1, Awaiting::Puback, &publish(1, 1, b"too long"), 0),
+            Err(InFlightError::TooLong)
+        );
+        assert!(table.is_empty());
+    }
+}
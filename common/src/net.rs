@@ -0,0 +1,78 @@
+//! Checks of the network addresses entered when provisioning the device,
+//! the same for both firmwares.
+
+use crate::dns;
+
+/// Maximum length of a host name, without the final dot
+pub const MAX_HOST_LEN: usize = 253;
+/// Maximum length of each label of a host name
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// Host name (labels of letters, digits and '-') or IPv4 address
+pub fn is_valid_host(host: &str) -> bool {
+    if dns::parse_ipv4(host).is_some() {
+        return true;
+    }
+    !host.is_empty()
+        && host.len() <= MAX_HOST_LEN
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL_LEN
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_hosts() {
+        for host in [
+            "mqtt",
+            "mqtt.example.com",
+            "my-broker.local",
+            "192.168.1.10",
+        ] {
+            assert!(is_valid_host(host), "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn invalid_hosts() {
+        for host in [
+            "",
+            "-mqtt.example.com",
+            "mqtt-.example.com",
+            "mqtt..example.com",
+            "mqtt.example.com.",
+            "mqtt_broker",
+            "mqtt://example.com",
+            "example.com:1883",
+            "bröker.local",
+        ] {
+            assert!(!is_valid_host(host), "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn lengths() {
+        let label = [b'a'; MAX_LABEL_LEN + 1];
+        let label = core::str::from_utf8(&label).unwrap();
+        assert!(is_valid_host(&label[..MAX_LABEL_LEN]));
+        assert!(!is_valid_host(label));
+
+        // 4 labels of 63 bytes and their dots: 255 bytes
+        let mut name = [b'a'; 4 * (MAX_LABEL_LEN + 1) - 1];
+        for dot in (MAX_LABEL_LEN..name.len()).step_by(MAX_LABEL_LEN + 1) {
+            name[dot] = b'.';
+        }
+        let name = core::str::from_utf8(&name).unwrap();
+        assert!(is_valid_host(&name[..MAX_HOST_LEN]));
+        assert!(!is_valid_host(&name[..MAX_HOST_LEN + 1]));
+    }
+}
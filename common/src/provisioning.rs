@@ -0,0 +1,323 @@
+//! Provisioning of the device from a phone: the values written to the
+//! characteristics of the provisioning service, their validation, and the
+//! status reported back.
+//!
+//! Values longer than a packet arrive in pieces, each with its offset, so
+//! they are kept raw until they are applied.
+
+use crate::net::is_valid_host;
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+/// Maximum length of a value of the form
+pub const MAX_VALUE_LEN: usize = 128;
+
+const MQTT_PORT: u16 = 1883;
+const MQTTS_PORT: u16 = 8883;
+
+/// Field of the form
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    WifiSsid,
+    WifiPsk,
+    /// mqtt://host:port or mqtts://host:port
+    Broker,
+    /// user:password
+    Credentials,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormError {
+    /// The value doesn't fit, or the offset is past its end
+    TooLong(Field),
+    Invalid(Field),
+    /// A field required is empty
+    Missing(Field),
+}
+
+/// Result of the provisioning, reported to the phone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Waiting for the configuration
+    Idle,
+    /// The configuration applied is not valid
+    Invalid,
+    /// The time to provision the device after the boot is over
+    Closed,
+    /// The configuration is stored, the device restarts to try it
+    Saved,
+    WifiConnecting,
+    WifiFailed,
+    WifiConnected,
+    MqttFailed,
+    MqttConnected,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Idle => "idle",
+            Status::Invalid => "invalid",
+            Status::Closed => "closed",
+            Status::Saved => "saved",
+            Status::WifiConnecting => "wifi_connecting",
+            Status::WifiFailed => "wifi_failed",
+            Status::WifiConnected => "wifi_connected",
+            Status::MqttFailed => "mqtt_failed",
+            Status::MqttConnected => "mqtt_connected",
+        }
+    }
+}
+
+/// MQTT server of a broker URI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Broker<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub tls: bool,
+}
+
+/// Parses a broker URI: [mqtt://|mqtts://]host[:port]. The port defaults
+/// to the one of the scheme.
+pub fn parse_broker_uri(uri: &str) -> Option<Broker<'_>> {
+    let (tls, rest) = if let Some(rest) = uri.strip_prefix("mqtts://") {
+        (true, rest)
+    } else {
+        (false, uri.strip_prefix("mqtt://").unwrap_or(uri))
+    };
+    let rest = rest.strip_suffix('/').unwrap_or(rest);
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok().filter(|port| *port != 0)?),
+        None => (rest, if tls { MQTTS_PORT } else { MQTT_PORT }),
+    };
+    if !is_valid_host(host) {
+        return None;
+    }
+    Some(Broker { host, port, tls })
+}
+
+/// Values applied, checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings<'a> {
+    pub wifi_ssid: &'a str,
+    pub wifi_psk: &'a str,
+    pub broker: Broker<'a>,
+    pub mqtt_user: Option<&'a str>,
+    pub mqtt_passwd: Option<&'a str>,
+}
+
+/// Values written to the characteristics
+#[derive(Debug, Clone, Default)]
+pub struct Form {
+    wifi_ssid: Vec<u8, MAX_VALUE_LEN>,
+    wifi_psk: Vec<u8, MAX_VALUE_LEN>,
+    broker: Vec<u8, MAX_VALUE_LEN>,
+    credentials: Vec<u8, MAX_VALUE_LEN>,
+}
+
+impl Form {
+    pub const fn new() -> Self {
+        Form {
+            wifi_ssid: Vec::new(),
+            wifi_psk: Vec::new(),
+            broker: Vec::new(),
+            credentials: Vec::new(),
+        }
+    }
+
+    /// Form with the values of a configuration, so the fields not written
+    /// keep them
+    pub fn from_settings(settings: &Settings) -> Result<Self, FormError> {
+        let mut form = Form::new();
+        form.write(Field::WifiSsid, 0, settings.wifi_ssid.as_bytes())?;
+        form.write(Field::WifiPsk, 0, settings.wifi_psk.as_bytes())?;
+
+        let broker = &settings.broker;
+        let mut uri: String<MAX_VALUE_LEN> = String::new();
+        let scheme = if broker.tls { "mqtts" } else { "mqtt" };
+        write!(uri, "{}://{}:{}", scheme, broker.host, broker.port)
+            .map_err(|_| FormError::TooLong(Field::Broker))?;
+        form.write(Field::Broker, 0, uri.as_bytes())?;
+
+        // a password without user can't be written in the form
+        if let Some(user) = settings.mqtt_user {
+            form.write(Field::Credentials, 0, user.as_bytes())?;
+            if let Some(passwd) = settings.mqtt_passwd {
+                let offset = user.len();
+                form.write(Field::Credentials, offset, b":")?;
+                form.write(Field::Credentials, offset + 1, passwd.as_bytes())?;
+            }
+        }
+        Ok(form)
+    }
+
+    /// Writes a piece of a value at `offset`. A write at offset 0 replaces
+    /// the value.
+    pub fn write(&mut self, field: Field, offset: usize, data: &[u8]) -> Result<(), FormError> {
+        let value = self.value_mut(field);
+        if offset > value.len() {
+            return Err(FormError::TooLong(field));
+        }
+        value.truncate(offset);
+        value
+            .extend_from_slice(data)
+            .map_err(|_| FormError::TooLong(field))
+    }
+
+    /// Value of a field, as written
+    pub fn value(&self, field: Field) -> &[u8] {
+        match field {
+            Field::WifiSsid => &self.wifi_ssid,
+            Field::WifiPsk => &self.wifi_psk,
+            Field::Broker => &self.broker,
+            Field::Credentials => &self.credentials,
+        }
+    }
+
+    fn value_mut(&mut self, field: Field) -> &mut Vec<u8, MAX_VALUE_LEN> {
+        match field {
+            Field::WifiSsid => &mut self.wifi_ssid,
+            Field::WifiPsk => &mut self.wifi_psk,
+            Field::Broker => &mut self.broker,
+            Field::Credentials => &mut self.credentials,
+        }
+    }
+
+    /// Checks the values: the SSID (up to 32 bytes) and the broker are
+    /// required, the password of the wifi is empty (open network) or has 8
+    /// to 63 characters, the credentials are optional.
+    pub fn settings(&self) -> Result<Settings<'_>, FormError> {
+        let text =
+            |field| core::str::from_utf8(self.value(field)).map_err(|_| FormError::Invalid(field));
+
+        let wifi_ssid = text(Field::WifiSsid)?;
+        if wifi_ssid.is_empty() {
+            return Err(FormError::Missing(Field::WifiSsid));
+        }
+        if wifi_ssid.len() > 32 {
+            return Err(FormError::TooLong(Field::WifiSsid));
+        }
+
+        let wifi_psk = text(Field::WifiPsk)?;
+        if !wifi_psk.is_empty() && !(8..=63).contains(&wifi_psk.len()) || !wifi_psk.is_ascii() {
+            return Err(FormError::Invalid(Field::WifiPsk));
+        }
+
+        let broker = text(Field::Broker)?;
+        if broker.is_empty() {
+            return Err(FormError::Missing(Field::Broker));
+        }
+        let broker = parse_broker_uri(broker).ok_or(FormError::Invalid(Field::Broker))?;
+
+        let credentials = text(Field::Credentials)?;
+        let (mqtt_user, mqtt_passwd) = match credentials.split_once(':') {
+            _ if credentials.is_empty() => (None, None),
+            Some(("", _)) => return Err(FormError::Invalid(Field::Credentials)),
+            Some((user, passwd)) => (Some(user), Some(passwd)),
+            None => (Some(credentials), None),
+        };
+
+        Ok(Settings {
+            wifi_ssid,
+            wifi_psk,
+            broker,
+            mqtt_user,
+            mqtt_passwd,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings<'static> {
+        Settings {
+            wifi_ssid: "home",
+            wifi_psk: "wifi secret",
+            broker: Broker {
+                host: "broker.local",
+                port: 8883,
+                tls: true,
+            },
+            mqtt_user: Some("sensor"),
+            mqtt_passwd: Some("mqtt:secret"),
+        }
+    }
+
+    #[test]
+    fn form_from_settings() {
+        let form = Form::from_settings(&settings()).unwrap();
+        assert_eq!(form.value(Field::Broker), b"mqtts://broker.local:8883");
+        assert_eq!(form.value(Field::Credentials), b"sensor:mqtt:secret");
+        assert_eq!(form.settings(), Ok(settings()));
+
+        let open = Settings {
+            wifi_psk: "",
+            mqtt_user: Some("sensor"),
+            mqtt_passwd: None,
+            ..settings()
+        };
+        assert_eq!(Form::from_settings(&open).unwrap().settings(), Ok(open));
+        let anonymous = Settings {
+            mqtt_user: None,
+            mqtt_passwd: None,
+            ..settings()
+        };
+        let form = Form::from_settings(&anonymous).unwrap();
+        assert_eq!(form.value(Field::Credentials), b"");
+        assert_eq!(form.settings(), Ok(anonymous));
+    }
+
+    #[test]
+    fn partial_write_keeps_other_values() {
+        let mut form = Form::from_settings(&settings()).unwrap();
+        // a new network, in two pieces: the passwords are kept
+        form.write(Field::WifiSsid, 0, b"off").unwrap();
+        form.write(Field::WifiSsid, 3, b"ice").unwrap();
+        assert_eq!(
+            form.settings(),
+            Ok(Settings {
+                wifi_ssid: "office",
+                ..settings()
+            })
+        );
+
+        // other broker, same credentials
+        form.write(Field::Broker, 0, b"mqtts://10.0.0.2").unwrap();
+        let settings = form.settings().unwrap();
+        assert_eq!(settings.wifi_psk, "wifi secret");
+        assert_eq!(settings.mqtt_user, Some("sensor"));
+        assert_eq!(settings.mqtt_passwd, Some("mqtt:secret"));
+        assert_eq!(
+            settings.broker,
+            Broker {
+                host: "10.0.0.2",
+                port: 8883,
+                tls: true
+            }
+        );
+
+        // the credentials are removed writing them empty
+        form.write(Field::Credentials, 0, b"").unwrap();
+        assert_eq!(form.settings().unwrap().mqtt_user, None);
+    }
+
+    #[test]
+    fn value_too_long() {
+        let host = [b'a'; MAX_VALUE_LEN];
+        let long = Settings {
+            broker: Broker {
+                host: core::str::from_utf8(&host[..60]).unwrap(),
+                ..settings().broker
+            },
+            mqtt_user: Some(core::str::from_utf8(&host[..70]).unwrap()),
+            mqtt_passwd: Some(core::str::from_utf8(&host[..60]).unwrap()),
+            ..settings()
+        };
+        assert_eq!(
+            Form::from_settings(&long).map(|_| ()),
+            Err(FormError::TooLong(Field::Credentials))
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Sensors of both firmwares behind one interface. A sensor yields typed
+//! measurements: what is measured, in which unit, the value and when it was
+//! taken. [`Sensor`] is for blocking drivers (esp-idf) and [`AsyncSensor`]
+//! for async ones (embassy).
+
+use core::fmt::{self, Write};
+use core::future::Future;
+use heapless::Vec;
+
+/// Maximum number of measurements of one reading of a sensor
+pub const MAX_MEASUREMENTS: usize = 8;
+
+/// Axis of a motion sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+/// Physical quantity measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    RelativeHumidity,
+    /// Temperature at which the air would be saturated of water vapour
+    DewPoint,
+    /// Mass of water vapour in a volume of air
+    AbsoluteHumidity,
+    Acceleration(Axis),
+    AngularRate(Axis),
+}
+
+impl Quantity {
+    /// Name of the quantity, to be used in topics and payloads
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temperature",
+            Quantity::RelativeHumidity => "humidity",
+            Quantity::DewPoint => "dew_point",
+            Quantity::AbsoluteHumidity => "absolute_humidity",
+            Quantity::Acceleration(Axis::X) => "accel_x",
+            Quantity::Acceleration(Axis::Y) => "accel_y",
+            Quantity::Acceleration(Axis::Z) => "accel_z",
+            Quantity::AngularRate(Axis::X) => "gyro_x",
+            Quantity::AngularRate(Axis::Y) => "gyro_y",
+            Quantity::AngularRate(Axis::Z) => "gyro_z",
+        }
+    }
+
+    /// Unit the quantity is measured in
+    pub fn unit(&self) -> Unit {
+        match self {
+            Quantity::Temperature => Unit::Celsius,
+            Quantity::RelativeHumidity => Unit::Percent,
+            Quantity::DewPoint => Unit::Celsius,
+            Quantity::AbsoluteHumidity => Unit::GramsPerCubicMeter,
+            Quantity::Acceleration(_) => Unit::StandardGravity,
+            Quantity::AngularRate(_) => Unit::DegreesPerSecond,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Celsius,
+    Percent,
+    GramsPerCubicMeter,
+    /// Multiples of the standard gravity (9.80665 m/s²)
+    StandardGravity,
+    DegreesPerSecond,
+}
+
+impl Unit {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Percent => "%",
+            Unit::GramsPerCubicMeter => "g/m³",
+            Unit::StandardGravity => "g",
+            Unit::DegreesPerSecond => "°/s",
+        }
+    }
+}
+
+/// A value measured by a sensor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    /// Name of the sensor that took it
+    pub sensor: &'static str,
+    pub quantity: Quantity,
+    pub unit: Unit,
+    pub value: f32,
+    /// Milliseconds since the epoch, or since boot if the clock is not set
+    pub timestamp_millis: u64,
+}
+
+impl Measurement {
+    /// Measurement in the unit of the quantity
+    pub fn new(
+        sensor: &'static str,
+        quantity: Quantity,
+        value: f32,
+        timestamp_millis: u64,
+    ) -> Self {
+        Measurement {
+            sensor,
+            quantity,
+            unit: quantity.unit(),
+            value,
+            timestamp_millis,
+        }
+    }
+
+    /// Writes the measurement as JSON:
+    /// {"sensor":..,"quantity":..,"unit":..,"value":..,"ts":..}
+    pub fn write_json<W: Write>(&self, w: &mut W) -> fmt::Result {
+        write!(
+            w,
+            "{{\"sensor\":\"{}\",\"quantity\":\"{}\",\"unit\":\"{}\",\"value\":{},\"ts\":{}}}",
+            self.sensor,
+            self.quantity.name(),
+            self.unit.symbol(),
+            self.value,
+            self.timestamp_millis
+        )
+    }
+}
+
+/// Measurements of one reading of a sensor
+pub type Measurements = Vec<Measurement, MAX_MEASUREMENTS>;
+
+/// Sensor with a blocking driver
+pub trait Sensor {
+    type Error: fmt::Debug;
+
+    /// Name of the sensor, it goes in its measurements
+    fn name(&self) -> &'static str;
+
+    /// Takes a reading. The measurements get the timestamp given.
+    fn measure(&mut self, timestamp_millis: u64) -> Result<Measurements, Self::Error>;
+}
+
+/// Sensor with an async driver
+pub trait AsyncSensor {
+    type Error: fmt::Debug;
+    type MeasureFuture<'a>: Future<Output = Result<Measurements, Self::Error>> + 'a
+    where
+        Self: 'a;
+
+    /// Name of the sensor, it goes in its measurements
+    fn name(&self) -> &'static str;
+
+    /// Takes a reading. The measurements get the timestamp given.
+    fn measure(&mut self, timestamp_millis: u64) -> Self::MeasureFuture<'_>;
+}
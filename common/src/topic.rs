@@ -0,0 +1,249 @@
+//! MQTT topic filters and a router that dispatches the received publishes to
+//! the handlers registered for the filters matching their topic.
+//!
+//! Filters follow MQTT 3.1.1 (4.7): levels are separated by `/`, `+` matches
+//! exactly one level and `#`, only valid as the last level, matches any
+//! number of levels, including the parent level.
+
+use heapless::{String, Vec};
+
+/// Maximum length of a topic filter stored in a [`Router`]
+pub const MAX_FILTER_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouterError {
+    /// Wildcards not occupying a whole level, or `#` not at the end
+    InvalidFilter,
+    /// The filter is longer than MAX_FILTER_LEN
+    FilterTooLong,
+    /// There is no room for more routes
+    Full,
+}
+
+/// Returns true if the topic filter is well formed: `+` and `#` occupy a
+/// whole level and `#` is the last level.
+pub fn is_valid_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+            return false;
+        }
+        if level.contains('+') && level != "+" {
+            return false;
+        }
+    }
+    true
+}
+
+/// Returns true if the topic name matches the topic filter.
+/// Topics beginning with `$` are not matched by filters beginning with a
+/// wildcard.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+struct Route<H> {
+    filter: String<MAX_FILTER_LEN>,
+    handler: H,
+}
+
+/// Table of handlers by topic filter, with room for N routes.
+/// A handler is usually a closure `Fn(&str, &[u8])` that receives the topic
+/// and the payload of the message.
+pub struct Router<H, const N: usize> {
+    routes: Vec<Route<H>, N>,
+}
+
+impl<H, const N: usize> Router<H, N> {
+    pub const fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    /// Registers a handler for the topics matching the filter. Several
+    /// handlers can be registered for the same filter.
+    pub fn add(&mut self, filter: &str, handler: H) -> Result<(), RouterError> {
+        if !is_valid_filter(filter) {
+            return Err(RouterError::InvalidFilter);
+        }
+        let mut stored = String::new();
+        stored
+            .push_str(filter)
+            .map_err(|_| RouterError::FilterTooLong)?;
+        self.routes
+            .push(Route {
+                filter: stored,
+                handler,
+            })
+            .map_err(|_| RouterError::Full)
+    }
+
+    /// Removes all the handlers of the filter. Returns how many were removed.
+    pub fn remove(&mut self, filter: &str) -> usize {
+        let before = self.routes.len();
+        self.routes.retain(|route| route.filter != filter);
+        before - self.routes.len()
+    }
+
+    /// Filters with at least one handler, without repetitions. These are the
+    /// filters to subscribe to.
+    pub fn filters(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().enumerate().filter_map(|(i, route)| {
+            let repeated = self.routes[..i].iter().any(|r| r.filter == route.filter);
+            (!repeated).then_some(route.filter.as_str())
+        })
+    }
+
+    /// Handlers registered for filters matching the topic, in order of
+    /// registration.
+    pub fn handlers<'r>(&'r self, topic: &'r str) -> impl Iterator<Item = &'r H> {
+        self.routes
+            .iter()
+            .filter(move |route| matches(&route.filter, topic))
+            .map(|route| &route.handler)
+    }
+}
+
+impl<H, const N: usize> Router<H, N>
+where
+    H: Fn(&str, &[u8]),
+{
+    /// Calls every handler whose filter matches the topic.
+    /// Returns the number of handlers called.
+    pub fn dispatch(&self, topic: &str, payload: &[u8]) -> usize {
+        let mut called = 0;
+        for handler in self.handlers(topic) {
+            handler(topic, payload);
+            called += 1;
+        }
+        called
+    }
+}
+
+impl<H, const N: usize> Default for Router<H, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Handler<'a> = &'a dyn Fn(&str, &[u8]);
+
+    #[test]
+    fn valid_filters() {
+        for filter in [
+            "#", "+", "a", "a/b", "a/#", "+/b", "a/+/c", "a/+", "+/+", "/", "a//b",
+        ] {
+            assert!(is_valid_filter(filter), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn invalid_filters() {
+        for filter in [
+            "", "a#", "a/b#", "#/a", "a/#/c", "a+", "a/+b", "a/b+/c", "##",
+        ] {
+            assert!(!is_valid_filter(filter), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn single_level_wildcard() {
+        // root
+        assert!(matches("+/temperature", "sensor/temperature"));
+        assert!(!matches("+/temperature", "a/sensor/temperature"));
+        assert!(matches("+", "sensor"));
+        assert!(!matches("+", "sensor/temperature"));
+        // middle
+        assert!(matches("rust/+/temperature", "rust/esp32/temperature"));
+        assert!(!matches("rust/+/temperature", "rust/esp32/humidity"));
+        assert!(!matches("rust/+/temperature", "rust/temperature"));
+        // end
+        assert!(matches("rust/+", "rust/esp32"));
+        assert!(!matches("rust/+", "rust/esp32/temperature"));
+        assert!(!matches("rust/+", "rust"));
+    }
+
+    #[test]
+    fn multi_level_wildcard() {
+        // root
+        assert!(matches("#", "rust"));
+        assert!(matches("#", "rust/esp32/temperature"));
+        // end, including the parent level
+        assert!(matches("rust/#", "rust/esp32/temperature"));
+        assert!(matches("rust/#", "rust/esp32"));
+        assert!(matches("rust/#", "rust"));
+        assert!(!matches("rust/#", "embsens/esp32"));
+        // after a single level one
+        assert!(matches("+/esp32/#", "rust/esp32/temperature"));
+        assert!(!matches("+/esp32/#", "rust/esp8266/temperature"));
+    }
+
+    #[test]
+    fn multi_level_wildcard_not_last() {
+        let mut router: Router<fn(&str, &[u8]), 4> = Router::new();
+        assert_eq!(
+            router.add("rust/#/temperature", |_, _| {}),
+            Err(RouterError::InvalidFilter)
+        );
+        assert_eq!(router.filters().count(), 0);
+    }
+
+    #[test]
+    fn system_topics() {
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(!matches("+/#", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn empty_levels() {
+        assert!(matches("/temperature", "/temperature"));
+        assert!(!matches("temperature", "/temperature"));
+        assert!(matches("+/temperature", "/temperature"));
+        assert!(matches("rust//temperature", "rust//temperature"));
+        assert!(matches("rust/+/temperature", "rust//temperature"));
+        assert!(!matches("rust/temperature", "rust//temperature"));
+        assert!(matches("rust/+", "rust/"));
+        assert!(matches("#", "/"));
+        assert!(matches("+/+", "/"));
+    }
+
+    #[test]
+    fn router_dispatch() {
+        let calls = core::cell::Cell::new(0);
+        let count = |_: &str, _: &[u8]| calls.set(calls.get() + 1);
+        let mut router: Router<Handler, 4> = Router::new();
+        router.add("rust/+/command", &count).unwrap();
+        router.add("rust/#", &count).unwrap();
+        router.add("rust/#", &count).unwrap();
+        assert_eq!(router.filters().count(), 2);
+
+        assert_eq!(router.dispatch("rust/esp32/command", b"reset"), 3);
+        assert_eq!(router.dispatch("embsens/command", b"reset"), 0);
+        assert_eq!(calls.get(), 3);
+
+        assert_eq!(router.remove("rust/#"), 2);
+        assert_eq!(router.dispatch("rust/esp32/command", b"reset"), 1);
+    }
+}
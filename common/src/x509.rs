@@ -0,0 +1,430 @@
+//! Minimal reading of X.509 certificates: PEM to DER and the validity
+//! period. It doesn't verify anything, that is done by the TLS library; it
+//! is used to report when the certificate of the device expires.
+
+use core::fmt::{self, Write};
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// DER tags
+const TAG_INTEGER: u8 = 0x02;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+/// Explicit version, [0] in the TBSCertificate
+const TAG_VERSION: u8 = 0xA0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum X509Error {
+    /// There is no certificate in the PEM, or its base64 is not valid
+    InvalidPem,
+    /// The DER structure is not the one of a certificate
+    InvalidDer,
+    /// A date of the validity period is malformed
+    InvalidTime,
+    /// The buffer for the DER is too small
+    BufferTooSmall,
+}
+
+/// Date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch
+    pub fn unix_time(&self) -> i64 {
+        // days from civil, proleptic gregorian calendar
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days * 86_400 + self.hour as i64 * 3_600 + self.minute as i64 * 60 + self.second as i64
+    }
+}
+
+/// ISO 8601: 2024-01-31T12:00:00Z
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Period in which a certificate is valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validity {
+    pub not_before: DateTime,
+    pub not_after: DateTime,
+}
+
+impl Validity {
+    /// Writes the validity as JSON:
+    /// {"not_before":"2024-01-01T00:00:00Z","not_after":"...","expires":<unix time>}
+    pub fn write_json<W: Write>(&self, w: &mut W) -> fmt::Result {
+        write!(
+            w,
+            "{{\"not_before\":\"{}\",\"not_after\":\"{}\",\"expires\":{}}}",
+            self.not_before,
+            self.not_after,
+            self.not_after.unix_time()
+        )
+    }
+}
+
+/// Decodes the first certificate of a PEM into `der`, returning its length
+pub fn pem_to_der(pem: &str, der: &mut [u8]) -> Result<usize, X509Error> {
+    let begin = pem.find(PEM_BEGIN).ok_or(X509Error::InvalidPem)? + PEM_BEGIN.len();
+    let end = begin + pem[begin..].find(PEM_END).ok_or(X509Error::InvalidPem)?;
+
+    let mut len = 0;
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    for c in pem[begin..end].bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            c if c.is_ascii_whitespace() => continue,
+            _ => return Err(X509Error::InvalidPem),
+        };
+        bits = (bits << 6) | value as u32;
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            *der.get_mut(len).ok_or(X509Error::BufferTooSmall)? = (bits >> nbits) as u8;
+            len += 1;
+        }
+    }
+    if len == 0 {
+        return Err(X509Error::InvalidPem);
+    }
+    Ok(len)
+}
+
+/// Validity period of a certificate in DER
+pub fn validity(der: &[u8]) -> Result<Validity, X509Error> {
+    let (certificate, _) = expect(der, TAG_SEQUENCE)?;
+    let (tbs, _) = expect(certificate, TAG_SEQUENCE)?;
+    let mut rest = tbs;
+    if rest.first() == Some(&TAG_VERSION) {
+        rest = read_tlv(rest)?.2;
+    }
+    let (_serial, rest) = expect(rest, TAG_INTEGER)?;
+    let (_signature, rest) = expect(rest, TAG_SEQUENCE)?;
+    let (_issuer, rest) = expect(rest, TAG_SEQUENCE)?;
+    let (validity, _) = expect(rest, TAG_SEQUENCE)?;
+    let (not_before, rest) = read_time(validity)?;
+    let (not_after, _) = read_time(rest)?;
+    Ok(Validity {
+        not_before,
+        not_after,
+    })
+}
+
+/// Validity period of the first certificate of a PEM. `buffer` is used to
+/// decode it, it must be as large as the certificate.
+pub fn pem_validity(pem: &str, buffer: &mut [u8]) -> Result<Validity, X509Error> {
+    let len = pem_to_der(pem, buffer)?;
+    validity(&buffer[..len])
+}
+
+/// Reads an element: its tag, its content and the data after it
+fn read_tlv(data: &[u8]) -> Result<(u8, &[u8], &[u8]), X509Error> {
+    let (&tag, data) = data.split_first().ok_or(X509Error::InvalidDer)?;
+    let (&first, data) = data.split_first().ok_or(X509Error::InvalidDer)?;
+    let (len, data) = if first < 0x80 {
+        (first as usize, data)
+    } else {
+        // long form: the number of bytes of the length, up to 4
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || data.len() < count {
+            return Err(X509Error::InvalidDer);
+        }
+        let len = data[..count]
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        (len, &data[count..])
+    };
+    if data.len() < len {
+        return Err(X509Error::InvalidDer);
+    }
+    Ok((tag, &data[..len], &data[len..]))
+}
+
+/// Reads an element that must have the tag given
+fn expect(data: &[u8], tag: u8) -> Result<(&[u8], &[u8]), X509Error> {
+    match read_tlv(data)? {
+        (found, content, rest) if found == tag => Ok((content, rest)),
+        _ => Err(X509Error::InvalidDer),
+    }
+}
+
+/// Reads a UTCTime (YYMMDDHHMMSSZ) or GeneralizedTime (YYYYMMDDHHMMSSZ)
+fn read_time(data: &[u8]) -> Result<(DateTime, &[u8]), X509Error> {
+    let (tag, content, rest) = read_tlv(data)?;
+    let (year, content) = match tag {
+        TAG_UTC_TIME if content.len() == 13 => {
+            // RFC 5280: 50 to 99 are 19xx, 00 to 49 are 20xx
+            let year = digits(&content[..2])?;
+            (
+                if year >= 50 { 1900 + year } else { 2000 + year },
+                &content[2..],
+            )
+        }
+        TAG_GENERALIZED_TIME if content.len() == 15 => (digits(&content[..4])?, &content[4..]),
+        _ => return Err(X509Error::InvalidTime),
+    };
+    if content[10] != b'Z' {
+        return Err(X509Error::InvalidTime);
+    }
+    let time = DateTime {
+        year,
+        month: digits(&content[0..2])? as u8,
+        day: digits(&content[2..4])? as u8,
+        hour: digits(&content[4..6])? as u8,
+        minute: digits(&content[6..8])? as u8,
+        second: digits(&content[8..10])? as u8,
+    };
+    if !(1..=12).contains(&time.month)
+        || !(1..=31).contains(&time.day)
+        || time.hour > 23
+        || time.minute > 59
+        || time.second > 60
+    {
+        return Err(X509Error::InvalidTime);
+    }
+    Ok((time, rest))
+}
+
+/// Number written with decimal digits
+fn digits(data: &[u8]) -> Result<u16, X509Error> {
+    data.iter().try_fold(0u16, |value, c| match c {
+        b'0'..=b'9' => Ok(value * 10 + (c - b'0') as u16),
+        _ => Err(X509Error::InvalidTime),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed by openssl, valid from 2024-02-29 12:00 (UTCTime) to
+    /// 2050-01-01 (GeneralizedTime, the UTCTime can't go past 2049)
+    const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----
+MIIBhTCCASugAwIBAgIUA9dNHLFzMWLPhGdwjidTbfWPfB0wCgYIKoZIzj0EAwIw
+FzEVMBMGA1UEAwwMZW1ic2Vucy10ZXN0MCAXDTI0MDIyOTEyMDAwMFoYDzIwNTAw
+MTAxMDAwMDAwWjAXMRUwEwYDVQQDDAxlbWJzZW5zLXRlc3QwWTATBgcqhkjOPQIB
+BggqhkjOPQMBBwNCAATV2Bee8LrnTIzCakk/GL9Vf3gP1rLhrVZEXuOrVVNHktPJ
+Y/yTQYxfcfGs+Wlt7qA5826X+fso8WGUhw3+R5uqo1MwUTAdBgNVHQ4EFgQUyA79
+J3gedb/l25hKGvL1gXZmdZUwHwYDVR0jBBgwFoAUyA79J3gedb/l25hKGvL1gXZm
+dZUwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiB7BTHyNeoeZ3TJ
+9LzzILfQGGi8pwswZ4/2fV9Hpa2qkgIhALIgUG7LRd7tyWSotioidtghqKsRhP17
+hGE5zJp08u3j
+-----END CERTIFICATE-----
+";
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    fn der() -> heapless::Vec<u8, 512> {
+        let mut der = heapless::Vec::new();
+        der.resize(512, 0).unwrap();
+        let len = pem_to_der(CERTIFICATE, &mut der).unwrap();
+        der.truncate(len);
+        der
+    }
+
+    /// A time element with the tag and text given
+    fn time(tag: u8, text: &str) -> heapless::Vec<u8, 32> {
+        let mut data = heapless::Vec::new();
+        data.push(tag).unwrap();
+        data.push(text.len() as u8).unwrap();
+        data.extend_from_slice(text.as_bytes()).unwrap();
+        data
+    }
+
+    #[test]
+    fn real_certificate() {
+        let der = der();
+        assert_eq!(der.len(), 0x185 + 4);
+        let validity = validity(&der).unwrap();
+        assert_eq!(validity.not_before, date(2024, 2, 29, 12, 0, 0));
+        assert_eq!(validity.not_after, date(2050, 1, 1, 0, 0, 0));
+
+        let mut buffer = [0; 512];
+        assert_eq!(pem_validity(CERTIFICATE, &mut buffer), Ok(validity));
+
+        let mut json = heapless::String::<128>::new();
+        validity.write_json(&mut json).unwrap();
+        assert_eq!(
+            json,
+            "{\"not_before\":\"2024-02-29T12:00:00Z\",\
+             \"not_after\":\"2050-01-01T00:00:00Z\",\"expires\":2524608000}"
+        );
+    }
+
+    #[test]
+    fn invalid_pem() {
+        let mut buffer = [0; 512];
+        assert_eq!(
+            pem_to_der("MIIBhTCCASugAwIBAgIU", &mut buffer),
+            Err(X509Error::InvalidPem)
+        );
+        assert_eq!(
+            pem_to_der(&CERTIFICATE[..100], &mut buffer),
+            Err(X509Error::InvalidPem)
+        );
+        assert_eq!(
+            pem_to_der(
+                "-----BEGIN CERTIFICATE-----\n-----END CERTIFICATE-----",
+                &mut buffer
+            ),
+            Err(X509Error::InvalidPem)
+        );
+        assert_eq!(
+            pem_to_der(
+                "-----BEGIN CERTIFICATE-----\nMIIB*hTCC\n-----END CERTIFICATE-----",
+                &mut buffer
+            ),
+            Err(X509Error::InvalidPem)
+        );
+        assert_eq!(
+            pem_validity(CERTIFICATE, &mut buffer[..100]),
+            Err(X509Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn truncated_der() {
+        let der = der();
+        for len in 0..der.len() {
+            assert_eq!(validity(&der[..len]), Err(X509Error::InvalidDer));
+        }
+    }
+
+    #[test]
+    fn overlong_der_lengths() {
+        // the certificate says it has one byte more than there is
+        let mut der = der();
+        der[3] += 1;
+        assert_eq!(validity(&der), Err(X509Error::InvalidDer));
+
+        // long forms without bytes, with more than 4, and too large
+        for data in [
+            &[0x30, 0x80, 0x00][..],
+            &[0x30, 0x85, 0x00, 0x00, 0x00, 0x01, 0x85],
+            &[0x30, 0x82, 0x01],
+            &[0x30, 0x84, 0xff, 0xff, 0xff, 0xff, 0x00],
+            &[0x30, 0x05, 0x30, 0x06],
+        ] {
+            assert_eq!(validity(data), Err(X509Error::InvalidDer));
+        }
+    }
+
+    #[test]
+    fn both_time_formats() {
+        let data = time(TAG_UTC_TIME, "240229120000Z");
+        assert_eq!(read_time(&data), Ok((date(2024, 2, 29, 12, 0, 0), &[][..])));
+        let data = time(TAG_GENERALIZED_TIME, "21060207062815Z");
+        assert_eq!(read_time(&data), Ok((date(2106, 2, 7, 6, 28, 15), &[][..])));
+
+        // the length of the text has to match the tag
+        let data = time(TAG_UTC_TIME, "20240229120000Z");
+        assert_eq!(read_time(&data), Err(X509Error::InvalidTime));
+        let data = time(TAG_GENERALIZED_TIME, "240229120000Z");
+        assert_eq!(read_time(&data), Err(X509Error::InvalidTime));
+    }
+
+    #[test]
+    fn utc_time_pivot() {
+        let data = time(TAG_UTC_TIME, "491231235959Z");
+        assert_eq!(read_time(&data).unwrap().0, date(2049, 12, 31, 23, 59, 59));
+        let data = time(TAG_UTC_TIME, "500101000000Z");
+        assert_eq!(read_time(&data).unwrap().0, date(1950, 1, 1, 0, 0, 0));
+        let data = time(TAG_UTC_TIME, "000101000000Z");
+        assert_eq!(read_time(&data).unwrap().0.year, 2000);
+        let data = time(TAG_UTC_TIME, "991231235959Z");
+        assert_eq!(read_time(&data).unwrap().0.year, 1999);
+    }
+
+    #[test]
+    fn invalid_times() {
+        for text in [
+            "2402291200000",
+            "24022912000+1",
+            "2402a9120000Z",
+            "241329120000Z",
+            "240200120000Z",
+            "240232120000Z",
+            "240229240000Z",
+            "240229126000Z",
+            "240229120061Z",
+            "2402291200 0Z",
+        ] {
+            let data = time(TAG_UTC_TIME, text);
+            assert_eq!(read_time(&data), Err(X509Error::InvalidTime), "{}", text);
+        }
+        let data = time(TAG_INTEGER, "240229120000Z");
+        assert_eq!(read_time(&data), Err(X509Error::InvalidTime));
+    }
+
+    #[test]
+    fn unix_time() {
+        assert_eq!(date(1970, 1, 1, 0, 0, 0).unix_time(), 0);
+        assert_eq!(date(1969, 12, 31, 23, 59, 59).unix_time(), -1);
+        assert_eq!(date(2000, 3, 1, 0, 0, 0).unix_time(), 951_868_800);
+        assert_eq!(date(2024, 2, 29, 12, 0, 0).unix_time(), 1_709_208_000);
+        assert_eq!(date(2038, 1, 19, 3, 14, 8).unix_time(), 2_147_483_648);
+        assert_eq!(date(2050, 1, 1, 0, 0, 0).unix_time(), 2_524_608_000);
+    }
+
+    #[test]
+    fn malformed_input_never_panics() {
+        // every byte of the certificate replaced by every value
+        let der = der();
+        let mut corrupted = der.clone();
+        for i in 0..der.len() {
+            for byte in 0..=255 {
+                corrupted[i] = byte;
+                let _ = validity(&corrupted);
+            }
+            corrupted[i] = der[i];
+        }
+
+        // and every character of the PEM removed
+        let mut buffer = [0; 512];
+        let mut pem = heapless::String::<1024>::new();
+        for i in 0..CERTIFICATE.len() {
+            pem.clear();
+            pem.push_str(&CERTIFICATE[..i]).unwrap();
+            pem.push_str(&CERTIFICATE[i + 1..]).unwrap();
+            let _ = pem_validity(&pem, &mut buffer);
+        }
+    }
+}
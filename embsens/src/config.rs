@@ -0,0 +1,243 @@
+//! Configuration of the device: wifi credentials and MQTT server. It is
+//! read at boot from the key-value store in flash (`common::kvstore`), so
+//! the same firmware works in any site. The values not in the store are
+//! taken from the ones given at build time, if any, for development:
+//!
+//!     SSID, PASSWORD, MQTT_HOST, MQTT_PORT, MQTT_USER, MQTT_PASSWORD
+//!
+//! A configuration is a set of keys, so it is written in two slots by
+//! turns: the keys of the slot not in use ("a/wifi_ssid", "b/wifi_ssid",
+//! ...) and then `config_slot`, a single record, to switch to it. A power
+//! loss before the switch keeps the previous configuration whole. The keys
+//! without slot are the ones stored before the slots.
+
+use crate::transport::MqttTransport;
+use common::kvstore::{self, KvError, KvStore};
+use core::fmt::Write;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_println::println;
+use esp_storage::{FlashStorage, FlashStorageError};
+use heapless::String;
+
+/// Offset of the store in the flash: the `config` partition (two 4 KB
+/// sectors) of `partitions.csv`, 16 KB after the start of the identity of
+/// the device (see `identity`)
+pub const CONFIG_OFFSET: u32 = 0x3F_4000;
+
+/// Key with the slot of the configuration in use: "a" or "b"
+const CONFIG_SLOT: &str = "config_slot";
+const SLOTS: [&str; 2] = ["a", "b"];
+
+/// Keys of the values in the store, in a slot
+const WIFI_SSID: &str = "wifi_ssid";
+const WIFI_PSK: &str = "wifi_psk";
+const MQTT_HOST: &str = "mqtt_host";
+const MQTT_PORT: &str = "mqtt_port";
+const MQTT_USER: &str = "mqtt_user";
+const MQTT_PASSWD: &str = "mqtt_passwd";
+const KEYS: [&str; 6] = [
+    WIFI_SSID,
+    WIFI_PSK,
+    MQTT_HOST,
+    MQTT_PORT,
+    MQTT_USER,
+    MQTT_PASSWD,
+];
+
+/// Shown instead of the secrets in the debug output
+const REDACTED: &str = "<redacted>";
+
+/// MQTT server when there is none configured
+const DEFAULT_MQTT_HOST: &str = "test.mosquitto.org";
+
+/// Store of the configuration
+pub type ConfigStore = KvStore<FlashSectors>;
+
+/// Sectors of the flash of the ESP32-C3 for the store
+pub struct FlashSectors(FlashStorage);
+
+impl kvstore::Flash for FlashSectors {
+    type Error = FlashStorageError;
+    const SECTOR_SIZE: u32 = <FlashStorage as NorFlash>::ERASE_SIZE as u32;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        ReadNorFlash::read(&mut self.0, offset, bytes)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        NorFlash::write(&mut self.0, offset, bytes)
+    }
+
+    fn erase(&mut self, offset: u32) -> Result<(), Self::Error> {
+        NorFlash::erase(&mut self.0, offset, offset + Self::SECTOR_SIZE)
+    }
+}
+
+/// Opens the store of the configuration
+pub fn open_store() -> Option<ConfigStore> {
+    match KvStore::mount(FlashSectors(FlashStorage::new()), CONFIG_OFFSET) {
+        Ok(store) => Some(store),
+        Err(e) => {
+            println!("[CFG] Error opening the configuration store: {:?}", e);
+            None
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct DeviceConfig {
+    pub wifi_ssid: String<32>,
+    pub wifi_psk: String<64>,
+    /// Name or IPv4 address of the MQTT server
+    pub mqtt_host: String<64>,
+    pub mqtt_port: u16,
+    pub mqtt_user: Option<String<32>>,
+    pub mqtt_passwd: Option<String<64>>,
+}
+
+/// The secrets (wifi PSK and MQTT password) are not shown, so the
+/// configuration can be logged
+impl core::fmt::Debug for DeviceConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DeviceConfig")
+            .field("wifi_ssid", &self.wifi_ssid)
+            .field("wifi_psk", &REDACTED)
+            .field("mqtt_host", &self.mqtt_host)
+            .field("mqtt_port", &self.mqtt_port)
+            .field("mqtt_user", &self.mqtt_user)
+            .field("mqtt_passwd", &self.mqtt_passwd.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+impl DeviceConfig {
+    /// It has the credentials needed to connect
+    pub fn is_provisioned(&self) -> bool {
+        !self.wifi_ssid.is_empty() && !self.mqtt_host.is_empty()
+    }
+
+    /// Reads the configuration from the store, with the build time values
+    /// for the ones missing
+    pub fn load(mut store: Option<&mut ConfigStore>) -> Self {
+        let slot = store.as_deref_mut().and_then(current_slot).unwrap_or("");
+        let mqtt_port: Option<String<8>> = read(
+            store.as_deref_mut(),
+            slot,
+            MQTT_PORT,
+            option_env!("MQTT_PORT"),
+        );
+        DeviceConfig {
+            wifi_ssid: read(store.as_deref_mut(), slot, WIFI_SSID, option_env!("SSID"))
+                .unwrap_or_default(),
+            wifi_psk: read(
+                store.as_deref_mut(),
+                slot,
+                WIFI_PSK,
+                option_env!("PASSWORD"),
+            )
+            .unwrap_or_default(),
+            mqtt_host: read(
+                store.as_deref_mut(),
+                slot,
+                MQTT_HOST,
+                option_env!("MQTT_HOST"),
+            )
+            .unwrap_or_else(|| String::from(DEFAULT_MQTT_HOST)),
+            mqtt_port: mqtt_port
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(default_mqtt_port()),
+            mqtt_user: read(
+                store.as_deref_mut(),
+                slot,
+                MQTT_USER,
+                option_env!("MQTT_USER"),
+            ),
+            mqtt_passwd: read(store, slot, MQTT_PASSWD, option_env!("MQTT_PASSWORD")),
+        }
+    }
+
+    /// Writes the configuration in the store, in the slot not in use, and
+    /// then switches to it
+    pub fn store(&self, store: &mut ConfigStore) -> Result<(), KvError<FlashStorageError>> {
+        let slot = match current_slot(store) {
+            Some(slot) if slot == SLOTS[0] => SLOTS[1],
+            _ => SLOTS[0],
+        };
+        let key = |name| slot_key(slot, name);
+        let mut port: String<8> = String::new();
+        write!(port, "{}", self.mqtt_port).ok();
+        store.set(&key(WIFI_SSID), self.wifi_ssid.as_bytes())?;
+        store.set(&key(WIFI_PSK), self.wifi_psk.as_bytes())?;
+        store.set(&key(MQTT_HOST), self.mqtt_host.as_bytes())?;
+        store.set(&key(MQTT_PORT), port.as_bytes())?;
+        match &self.mqtt_user {
+            Some(user) => store.set(&key(MQTT_USER), user.as_bytes())?,
+            None => store.remove(&key(MQTT_USER))?,
+        }
+        match &self.mqtt_passwd {
+            Some(passwd) => store.set(&key(MQTT_PASSWD), passwd.as_bytes())?,
+            None => store.remove(&key(MQTT_PASSWD))?,
+        }
+        store.set(CONFIG_SLOT, slot.as_bytes())?;
+        // the keys stored before the slots are not used any more
+        for name in KEYS {
+            store.remove(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Slot of the configuration in use, if it has been stored in one
+fn current_slot(store: &mut ConfigStore) -> Option<&'static str> {
+    let mut slot = [0u8; 1];
+    match store.get(CONFIG_SLOT, &mut slot) {
+        Ok(Some(1)) => SLOTS.into_iter().find(|name| name.as_bytes() == slot),
+        Ok(_) => None,
+        Err(e) => {
+            println!("[CFG] Error reading {}: {:?}", CONFIG_SLOT, e);
+            None
+        }
+    }
+}
+
+/// Key of a value in a slot: "a/wifi_ssid". Without slot, the name.
+fn slot_key(slot: &str, name: &str) -> String<{ kvstore::MAX_KEY_LEN }> {
+    let mut key = String::new();
+    if !slot.is_empty() {
+        write!(key, "{}/", slot).ok();
+    }
+    key.push_str(name).ok();
+    key
+}
+
+/// Port of the MQTT server: mqtts with the `tls` feature, mqtt otherwise
+pub const fn default_mqtt_port() -> u16 {
+    if MqttTransport::is_secure() {
+        8883
+    } else {
+        1883
+    }
+}
+
+/// Value of `name` in the slot of the store, or the fallback if it is not
+/// there. A value that doesn't fit in the string is ignored.
+fn read<const N: usize>(
+    store: Option<&mut ConfigStore>,
+    slot: &str,
+    name: &str,
+    fallback: Option<&str>,
+) -> Option<String<N>> {
+    let key = slot_key(slot, name);
+    let mut value = [0u8; N];
+    let stored = match store.map(|store| store.get(&key, &mut value)) {
+        Some(Ok(Some(len))) => core::str::from_utf8(&value[..len]).ok(),
+        Some(Err(e)) => {
+            println!("[CFG] Error reading {}: {:?}", key, e);
+            None
+        }
+        _ => None,
+    };
+    let mut text = String::new();
+    text.push_str(stored.or(fallback)?).ok()?;
+    Some(text)
+}
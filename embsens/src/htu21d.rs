@@ -0,0 +1,122 @@
+//! Async driver of the HTU21D and SI7021 temperature and humidity sensors.
+//! It measures in no hold master mode: the bus is free while the sensor
+//! measures, and the result is polled until the sensor stops NACKing the
+//! reads. The protocol (CRC, conversions, user register) is in
+//! `common::htu21d`.
+
+use common::htu21d::{self, command, CrcError, Resolution, UserRegister};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::blocking::i2c::{Read, Write};
+
+/// Time between reads while the sensor is measuring
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+/// Extra time, over the maximum of the datasheet, before giving up
+const MEASURE_MARGIN_MILLIS: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    /// Error in the i2c bus
+    I2c(E),
+    /// The CRC of the measurement doesn't match
+    Crc,
+    /// The measurement didn't finish in time
+    Timeout,
+}
+
+impl<E> From<CrcError> for Error<E> {
+    fn from(_: CrcError) -> Self {
+        Error::Crc
+    }
+}
+
+pub struct Htu21d<I2C> {
+    i2c: I2C,
+    resolution: Resolution,
+}
+
+impl<I2C, E> Htu21d<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+{
+    /// The sensor starts with the maximum resolution
+    pub fn new(i2c: I2C) -> Self {
+        Htu21d {
+            i2c,
+            resolution: Resolution::Rh12Temp14,
+        }
+    }
+
+    /// Restarts the sensor, with the default user register (maximum
+    /// resolution, heater off)
+    pub async fn soft_reset(&mut self) -> Result<(), Error<E>> {
+        self.write(&[command::SOFT_RESET])?;
+        Timer::after(Duration::from_millis(htu21d::SOFT_RESET_MILLIS)).await;
+        self.resolution = Resolution::Rh12Temp14;
+        Ok(())
+    }
+
+    pub fn user_register(&mut self) -> Result<UserRegister, Error<E>> {
+        self.write(&[command::READ_USER_REGISTER])?;
+        let mut buf = [0u8; 1];
+        self.i2c
+            .read(htu21d::ADDRESS, &mut buf)
+            .map_err(Error::I2c)?;
+        Ok(UserRegister(buf[0]))
+    }
+
+    fn set_user_register(&mut self, register: UserRegister) -> Result<(), Error<E>> {
+        self.write(&[command::WRITE_USER_REGISTER, register.0])
+    }
+
+    pub fn set_resolution(&mut self, resolution: Resolution) -> Result<(), Error<E>> {
+        let register = self.user_register()?;
+        self.set_user_register(register.with_resolution(resolution))?;
+        self.resolution = resolution;
+        Ok(())
+    }
+
+    /// The heater raises the temperature of the sensor a few degrees, to
+    /// evaporate condensation or check that it works
+    pub fn set_heater(&mut self, on: bool) -> Result<(), Error<E>> {
+        let register = self.user_register()?;
+        self.set_user_register(register.with_heater(on))
+    }
+
+    /// Temperature in °C
+    pub async fn temperature(&mut self) -> Result<f32, Error<E>> {
+        let millis = self.resolution.temperature_millis();
+        let raw = self.measure(command::MEASURE_TEMPERATURE, millis).await?;
+        Ok(htu21d::temperature(raw))
+    }
+
+    /// Relative humidity in %
+    pub async fn relative_humidity(&mut self) -> Result<f32, Error<E>> {
+        let millis = self.resolution.humidity_millis();
+        let raw = self.measure(command::MEASURE_HUMIDITY, millis).await?;
+        Ok(htu21d::relative_humidity(raw))
+    }
+
+    /// Starts a measurement and reads it when done. `millis` is the maximum
+    /// time it takes.
+    async fn measure(&mut self, command: u8, millis: u64) -> Result<u16, Error<E>> {
+        self.write(&[command])?;
+        let deadline = Instant::now() + Duration::from_millis(millis + MEASURE_MARGIN_MILLIS);
+        let mut buf = [0u8; 3];
+        loop {
+            Timer::after(POLL_INTERVAL).await;
+            // the sensor NACKs the read while measuring, which can't be told
+            // apart from other bus errors: retry until the deadline
+            if self.i2c.read(htu21d::ADDRESS, &mut buf).is_ok() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+        }
+        Ok(htu21d::decode(buf)?)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error<E>> {
+        self.i2c.write(htu21d::ADDRESS, bytes).map_err(Error::I2c)
+    }
+}
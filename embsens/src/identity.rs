@@ -0,0 +1,109 @@
+//! Identity of the device for TLS: its certificate and private key, for
+//! the client authentication, and the CA of the MQTT server. They are
+//! installed in a region of the flash apart from the firmware, so every
+//! device has its own identity, and the CA can change, with the same
+//! firmware (see the README to write it).
+//!
+//! Layout of the region, at IDENTITY_OFFSET:
+//!
+//! | magic "EMI2" | cert_len | key_len | ca_len | 0 | cert PEM | key PEM | CA PEM |
+//!
+//! The lengths are u16 LE. The PEMs end with a nul character, included in
+//! their lengths, as the TLS library needs. A length of 0 means the PEM is
+//! not there: the certificate and the key go together, the CA may be alone.
+//! The first layout, magic "EMID", has only the certificate and the key:
+//!
+//! | magic "EMID" | cert_len | key_len | cert PEM | key PEM |
+
+use embedded_storage::ReadStorage;
+use esp_println::println;
+use esp_storage::FlashStorage;
+
+/// Offset of the identity in the flash: the `identity` partition (16 KB)
+/// of `partitions.csv`, after the app partition.
+pub const IDENTITY_OFFSET: u32 = 0x3F_0000;
+
+const MAGIC: &[u8; 4] = b"EMI2";
+const HEADER_LEN: usize = 12;
+const MAGIC_V1: &[u8; 4] = b"EMID";
+const HEADER_LEN_V1: usize = 8;
+/// Room for the certificate, the key and the CA
+pub const MAX_IDENTITY_LEN: usize = 8192;
+
+/// Certificate and private key of the device, PEM ended by a nul character
+#[derive(Clone, Copy)]
+pub struct ClientIdentity {
+    pub certificate: &'static str,
+    pub private_key: &'static str,
+}
+
+/// Contents of the identity region, PEM ended by a nul character
+#[derive(Clone, Copy, Default)]
+pub struct Identity {
+    pub client: Option<ClientIdentity>,
+    /// CA of the MQTT server, instead of the one built into the firmware
+    pub ca: Option<&'static str>,
+}
+
+/// Reads the identity installed in the flash, if any. `buffer` keeps the
+/// PEMs for the life of the firmware.
+pub fn load(buffer: &'static mut [u8; MAX_IDENTITY_LEN]) -> Identity {
+    let mut flash = FlashStorage::new();
+    let mut header = [0u8; HEADER_LEN];
+    if flash.read(IDENTITY_OFFSET, &mut header).is_err() {
+        println!("[ID] Error reading the identity from flash");
+        return Identity::default();
+    }
+    let len = |at: usize| u16::from_le_bytes([header[at], header[at + 1]]) as usize;
+    // an erased flash reads 0xff
+    let (header_len, cert_len, key_len, ca_len) = match &header[..4] {
+        magic if magic == MAGIC => (HEADER_LEN, len(4), len(6), len(8)),
+        magic if magic == MAGIC_V1 => (HEADER_LEN_V1, len(4), len(6), 0),
+        _ => return Identity::default(),
+    };
+    if (cert_len == 0) != (key_len == 0) || cert_len + key_len + ca_len > MAX_IDENTITY_LEN {
+        println!("[ID] Identity in flash with wrong lengths");
+        return Identity::default();
+    }
+
+    let data = &mut buffer[..cert_len + key_len + ca_len];
+    if flash
+        .read(IDENTITY_OFFSET + header_len as u32, data)
+        .is_err()
+    {
+        println!("[ID] Error reading the identity from flash");
+        return Identity::default();
+    }
+    let data: &'static [u8] = data;
+    let (certificate, rest) = data.split_at(cert_len);
+    let (private_key, ca) = rest.split_at(key_len);
+
+    let client = match (pem(certificate), pem(private_key)) {
+        (Some(certificate), Some(private_key)) => Some(ClientIdentity {
+            certificate,
+            private_key,
+        }),
+        (None, None) if cert_len == 0 => None,
+        _ => {
+            println!("[ID] Client identity in flash is not valid PEM");
+            None
+        }
+    };
+    let ca = match pem(ca) {
+        Some(ca) => Some(ca),
+        None if ca_len == 0 => None,
+        None => {
+            println!("[ID] CA in flash is not valid PEM");
+            None
+        }
+    };
+    Identity { client, ca }
+}
+
+/// PEM ended by a nul character, the only one
+fn pem(data: &'static [u8]) -> Option<&'static str> {
+    match data.split_last() {
+        Some((&0, text)) if !text.contains(&0) => core::str::from_utf8(data).ok(),
+        _ => None,
+    }
+}
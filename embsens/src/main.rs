@@ -1,14 +1,26 @@
 #![no_std]
 #![no_main]
 #![feature(type_alias_impl_trait)]
-
-use crate::tiny_mqtt::TinyMqtt;
+#![feature(impl_trait_in_assoc_type)]
+#![feature(async_fn_in_trait)]
+
+use crate::config::DeviceConfig;
+use crate::identity::ClientIdentity;
+use crate::resolver::Resolver;
+use crate::sensors::{Driver, Icm42670Sensor, IMU, SENSORS};
+use crate::transport::MqttTransport;
+use common::backoff::Backoff;
+use common::buffer::{OverflowPolicy, ReadingBuffer};
+use common::imu::ImuWindow;
+use common::provisioning::Status;
+use common::sensor::{AsyncSensor, Axis, Measurement, Quantity};
+use common::x509::{self, Validity};
 use core::cell::RefCell;
 use core::fmt::Write;
 use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
 use embassy_executor::Executor;
 use embassy_net::tcp::TcpSocket;
-use embassy_net::{Config, Ipv4Address, Ipv4Cidr, Stack, StackResources};
+use embassy_net::{Config, Ipv4Cidr, Stack, StackResources};
 use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, raw::NoopRawMutex, NoopMutex};
 use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
@@ -30,14 +42,46 @@ use hal::{
     timer::TimerGroup,
     Priority, Rng, Rtc, IO,
 };
-use icm42670::{prelude::*, Address, Icm42670};
-use mqttrust::encoding::v4::Pid;
+use mqttrust::encoding::v4::LastWill;
 use mqttrust::SubscribeTopic;
 use static_cell::StaticCell;
-mod tiny_mqtt;
-
-const SSID: &str = env!("SSID");
-const PASSWORD: &str = env!("PASSWORD");
+use tiny_mqtt::TinyMqtt;
+mod config;
+mod htu21d;
+mod identity;
+mod provisioning;
+mod resolver;
+mod sensors;
+mod transport;
+
+/// Topic to receive commands
+const COMMAND_TOPIC: &str = "/embsens/command";
+
+/// MQTT client identifier of this device
+const CLIENT_ID: &str = "esp32";
+/// Retained status of the device: "online" while connected to the MQTT
+/// broker, "offline" (last will) when the connection is lost.
+const STATUS_TOPIC: &str = "/embsens/esp32/status";
+
+/// Last temperature taken, as a plain value
+const TEMPERATURE_TOPIC: &str = "/embsens/temperature";
+/// Temperature readings taken while disconnected, published once the
+/// connection is back as {"ts":<uptime ms>,"value":<temperature>}
+const BACKLOG_TOPIC: &str = "/embsens/temperature/backlog";
+/// Number of readings kept while disconnected
+const READINGS_CAPACITY: usize = 64;
+
+/// Validity of the certificate of the device, published when the
+/// "certificate" command is received:
+/// {"not_before":"..","not_after":"..","expires":<unix time>}, or
+/// {"status":"none"} without one
+const CERTIFICATE_TOPIC: &str = "/embsens/esp32/certificate";
+/// Command that asks for the validity of the certificate
+const CERTIFICATE_COMMAND: &str = "certificate";
+
+/// Aggregates of the accelerometer and gyroscope samples of each window, as
+/// JSON (see `ImuWindow::write_json`). They are not kept while disconnected.
+const IMU_TOPIC: &str = "/embsens/imu";
 
 macro_rules! singleton {
     ($val:expr) => {{
@@ -53,10 +97,16 @@ pub enum Signal {
     WifiStaConnected,
     WifiConnected(Ipv4Cidr),
     WifiDisconnected,
-    TempHumData { temp: f32, hum: f32 },
-    AccelDataData([f32; 6]),
+    Measurement(Measurement),
+    Imu(ImuWindow),
+    CertificateQuery,
+    /// The MQTT client is connected and ready to publish
+    MqttConnected,
 }
 
+/// MQTT client over a TCP socket (with TLS or not), shared between tasks
+type SharedMqtt = Mutex<NoopRawMutex, RefCell<TinyMqtt<'static, MqttTransport>>>;
+
 static EXECUTOR: StaticCell<Executor> = StaticCell::new();
 
 static I2C_BUS: StaticCell<NoopMutex<RefCell<I2C<I2C0>>>> = StaticCell::new();
@@ -78,29 +128,46 @@ fn main() -> ! {
     rtc.swd.disable();
     rtc.rwdt.disable();
 
+    // Configuration of the wifi and the MQTT server, from the flash
+    let mut store = config::open_store();
+    let config: &'static DeviceConfig = singleton!(DeviceConfig::load(store.as_mut()));
+    println!(
+        "Wifi network {}, MQTT server {}:{}",
+        config.wifi_ssid, config.mqtt_host, config.mqtt_port
+    );
+
     let timer = hal::systimer::SystemTimer::new(peripherals.SYSTIMER).alarm0;
 
-    let init = initialize(
-        EspWifiInitFor::Wifi,
+    // BLE, along with wifi, only to provision the device
+    let start_provisioning = provisioning::is_needed(config, store.as_mut());
+    let init_for = if start_provisioning {
+        println!("Starting BLE provisioning");
+        EspWifiInitFor::WifiBle
+    } else {
+        EspWifiInitFor::Wifi
+    };
+    let init = &*singleton!(initialize(
+        init_for,
         timer,
         Rng::new(peripherals.RNG),
         system.radio_clock_control,
         &clocks,
     )
-    .unwrap();
+    .unwrap());
 
-    let (wifi, _) = peripherals.RADIO.split();
-    let (wifi_interface, controller) = esp_wifi::wifi::new_with_mode(&init, wifi, WifiMode::Sta);
+    let (wifi, bluetooth) = peripherals.RADIO.split();
+    let (wifi_interface, controller) = esp_wifi::wifi::new_with_mode(init, wifi, WifiMode::Sta);
 
     let timer_group0 = TimerGroup::new(peripherals.TIMG0, &clocks, &mut peripheral_clock_control);
     embassy::init(&clocks, timer_group0.timer0);
-    let config = Config::dhcpv4(Default::default());
+    let net_config = Config::dhcpv4(Default::default());
     let seed = 1234;
 
     // Initialize network stack
     let stack = &*singleton!(Stack::new(
         wifi_interface,
-        config,
+        net_config,
+        // DHCP, the MQTT socket and the DNS queries
         singleton!(StackResources::<3>::new()),
         seed
     ));
@@ -119,10 +186,6 @@ fn main() -> ! {
     let i2c_bus = NoopMutex::new(RefCell::new(i2c));
     let i2c_bus = I2C_BUS.init(i2c_bus);
 
-    // Share the i2c bus between devices in embassy (sync)
-    let i2c_dev1 = I2cDevice::new(i2c_bus);
-    let i2c_dev2 = I2cDevice::new(i2c_bus);
-
     hal::interrupt::enable(Interrupt::I2C_EXT0, Priority::Priority1).unwrap();
 
     // Socket for MQTT.
@@ -130,87 +193,249 @@ fn main() -> ! {
     // static lifetile.
     let rx_buffer = singleton!([0u8; 4096]);
     let tx_buffer = singleton!([0u8; 4096]);
-    let socket = TcpSocket::new(&stack, rx_buffer, tx_buffer);
+    let socket = singleton!(RefCell::new(TcpSocket::new(&stack, rx_buffer, tx_buffer)));
+    let identity = identity::load(singleton!([0u8; identity::MAX_IDENTITY_LEN]));
+    let certificate = identity.client.and_then(certificate_validity);
+    if identity.client.is_some() && !MqttTransport::is_secure() {
+        println!("Client certificate installed, but not used without the tls feature");
+    }
+    if identity.ca.is_some() && MqttTransport::is_secure() {
+        println!("Using the CA installed with the identity");
+    }
+    let socket = MqttTransport::new(socket, identity);
 
     // Library for MQTT access.
-    let mqtt = TinyMqtt::new("esp32", socket, esp_wifi::current_millis, None);
+    let mut mqtt = TinyMqtt::new(CLIENT_ID, socket, esp_wifi::current_millis);
+    mqtt.add_route(COMMAND_TOPIC, &command_handler).unwrap();
+    // Subscribe to topic /command. It is sent each time the client connects.
+    let topics = [SubscribeTopic {
+        topic_path: COMMAND_TOPIC,
+        qos: mqttrust::QoS::AtLeastOnce,
+    }];
+    mqtt.subscribe(None, &topics).unwrap();
+    mqtt.set_last_will(Some(LastWill {
+        topic: STATUS_TOPIC,
+        message: b"offline",
+        qos: mqttrust::QoS::AtLeastOnce,
+        retain: true,
+    }));
     // But is can't be shared between tasks in this way, so we wrap it with
     // a Mutex (an embassy async Mutex that can lock between await points).
-    let mqtt: &Mutex<NoopRawMutex, RefCell<TinyMqtt<'static>>> =
-        singleton!(Mutex::new(RefCell::new(mqtt)));
+    let mqtt: &SharedMqtt = singleton!(Mutex::new(RefCell::new(mqtt)));
 
     let executor = EXECUTOR.init(Executor::new());
     executor.run(|spawner| {
         // General coordination task
-        spawner.spawn(fsm(mqtt)).ok();
+        spawner.spawn(fsm(mqtt, certificate)).ok();
+
+        if start_provisioning {
+            match store {
+                Some(store) => {
+                    spawner
+                        .spawn(provisioning::ble_provisioning(
+                            init, bluetooth, store, config,
+                        ))
+                        .ok();
+                }
+                None => println!("No configuration store, BLE provisioning not started"),
+            }
+        }
 
         // Wifi and network handling tasks
-        spawner.spawn(connection(controller)).ok();
+        spawner.spawn(connection(controller, config)).ok();
         spawner.spawn(net_task(&stack)).ok();
 
         // Tasks to send and receive MQTT messages
-        spawner.spawn(mqtt_task(&stack, mqtt)).ok();
+        spawner.spawn(mqtt_task(&stack, mqtt, config)).ok();
         spawner.spawn(mqtt_receiver(mqtt)).ok();
 
-        // Sensor reading tasks
-        spawner.spawn(run_i2c(i2c_dev1)).ok();
-        spawner.spawn(run_htu(i2c_dev2)).ok();
+        // Sensor reading tasks, one for each sensor registered. They share
+        // the i2c bus in embassy (sync).
+        for config in SENSORS {
+            match Driver::new(config.kind, I2cDevice::new(i2c_bus)) {
+                Ok(driver) => {
+                    spawner.spawn(run_sensor(driver, config.period)).ok();
+                }
+                Err(e) => println!("Error starting sensor {:?}: {:?}", config.kind, e),
+            }
+        }
+        match Icm42670Sensor::new(I2cDevice::new(i2c_bus), IMU) {
+            Ok(imu) => {
+                spawner.spawn(run_imu(imu)).ok();
+            }
+            Err(e) => println!("Error starting the IMU: {:?}", e),
+        }
     })
 }
 
+/// Validity of the certificate of the device
+fn certificate_validity(identity: ClientIdentity) -> Option<Validity> {
+    let mut der = [0u8; 2048];
+    match x509::pem_validity(identity.certificate, &mut der) {
+        Ok(validity) => {
+            println!("Client certificate valid until {}", validity.not_after);
+            Some(validity)
+        }
+        Err(e) => {
+            println!("Error reading the client certificate: {:?}", e);
+            None
+        }
+    }
+}
+
 #[embassy_executor::task]
-async fn fsm(mqtt: &'static Mutex<NoopRawMutex, RefCell<TinyMqtt<'static>>>) {
-    // let mut topic_name: heapless::String<32> = heapless::String::new();
-    // write!(topic_name, "/embsens/temp{}", 1).ok();
-    let topic_name = "/embsens/temperature";
-    let mut pkt_num = 1;
+async fn fsm(mqtt: &'static SharedMqtt, certificate: Option<Validity>) {
+    // Readings waiting to be published, kept while the MQTT connection is
+    // down. When it fills up the older readings are thinned out.
+    let mut readings: ReadingBuffer<f32, READINGS_CAPACITY> =
+        ReadingBuffer::new(OverflowPolicy::Downsample);
 
     loop {
         let signal = CHANNEL.recv().await;
         println!("[FSM] signal received: {:?}", signal);
-        if let Signal::TempHumData { temp, hum } = signal {
-            // println!("Temperature = {}", temp);
-            // println!("Humidity = {}", hum);
-
-            // prepare message payload
-            let mut msg: heapless::String<32> = heapless::String::new();
-            write!(msg, "{}", temp).ok();
-            {
-                // this block of code limits the lock of 'shared'
-                let shared = mqtt.lock().await;
-
-                if shared.borrow_mut().ready {
-                    println!(
-                        "[FSM] publishing in {} temperature {} and humidity {}",
-                        topic_name, temp, hum
-                    );
-                    // send publish mqtt packet, with package identifier pkt_num
-                    if shared
-                        .borrow_mut()
-                        .publish_with_pid(
-                            Some(Pid::try_from(pkt_num).unwrap()),
-                            &topic_name,
-                            msg.as_bytes(),
-                            mqttrust::QoS::AtLeastOnce,
-                        )
-                        .is_err()
-                    {
-                        println!("[FSM] Error sending MQTT temperature.");
-                    }
-                } else {
-                    println!("[FSM] mqtt connection not ready to send");
-                }
+        match signal {
+            Signal::Imu(window) => publish_imu(mqtt, &window).await,
+            Signal::CertificateQuery => publish_certificate(mqtt, certificate.as_ref()).await,
+            Signal::Measurement(measurement) if measurement.quantity == Quantity::Temperature => {
+                readings.push(measurement.timestamp_millis, measurement.value);
+                publish_readings(mqtt, &mut readings, true).await;
             }
-            pkt_num += 1;
+            // the readings taken while disconnected don't wait for the next
+            // one
+            Signal::MqttConnected => publish_readings(mqtt, &mut readings, false).await,
+            _ => {}
         }
     }
 }
 
+/// Publishes the readings buffered, oldest first, if connected. With
+/// `current` the newest one is the reading just taken: it goes as a plain
+/// value to the temperature topic. The others go to the backlog topic with
+/// the time they were taken. A reading is removed only once it is queued,
+/// so if the client is busy the rest wait for the next round.
+async fn publish_readings(
+    mqtt: &'static SharedMqtt,
+    readings: &mut ReadingBuffer<f32, READINGS_CAPACITY>,
+    current: bool,
+) {
+    let shared = mqtt.lock().await;
+    if !shared.borrow().ready {
+        println!(
+            "[FSM] mqtt connection not ready to send, {} readings buffered ({} dropped)",
+            readings.len(),
+            readings.dropped()
+        );
+        return;
+    }
+    while let Some(reading) = readings.front() {
+        // prepare message payload: the last reading as a plain value, older
+        // ones with the time they were taken
+        let mut msg: heapless::String<64> = heapless::String::new();
+        let topic = if current && readings.len() == 1 {
+            write!(msg, "{}", reading.value).ok();
+            println!(
+                "[FSM] publishing in {} temperature {}",
+                TEMPERATURE_TOPIC, msg
+            );
+            TEMPERATURE_TOPIC
+        } else {
+            write!(
+                msg,
+                "{{\"ts\":{},\"value\":{}}}",
+                reading.timestamp_millis, reading.value
+            )
+            .ok();
+            BACKLOG_TOPIC
+        };
+
+        // send publish mqtt packet, with a new package identifier
+        let pid = shared.borrow().next_pid();
+        if shared
+            .borrow_mut()
+            .publish_with_pid(
+                Some(pid),
+                topic,
+                msg.as_bytes(),
+                mqttrust::QoS::AtLeastOnce,
+                false,
+            )
+            .is_err()
+        {
+            println!("[FSM] Error sending MQTT temperature.");
+            break;
+        }
+        readings.pop();
+    }
+}
+
+/// Publishes the aggregates of a window of the IMU, if connected
+async fn publish_imu(mqtt: &'static SharedMqtt, window: &ImuWindow) {
+    // it must fit, with the topic, in the 1024 bytes of a TinyMqtt packet
+    let mut msg: heapless::String<768> = heapless::String::new();
+    if window.write_json(&mut msg).is_err() {
+        println!("[FSM] IMU payload too long");
+        return;
+    }
+    let shared = mqtt.lock().await;
+    if !shared.borrow().ready {
+        println!("[FSM] mqtt connection not ready, IMU window dropped");
+        return;
+    }
+    println!("[FSM] publishing in {} {}", IMU_TOPIC, msg);
+    let pid = shared.borrow().next_pid();
+    if shared
+        .borrow_mut()
+        .publish_with_pid(
+            Some(pid),
+            IMU_TOPIC,
+            msg.as_bytes(),
+            mqttrust::QoS::AtLeastOnce,
+            false,
+        )
+        .is_err()
+    {
+        println!("[FSM] Error sending MQTT IMU window.");
+    }
+}
+
+/// Publishes the validity of the certificate of the device, if connected
+async fn publish_certificate(mqtt: &'static SharedMqtt, certificate: Option<&Validity>) {
+    let mut msg: heapless::String<128> = heapless::String::new();
+    match certificate {
+        Some(validity) => validity.write_json(&mut msg).ok(),
+        None => msg.push_str("{\"status\":\"none\"}").ok(),
+    };
+    let shared = mqtt.lock().await;
+    if !shared.borrow().ready {
+        println!("[FSM] mqtt connection not ready, certificate status dropped");
+        return;
+    }
+    println!("[FSM] publishing in {} {}", CERTIFICATE_TOPIC, msg);
+    let pid = shared.borrow().next_pid();
+    if shared
+        .borrow_mut()
+        .publish_with_pid(
+            Some(pid),
+            CERTIFICATE_TOPIC,
+            msg.as_bytes(),
+            mqttrust::QoS::AtLeastOnce,
+            false,
+        )
+        .is_err()
+    {
+        println!("[FSM] Error sending MQTT certificate status.");
+    }
+}
+
 /// Establish connection with the wifi access point
 /// It keep trying every 5 s in case of error.
 #[embassy_executor::task]
-async fn connection(mut controller: WifiController<'static>) {
+async fn connection(mut controller: WifiController<'static>, config: &'static DeviceConfig) {
     println!("[CON] start connection task");
+    if config.wifi_ssid.is_empty() {
+        println!("[CON] No wifi network configured");
+        return;
+    }
     println!(
         "[CON] Device capabilities: {:?}",
         controller.get_capabilities()
@@ -226,8 +451,8 @@ async fn connection(mut controller: WifiController<'static>) {
         }
         if !matches!(controller.is_started(), Ok(true)) {
             let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.into(),
-                password: PASSWORD.into(),
+                ssid: config.wifi_ssid.as_str().into(),
+                password: config.wifi_psk.as_str().into(),
                 ..Default::default()
             });
             controller.set_configuration(&client_config).unwrap();
@@ -236,14 +461,17 @@ async fn connection(mut controller: WifiController<'static>) {
             println!("[CON] Wifi started!");
         }
         println!("[CON] About to connect...");
+        provisioning::set_status(Status::WifiConnecting);
 
         match controller.connect().await {
             Ok(_) => {
                 println!("[CON] Wifi connected!");
+                provisioning::set_status(Status::WifiConnected);
                 CHANNEL.send(Signal::WifiStaConnected).await;
             }
             Err(e) => {
                 println!("[CON] Failed to connect to wifi: {e:?}");
+                provisioning::set_status(Status::WifiFailed);
                 Timer::after(Duration::from_millis(5000)).await
             }
         }
@@ -256,77 +484,56 @@ async fn net_task(stack: &'static Stack<WifiDevice<'static>>) {
     stack.run().await
 }
 
-/// Embassy task to read accelerometer sensor on board (ICM42670)
-#[embassy_executor::task]
-async fn run_i2c(i2c: I2cDevice<'static, NoopRawMutex, I2C<'static, I2C0>>) {
-    let mut icm = Icm42670::new(i2c, Address::Primary).unwrap();
-
+/// Embassy task to read a sensor periodically. There is one for each sensor
+/// in SENSORS, so the pool must be at least as large.
+#[embassy_executor::task(pool_size = 4)]
+async fn run_sensor(mut driver: Driver, period: Duration) {
     loop {
-        let accel_norm = icm.accel_norm().unwrap();
-        let gyro_norm = icm.gyro_norm().unwrap();
-        println!(
-            "[ACEL] accelerations  =  X: {:+.04} Y: {:+.04} Z: {:+.04}\t\tGYRO  =  X: {:+.04} Y: {:+.04} Z: {:+.04}",
-            accel_norm.x, accel_norm.y, accel_norm.z, gyro_norm.x, gyro_norm.y, gyro_norm.z);
-        CHANNEL
-            .send(Signal::AccelDataData([
-                accel_norm.x,
-                accel_norm.y,
-                accel_norm.z,
-                gyro_norm.x,
-                gyro_norm.y,
-                gyro_norm.z,
-            ]))
-            .await;
-        Timer::after(Duration::from_millis(5000)).await;
+        match driver.measure(esp_wifi::current_millis()).await {
+            Ok(measurements) => {
+                for measurement in measurements {
+                    println!(
+                        "[SENSOR] {} {} = {} {}",
+                        measurement.sensor,
+                        measurement.quantity.name(),
+                        measurement.value,
+                        measurement.unit.symbol()
+                    );
+                    CHANNEL.send(Signal::Measurement(measurement)).await;
+                }
+            }
+            Err(e) => println!("[SENSOR] Error reading {}: {:?}", driver.name(), e),
+        }
+        Timer::after(period).await;
     }
 }
 
-/// Embassy task to read external I2C sensor HTU21D similar to SI7021.
-/// Not using device driver, writing and reading directly from i2c.
+/// Embassy task to sample the accelerometer and gyroscope. Each window of
+/// samples is aggregated and sent to the fsm.
 #[embassy_executor::task]
-async fn run_htu(mut i2c: I2cDevice<'static, NoopRawMutex, I2C<'static, I2C0>>) {
-    const SI7021_I2C_ADDRESS: u8 = 0x40;
-    const MEASURE_RELATIVE_HUMIDITY: u8 = 0xE5;
-    const MEASURE_TEMPERATURE: u8 = 0xE3;
-    // const READ_TEMPERATURE: u8 = 0xE0;
-
+async fn run_imu(mut imu: Icm42670Sensor) {
     loop {
-        Timer::after(Duration::from_millis(4000)).await;
-        // Temperature measurement
-        let mut buf = [0u8; 2];
-        i2c.write(SI7021_I2C_ADDRESS, &[MEASURE_TEMPERATURE])
-            .unwrap();
-        Timer::after(Duration::from_millis(50)).await;
-        i2c.read(SI7021_I2C_ADDRESS, &mut buf).unwrap();
-        // Write and read in one single operation
-        // i2c.write_read(SI7021_I2C_ADDRESS, &[MEASURE_TEMPERATURE], &mut buf).unwrap();
-        let word = u16::from_be_bytes(buf);
-        let temp: f32 = 175.72 * word as f32 / 65536.0 - 46.85;
-        println!("[HTU] buf {:?}, word: {}, temperatura: {}", buf, word, temp);
-
-        // medición de humedad
-        i2c.write(SI7021_I2C_ADDRESS, &[MEASURE_RELATIVE_HUMIDITY])
-            .unwrap();
-        Timer::after(Duration::from_millis(50)).await;
-        i2c.read(SI7021_I2C_ADDRESS, &mut buf).unwrap();
-        // Write and read in one single operation
-        // i2c.write_read(SI7021_I2C_ADDRESS, &[MEASURE_TEMPERATURE], &mut buf).unwrap();
-        let word = u16::from_be_bytes(buf);
-        let rel_hum = 125.0 * word as f32 / 65536.0 - 6.0;
-        // rel_hum = rel_hum.max(0.0).min(100.0);
-        println!("[HTU] buf {:?}, word: {}, humedad: {}", buf, word, rel_hum);
-        CHANNEL
-            .send(Signal::TempHumData { temp, hum: rel_hum })
-            .await;
+        match imu.measure_window(esp_wifi::current_millis()).await {
+            Ok(window) => {
+                println!(
+                    "[IMU] {} samples, accel rms {} {} {} g",
+                    window.samples(),
+                    window.accel(Axis::X).rms(),
+                    window.accel(Axis::Y).rms(),
+                    window.accel(Axis::Z).rms()
+                );
+                CHANNEL.send(Signal::Imu(window)).await;
+            }
+            Err(e) => {
+                println!("[IMU] Error reading {}: {:?}", imu.name(), e);
+                Timer::after(IMU.window).await;
+            }
+        }
     }
 }
 
-/// Embassy task to send data to MQTT server
-#[embassy_executor::task]
-async fn mqtt_task(
-    stack: &'static Stack<WifiDevice<'static>>,
-    mqtt: &'static Mutex<NoopRawMutex, RefCell<TinyMqtt<'static>>>,
-) {
+/// Waits until the network link is up and has an IPv4 address
+async fn wait_for_network(stack: &'static Stack<WifiDevice<'static>>) {
     // Wait until network is connected
     println!("[MQTT] Wait until network is connected...");
     loop {
@@ -345,82 +552,168 @@ async fn mqtt_task(
         }
         Timer::after(Duration::from_millis(500)).await;
     }
+}
+
+/// Random number for the jitter of the reconnection delays, taken from the
+/// current time
+fn jitter() -> u32 {
+    (esp_wifi::current_millis() as u32)
+        .wrapping_mul(1_103_515_245)
+        .wrapping_add(12_345)
+        >> 8
+}
+
+/// Embassy task that keeps the connection with the MQTT server.
+/// When the connection is lost (socket closed, missed PINGRESP or keep alive
+/// expired) it connects again. Failed attempts are retried with exponential
+/// backoff and jitter. The subscriptions are restored by TinyMqtt::connect.
+#[embassy_executor::task]
+async fn mqtt_task(
+    stack: &'static Stack<WifiDevice<'static>>,
+    mqtt: &'static SharedMqtt,
+    config: &'static DeviceConfig,
+) {
+    let mut backoff = Backoff::new(1_000, 60_000);
+    let mut resolver = Resolver::new(&config.mqtt_host);
 
     loop {
-        let remote_endpoint = (Ipv4Address::new(91, 121, 93, 94), 1883);
+        wait_for_network(stack).await;
+
+        // the address is resolved again when the TTL of the last one expires
+        let address = match resolver.resolve(stack).await {
+            Ok(address) => address,
+            Err(e) => {
+                provisioning::set_status(Status::MqttFailed);
+                let delay = backoff.next_delay(jitter());
+                println!(
+                    "[MQTT] Error resolving {}: {:?}. Retrying in {} ms",
+                    config.mqtt_host, e, delay
+                );
+                Timer::after(Duration::from_millis(delay)).await;
+                continue;
+            }
+        };
+        let remote_endpoint = (address, config.mqtt_port);
+
         println!("[MQTT] connecting socket...");
         {
             let shared = mqtt.lock().await;
+            // the socket must be closed before connecting it again
+            shared.borrow_mut().socket.abort();
             shared
                 .borrow_mut()
                 .socket
                 .set_timeout(Some(Duration::from_secs(30)));
-            let r = shared.borrow_mut().socket.connect(remote_endpoint).await;
+            let r = shared
+                .borrow_mut()
+                .socket
+                .connect(remote_endpoint, &config.mqtt_host)
+                .await;
             if let Err(e) = r {
-                println!("[MQTT] connect error: {:?}", e);
+                provisioning::set_status(Status::MqttFailed);
+                let delay = backoff.next_delay(jitter());
+                println!("[MQTT] connect error: {:?}. Retrying in {} ms", e, delay);
+                // the server may have moved to another address
+                resolver.invalidate();
                 // keep trying to open socket
-                Timer::after(Duration::from_millis(5_000)).await;
+                Timer::after(Duration::from_millis(delay)).await;
                 continue;
             }
-            println!("[MQTT] TCP socket connected to MQTT server!");
+            if MqttTransport::is_secure() {
+                println!("[MQTT] TLS connection with MQTT server established!");
+            } else {
+                println!("[MQTT] TCP socket connected to MQTT server!");
+            }
         }
 
-        // Send connect MQTT package to server
+        // Send connect MQTT package to server and wait for its CONNACK.
+        // The client is marked as ready to publish when it is accepted.
         {
             let shared = mqtt.lock().await;
-            if let Err(e) = shared.borrow_mut().connect(60, None, None) {
-                println!(
-                    "[MQTT] Error connecting to MQTT server. Retrying in 10 seconds. Error is {:?}",
-                    e
-                );
-                Timer::after(Duration::from_millis(10_000)).await;
-                continue;
+            let user = config.mqtt_user.as_deref();
+            let passwd = config.mqtt_passwd.as_deref().map(str::as_bytes);
+            let r = shared.borrow_mut().connect(60, user, passwd, 10_000).await;
+            match r {
+                Ok(session_present) => {
+                    println!(
+                        "[MQTT] Connected to MQTT broker, session present: {}",
+                        session_present
+                    );
+                    provisioning::set_status(Status::MqttConnected);
+                }
+                Err(e) => {
+                    provisioning::set_status(Status::MqttFailed);
+                    let delay = backoff.next_delay(jitter());
+                    println!(
+                        "[MQTT] Error connecting to MQTT server. Retrying in {} ms. Error is {:?}",
+                        delay, e
+                    );
+                    shared.borrow_mut().disconnect().ok();
+                    Timer::after(Duration::from_millis(delay)).await;
+                    continue;
+                }
             }
-            println!("[MQTT] Connected to MQTT broker");
         }
+        backoff.reset();
+        // the readings buffered are published, without holding the lock
+        CHANNEL.send(Signal::MqttConnected).await;
 
-        // Subscribe to topic /command
-        let topics = [SubscribeTopic {
-            topic_path: "/embsens/command",
-            qos: mqttrust::QoS::AtLeastOnce,
-        }];
-        Timer::after(Duration::from_millis(2_000)).await;
-
+        // Birth message, it replaces the retained last will
         {
             let shared = mqtt.lock().await;
-            if shared.borrow_mut().subscribe(None, &topics).is_err() {
-                println!("[MQTT] error sending subscribe packet");
+            let pid = shared.borrow().next_pid();
+            let r = shared.borrow_mut().publish_with_pid(
+                Some(pid),
+                STATUS_TOPIC,
+                b"online",
+                mqttrust::QoS::AtLeastOnce,
+                true,
+            );
+            if r.is_err() {
+                println!("[MQTT] error sending status message");
             }
         }
-        println!("[MQTT] Subscribe sent");
 
-        // mark the mqtt connection as ready to publish
-        {
+        // Wait until the connection is lost. mqtt_receiver polls the client,
+        // which stops being ready when the connection fails.
+        loop {
+            Timer::after(Duration::from_millis(1_000)).await;
             let shared = mqtt.lock().await;
-            shared.borrow_mut().ready = true;
-            break;
+            if !shared.borrow().ready {
+                break;
+            }
         }
+        println!("[MQTT] Connection with MQTT server lost. Reconnecting...");
     }
 }
 
-// use smoltcp::socket::tcp::State;
-use embassy_net::tcp::State;
+/// Handles the messages received in COMMAND_TOPIC
+fn command_handler(topic: &str, payload: &[u8]) {
+    match core::str::from_utf8(payload) {
+        Ok(command) if command.trim() == CERTIFICATE_COMMAND => {
+            // the fsm answers when it is free; if it is busy, it is dropped
+            if CHANNEL.try_send(Signal::CertificateQuery).is_err() {
+                println!("[RCV] Certificate query dropped, fsm busy");
+            }
+        }
+        Ok(command) => println!("[RCV] Command received in {}: {}", topic, command),
+        Err(_) => println!("[RCV] Command received in {} is not valid utf-8", topic),
+    }
+}
 
-/// Embassy task to receive data from MQTT server
+/// Embassy task to send and receive data from MQTT server
 #[embassy_executor::task]
-async fn mqtt_receiver(mqtt: &'static Mutex<NoopRawMutex, RefCell<TinyMqtt<'static>>>) {
+async fn mqtt_receiver(mqtt: &'static SharedMqtt) {
     loop {
         {
             let shared = mqtt.lock().await;
-            let state = shared.borrow_mut().socket.state();
-            if state == State::Established {
-                println!("[RCV] Waiting for MQTT packets from server...");
+            // while connecting, mqtt_task handles the connection
+            let ready = shared.borrow().ready;
+            if ready {
                 // if shared.borrow_mut().receive().await.is_err() {
-                if shared.borrow_mut().poll().await.is_err() {
-                    println!("[RCV] Error receiving data from mqtt server");
+                if let Err(e) = shared.borrow_mut().poll().await {
+                    println!("[RCV] Error receiving data from mqtt server: {:?}", e);
                 }
-            } else {
-                println!("[RCV] Socket not connected yet...");
             }
         }
         Timer::after(Duration::from_millis(1000)).await;
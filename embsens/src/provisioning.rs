@@ -0,0 +1,317 @@
+//! Provisioning from a phone over BLE, without an access point. The device
+//! advertises as "embsens" a GATT service with a characteristic for each
+//! value of the configuration and one for the status:
+//!
+//! - SSID, wifi password, broker (mqtt://host:port or mqtts://host:port)
+//!   and MQTT credentials (user:password) are written by the phone. The
+//!   SSID and the broker can be read back; the passwords can't.
+//! - Writing "apply" to the status checks the values, stores them in the
+//!   configuration and restarts the device to try them, with the service
+//!   still running. The status notifies the result: wifi_connecting,
+//!   wifi_failed, wifi_connected, mqtt_failed, mqtt_connected (see
+//!   `common::provisioning::Status`). Once connected to the MQTT server the
+//!   next boot is a normal one.
+//!
+//! It starts when there is no configuration stored, or after applying one
+//! until it works. The values not written keep the ones in use, so a phone
+//! can change the network without sending the MQTT credentials again.
+//!
+//! Security: the BLE link is not paired, so it is neither encrypted nor
+//! authenticated. Anyone in radio range while the service runs could
+//! write a configuration of their own (their wifi, their broker) and take
+//! over the device, and anyone listening could get the passwords as they
+//! are written. The service is only open for `PROVISIONING_WINDOW` after
+//! the boot: the attacker has to be near when someone with the device in
+//! hand powers it up, or when it restarts to try the configuration just
+//! applied. Afterwards it stops advertising and refuses the writes (status
+//! `closed`), and the device has to be restarted to provision it again.
+//! The passwords can't be read back, but they can be sniffed while written:
+//! provision with nobody unknown around, and change them if in doubt.
+
+use crate::config::{ConfigStore, DeviceConfig};
+use crate::transport::MqttTransport;
+use bleps::{
+    ad_structure::{
+        create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
+    },
+    async_attribute_server::AttributeServer,
+    asynch::Ble,
+    attribute_server::NotificationData,
+    gatt,
+};
+use common::provisioning::{Broker, Field, Form, Settings, Status};
+use core::cell::{Cell, RefCell};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_println::println;
+use esp_wifi::ble::controller::asynch::BleConnector;
+use esp_wifi::EspWifiInitialization;
+use heapless::String;
+
+use crate::hal::{radio::Bluetooth, reset::software_reset};
+
+/// Key of the store set while a configuration applied has not connected
+/// to the MQTT server
+const PENDING_KEY: &str = "prov_pending";
+/// Command written to the status to apply the configuration
+const APPLY_COMMAND: &[u8] = b"apply";
+/// Time for the phone to get the status before restarting
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+/// Time after the boot the service accepts a configuration
+const PROVISIONING_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<Status>> = Mutex::new(Cell::new(Status::Idle));
+static STATUS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Reports the progress of the connection. The wifi and MQTT tasks call it
+/// always; it is only read with the provisioning service running.
+pub fn set_status(status: Status) {
+    let changed = STATUS.lock(|current| current.replace(status)) != status;
+    if changed {
+        STATUS_CHANGED.signal(());
+    }
+}
+
+fn status() -> Status {
+    STATUS.lock(Cell::get)
+}
+
+/// The device must start the provisioning service: there is no
+/// configuration, or the last one applied has not connected yet
+pub fn is_needed(config: &DeviceConfig, store: Option<&mut ConfigStore>) -> bool {
+    if !config.is_provisioned() {
+        return true;
+    }
+    let mut pending = [0u8; 1];
+    matches!(
+        store.map(|store| store.get(PENDING_KEY, &mut pending)),
+        Some(Ok(Some(_)))
+    )
+}
+
+/// BLE provisioning service. `config` is the configuration in use: the
+/// form starts with its values, so the ones not written are kept.
+#[embassy_executor::task]
+pub async fn ble_provisioning(
+    init: &'static EspWifiInitialization,
+    mut bluetooth: Bluetooth,
+    store: ConfigStore,
+    config: &'static DeviceConfig,
+) {
+    let store = RefCell::new(store);
+    let form = RefCell::new(Form::from_settings(&settings(config)).unwrap_or_else(|e| {
+        println!("[BLE] Configuration not shown: {:?}", e);
+        Form::new()
+    }));
+    let restart = Cell::new(false);
+
+    let connector = BleConnector::new(init, &mut bluetooth);
+    let mut ble = Ble::new(connector, esp_wifi::current_millis);
+    loop {
+        if !is_window_open() {
+            println!("[BLE] Provisioning window closed, restart the device to open it");
+            break;
+        }
+        println!("[BLE] Starting provisioning service");
+        if let Err(e) = start_advertising(&mut ble).await {
+            println!("[BLE] Error advertising: {:?}", e);
+            Timer::after(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        let read = |field: Field, offset: usize, data: &mut [u8]| {
+            let form = form.borrow();
+            let value = form.value(field).get(offset..).unwrap_or(&[]);
+            let len = value.len().min(data.len());
+            data[..len].copy_from_slice(&value[..len]);
+            len
+        };
+        let write = |field: Field, offset: usize, data: &[u8]| {
+            if !is_window_open() {
+                println!("[BLE] Provisioning window closed, {:?} not written", field);
+                return;
+            }
+            if let Err(e) = form.borrow_mut().write(field, offset, data) {
+                println!("[BLE] {:?}", e);
+            }
+        };
+        let mut ssid_read = |offset: usize, data: &mut [u8]| read(Field::WifiSsid, offset, data);
+        let mut ssid_write = |offset: usize, data: &[u8]| write(Field::WifiSsid, offset, data);
+        let mut psk_write = |offset: usize, data: &[u8]| write(Field::WifiPsk, offset, data);
+        let mut broker_read = |offset: usize, data: &mut [u8]| read(Field::Broker, offset, data);
+        let mut broker_write = |offset: usize, data: &[u8]| write(Field::Broker, offset, data);
+        let mut credentials_write =
+            |offset: usize, data: &[u8]| write(Field::Credentials, offset, data);
+        let mut status_read = |offset: usize, data: &mut [u8]| {
+            let status = status().as_str().as_bytes();
+            let status = status.get(offset..).unwrap_or(&[]);
+            let len = status.len().min(data.len());
+            data[..len].copy_from_slice(&status[..len]);
+            len
+        };
+        let mut status_write = |_offset: usize, data: &[u8]| {
+            if data != APPLY_COMMAND {
+                println!("[BLE] Unknown command");
+                return;
+            }
+            if !is_window_open() {
+                println!("[BLE] Provisioning window closed, configuration not applied");
+                set_status(Status::Closed);
+                return;
+            }
+            match apply(&form.borrow(), &mut store.borrow_mut()) {
+                Ok(()) => {
+                    set_status(Status::Saved);
+                    restart.set(true);
+                }
+                Err(()) => set_status(Status::Invalid),
+            }
+        };
+
+        gatt!([service {
+            uuid: "5a0e0000-6d2b-4c55-9c3e-2f1b0a1e5b00",
+            characteristics: [
+                characteristic {
+                    uuid: "5a0e0001-6d2b-4c55-9c3e-2f1b0a1e5b00",
+                    read: ssid_read,
+                    write: ssid_write,
+                },
+                characteristic {
+                    uuid: "5a0e0002-6d2b-4c55-9c3e-2f1b0a1e5b00",
+                    write: psk_write,
+                },
+                characteristic {
+                    uuid: "5a0e0003-6d2b-4c55-9c3e-2f1b0a1e5b00",
+                    read: broker_read,
+                    write: broker_write,
+                },
+                characteristic {
+                    uuid: "5a0e0004-6d2b-4c55-9c3e-2f1b0a1e5b00",
+                    write: credentials_write,
+                },
+                characteristic {
+                    name: "status",
+                    uuid: "5a0e0005-6d2b-4c55-9c3e-2f1b0a1e5b00",
+                    notify: true,
+                    read: status_read,
+                    write: status_write,
+                },
+            ],
+        },]);
+
+        let mut server = AttributeServer::new(&mut ble, &mut gatt_attributes);
+        // notifies the status when it changes, and restarts once the phone
+        // has been told that the configuration is saved
+        let mut notifier = || async {
+            if status() == Status::MqttConnected {
+                complete(&mut store.borrow_mut());
+            }
+            if restart.get() {
+                Timer::after(RESTART_DELAY).await;
+                println!("[BLE] Configuration saved, restarting");
+                software_reset();
+            }
+            // the server needs to handle the requests meanwhile
+            with_timeout(Duration::from_secs(1), STATUS_CHANGED.wait())
+                .await
+                .ok();
+            NotificationData::new(status_handle, status().as_str().as_bytes())
+        };
+        if let Err(e) = server.run(&mut notifier).await {
+            println!("[BLE] Provisioning service stopped: {:?}", e);
+        }
+    }
+    // without the service, the configuration applied still has to be
+    // completed once it connects
+    while status() != Status::MqttConnected {
+        STATUS_CHANGED.wait().await;
+    }
+    complete(&mut store.borrow_mut());
+}
+
+/// The service accepts a configuration: the device was powered on a short
+/// time ago
+fn is_window_open() -> bool {
+    Instant::now().as_ticks() < PROVISIONING_WINDOW.as_ticks()
+}
+
+/// Advertises the device, connectable, until a phone connects
+async fn start_advertising(ble: &mut Ble<BleConnector<'_>>) -> Result<(), bleps::Error> {
+    ble.init().await?;
+    ble.cmd_set_le_advertising_parameters().await?;
+    // flags and name, it fits in the 31 bytes of the advertising data
+    let data = create_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::CompleteLocalName("embsens"),
+    ])
+    .expect("Error building the advertising data");
+    ble.cmd_set_le_advertising_data(data).await?;
+    ble.cmd_set_le_advertise_enable(true).await?;
+    Ok(())
+}
+
+/// Stores the configuration of the form, marked as pending until it
+/// connects to the MQTT server. The mark goes first: a power loss between
+/// both writes restarts the provisioning, not a normal boot with a
+/// configuration not tried.
+fn apply(form: &Form, store: &mut ConfigStore) -> Result<(), ()> {
+    let settings = form.settings().map_err(|e| println!("[BLE] {:?}", e))?;
+    if settings.broker.tls != MqttTransport::is_secure() {
+        println!("[BLE] The broker scheme doesn't match the firmware (tls feature)");
+        return Err(());
+    }
+    let config = device_config(&settings).ok_or_else(|| println!("[BLE] Value too long"))?;
+    store
+        .set(PENDING_KEY, b"1")
+        .and_then(|_| config.store(store))
+        .map_err(|e| println!("[BLE] Error storing the configuration: {:?}", e))
+}
+
+/// Values of the configuration in use, to start the form with them
+fn settings(config: &DeviceConfig) -> Settings<'_> {
+    Settings {
+        wifi_ssid: &config.wifi_ssid,
+        wifi_psk: &config.wifi_psk,
+        broker: Broker {
+            host: &config.mqtt_host,
+            port: config.mqtt_port,
+            tls: MqttTransport::is_secure(),
+        },
+        mqtt_user: config.mqtt_user.as_deref(),
+        mqtt_passwd: config.mqtt_passwd.as_deref(),
+    }
+}
+
+/// Configuration with the values of the form
+fn device_config(settings: &Settings) -> Option<DeviceConfig> {
+    Some(DeviceConfig {
+        wifi_ssid: string(settings.wifi_ssid)?,
+        wifi_psk: string(settings.wifi_psk)?,
+        mqtt_host: string(settings.broker.host)?,
+        mqtt_port: settings.broker.port,
+        mqtt_user: match settings.mqtt_user {
+            Some(user) => Some(string(user)?),
+            None => None,
+        },
+        mqtt_passwd: match settings.mqtt_passwd {
+            Some(passwd) => Some(string(passwd)?),
+            None => None,
+        },
+    })
+}
+
+/// Ends the provisioning once the configuration applied works: the next
+/// boot is a normal one
+fn complete(store: &mut ConfigStore) {
+    if let Err(e) = store.remove(PENDING_KEY) {
+        println!("[BLE] Error completing the provisioning: {:?}", e);
+    }
+}
+
+/// The text in a string, if it fits
+fn string<const N: usize>(text: &str) -> Option<String<N>> {
+    let mut string = String::new();
+    string.push_str(text).ok()?;
+    Some(string)
+}
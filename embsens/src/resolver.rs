@@ -0,0 +1,144 @@
+//! Resolution of the address of the MQTT server. A literal IPv4 address is
+//! used as is; a name is asked to the DNS servers given by DHCP, over UDP,
+//! and its address is kept while the TTL of the answer lasts.
+//!
+//! The DNS socket of embassy-net doesn't give the TTL of the answers, so
+//! the queries are made here with `common::dns`.
+
+use common::dns::{self, Answer, DnsCache, DnsError};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{with_timeout, Duration};
+use esp_println::println;
+use esp_wifi::wifi::WifiDevice;
+
+/// Time to wait for the answer of a server
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Queries sent to each server before trying the next one
+const ATTEMPTS: usize = 2;
+
+#[derive(Debug)]
+pub enum ResolveError {
+    /// DHCP gave no DNS server
+    NoServer,
+    /// No server answered in time
+    Timeout,
+    Udp,
+    Dns(DnsError),
+}
+
+/// Resolver of a host, with the cache of its address
+pub struct Resolver {
+    host: &'static str,
+    cache: DnsCache,
+    next_id: u16,
+}
+
+impl Resolver {
+    pub fn new(host: &'static str) -> Self {
+        Resolver {
+            host,
+            cache: DnsCache::new(),
+            next_id: esp_wifi::current_millis() as u16,
+        }
+    }
+
+    /// Address of the host: the literal address, the one in the cache or a
+    /// new one asked to the DNS servers
+    pub async fn resolve(
+        &mut self,
+        stack: &'static Stack<WifiDevice<'static>>,
+    ) -> Result<Ipv4Address, ResolveError> {
+        if let Some(address) = dns::parse_ipv4(self.host) {
+            return Ok(Ipv4Address(address));
+        }
+        if let Some(address) = self.cache.get(esp_wifi::current_millis()) {
+            return Ok(Ipv4Address(address));
+        }
+
+        let servers = stack
+            .config_v4()
+            .map(|config| config.dns_servers)
+            .unwrap_or_default();
+        if servers.is_empty() {
+            return Err(ResolveError::NoServer);
+        }
+        let mut error = ResolveError::Timeout;
+        for server in servers.iter() {
+            for _ in 0..ATTEMPTS {
+                match self.query(stack, *server).await {
+                    Ok(answer) => {
+                        println!(
+                            "[DNS] {} is {}, for {} s",
+                            self.host,
+                            Ipv4Address(answer.address),
+                            answer.ttl
+                        );
+                        self.cache.insert(&answer, esp_wifi::current_millis());
+                        return Ok(Ipv4Address(answer.address));
+                    }
+                    // the server knows the answer: asking again won't change it
+                    Err(ResolveError::Dns(e)) => return Err(ResolveError::Dns(e)),
+                    Err(e) => error = e,
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Forgets the address, so that it is resolved again in the next
+    /// connection (the server may have moved)
+    pub fn invalidate(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Asks a server for the address of the host
+    async fn query(
+        &mut self,
+        stack: &'static Stack<WifiDevice<'static>>,
+        server: Ipv4Address,
+    ) -> Result<Answer, ResolveError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut message = [0u8; dns::MAX_MESSAGE_LEN];
+        let len = dns::encode_query(id, self.host, &mut message).map_err(ResolveError::Dns)?;
+
+        let mut rx_meta = [PacketMetadata::EMPTY; 1];
+        let mut rx_buffer = [0u8; dns::MAX_MESSAGE_LEN];
+        let mut tx_meta = [PacketMetadata::EMPTY; 1];
+        let mut tx_buffer = [0u8; dns::MAX_MESSAGE_LEN];
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        // any local port
+        socket.bind(0).map_err(|_| ResolveError::Udp)?;
+        let endpoint = IpEndpoint::new(IpAddress::Ipv4(server), dns::DNS_PORT);
+        socket
+            .send_to(&message[..len], endpoint)
+            .await
+            .map_err(|_| ResolveError::Udp)?;
+
+        // answers to other queries (late ones) are ignored
+        with_timeout(QUERY_TIMEOUT, async {
+            loop {
+                let (len, from) = socket
+                    .recv_from(&mut message)
+                    .await
+                    .map_err(|_| ResolveError::Udp)?;
+                if from.addr != IpAddress::Ipv4(server) {
+                    continue;
+                }
+                match dns::parse_response(id, &message[..len]) {
+                    Err(DnsError::UnexpectedId) => continue,
+                    result => return result.map_err(ResolveError::Dns),
+                }
+            }
+        })
+        .await
+        .map_err(|_| ResolveError::Timeout)?
+    }
+}
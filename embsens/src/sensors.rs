@@ -0,0 +1,261 @@
+//! Sensors of the board. Each one is registered in `SENSORS`, with how
+//! often it is read, and has a driver implementing `AsyncSensor`. To add a
+//! sensor: write its driver, add it to `SensorKind` and `Driver`, and
+//! register it in `SENSORS`.
+//!
+//! The accelerometer and gyroscope is not read periodically but sampled
+//! continuously, configured in `IMU`, to aggregate the samples.
+
+use crate::hal::{i2c::I2C, peripherals::I2C0};
+use crate::htu21d::{self, Htu21d};
+use common::htu21d::Resolution;
+use common::imu::{ImuSettings, ImuWindow};
+use common::sensor::{AsyncSensor, Measurement, Measurements, Quantity};
+use core::future::Future;
+use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use icm42670::{prelude::*, AccelOdr, AccelRange, Address, GyroOdr, GyroRange, Icm42670};
+
+/// Device in the shared i2c bus (GPIO10 SDA, GPIO8 SCL)
+pub type I2cBus = I2cDevice<'static, NoopRawMutex, I2C<'static, I2C0>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorKind {
+    /// External temperature and humidity sensor, also SI7021
+    Htu21d,
+}
+
+/// Sensor to read and how often
+#[derive(Debug, Clone, Copy)]
+pub struct SensorConfig {
+    pub kind: SensorKind,
+    pub period: Duration,
+}
+
+/// Sensors of the board. Each one is read in its own task.
+pub const SENSORS: [SensorConfig; 1] = [SensorConfig {
+    kind: SensorKind::Htu21d,
+    period: Duration::from_millis(4000),
+}];
+
+/// Configuration of the accelerometer and gyroscope
+#[derive(Debug, Clone, Copy)]
+pub struct ImuConfig {
+    /// Output data rate of the accelerometer. The sensor is sampled at this
+    /// rate, so it shouldn't be over what the i2c bus can keep up with.
+    pub accel_odr: AccelOdr,
+    pub gyro_odr: GyroOdr,
+    /// Full scale ranges. The narrower the range, the higher the resolution.
+    pub accel_range: AccelRange,
+    pub gyro_range: GyroRange,
+    /// Time the samples are aggregated, one publication per window
+    pub window: Duration,
+}
+
+impl ImuConfig {
+    fn sample_period(&self) -> Duration {
+        Duration::from_micros((1_000_000.0 / self.accel_odr.as_f32()) as u64)
+    }
+
+    fn settings(&self) -> ImuSettings {
+        let accel_range_g = match self.accel_range {
+            AccelRange::G2 => 2,
+            AccelRange::G4 => 4,
+            AccelRange::G8 => 8,
+            AccelRange::G16 => 16,
+        };
+        let gyro_range_dps = match self.gyro_range {
+            GyroRange::Deg250 => 250,
+            GyroRange::Deg500 => 500,
+            GyroRange::Deg1000 => 1000,
+            GyroRange::Deg2000 => 2000,
+        };
+        ImuSettings {
+            odr_hz: self.accel_odr.as_f32(),
+            accel_range_g,
+            gyro_range_dps,
+        }
+    }
+}
+
+/// Accelerometer and gyroscope on board
+pub const IMU: ImuConfig = ImuConfig {
+    accel_odr: AccelOdr::Hz100,
+    gyro_odr: GyroOdr::Hz100,
+    accel_range: AccelRange::G4,
+    gyro_range: GyroRange::Deg500,
+    window: Duration::from_millis(5000),
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorError {
+    /// Error in the communication through the i2c bus
+    Bus,
+    /// The device is not the one expected or it doesn't answer properly
+    Device,
+    /// The checksum of the data read doesn't match
+    Checksum,
+    /// The device didn't finish the measurement in time
+    Timeout,
+}
+
+/// Driver of any of the sensors
+pub enum Driver {
+    Htu21d(Htu21dSensor),
+}
+
+impl Driver {
+    /// Driver for a sensor of the kind given, connected to the bus
+    pub fn new(kind: SensorKind, i2c: I2cBus) -> Result<Self, SensorError> {
+        let driver = match kind {
+            SensorKind::Htu21d => Driver::Htu21d(Htu21dSensor::new(i2c)),
+        };
+        Ok(driver)
+    }
+}
+
+impl AsyncSensor for Driver {
+    type Error = SensorError;
+    type MeasureFuture<'a>
+        = impl Future<Output = Result<Measurements, SensorError>> + 'a
+    where
+        Self: 'a;
+
+    fn name(&self) -> &'static str {
+        match self {
+            Driver::Htu21d(sensor) => sensor.name(),
+        }
+    }
+
+    fn measure(&mut self, timestamp_millis: u64) -> Self::MeasureFuture<'_> {
+        async move {
+            match self {
+                Driver::Htu21d(sensor) => sensor.measure(timestamp_millis).await,
+            }
+        }
+    }
+}
+
+/// Accelerometer and gyroscope ICM42670. It is sampled continuously, at
+/// the output data rate, and the samples of each window are aggregated.
+pub struct Icm42670Sensor {
+    icm: Icm42670<I2cBus>,
+    config: ImuConfig,
+}
+
+impl Icm42670Sensor {
+    pub fn new(i2c: I2cBus, config: ImuConfig) -> Result<Self, SensorError> {
+        let mut icm = Icm42670::new(i2c, Address::Primary).map_err(|_| SensorError::Device)?;
+        icm.set_accel_range(config.accel_range)
+            .and_then(|_| icm.set_gyro_range(config.gyro_range))
+            .and_then(|_| icm.set_accel_odr(config.accel_odr))
+            .and_then(|_| icm.set_gyro_odr(config.gyro_odr))
+            .map_err(|_| SensorError::Bus)?;
+        Ok(Icm42670Sensor { icm, config })
+    }
+
+    pub fn name(&self) -> &'static str {
+        "icm42670"
+    }
+
+    /// Samples the sensor during a window and returns the aggregates.
+    /// `start_millis` is the current time, the samples are timed from it.
+    pub async fn measure_window(&mut self, start_millis: u64) -> Result<ImuWindow, SensorError> {
+        let mut window = ImuWindow::new(self.name(), self.config.settings(), start_millis);
+        let period = self.config.sample_period();
+        let start = Instant::now();
+        let mut next = start;
+        while next < start + self.config.window {
+            let accel = self.icm.accel_norm().map_err(|_| SensorError::Bus)?;
+            let gyro = self.icm.gyro_norm().map_err(|_| SensorError::Bus)?;
+            let elapsed = Instant::now() - start;
+            window.add(
+                start_millis + elapsed.as_millis(),
+                [accel.x, accel.y, accel.z],
+                [gyro.x, gyro.y, gyro.z],
+            );
+            // timed from the start, so the time of the reads doesn't add up
+            next += period;
+            Timer::at(next).await;
+        }
+        Ok(window)
+    }
+}
+
+/// External temperature and humidity sensor HTU21D, similar to SI7021
+pub struct Htu21dSensor {
+    htu: Htu21d<I2cBus>,
+    /// The sensor is reset and configured before the first measurement
+    initialized: bool,
+}
+
+impl Htu21dSensor {
+    const RESOLUTION: Resolution = Resolution::Rh12Temp14;
+
+    pub fn new(i2c: I2cBus) -> Self {
+        Htu21dSensor {
+            htu: Htu21d::new(i2c),
+            initialized: false,
+        }
+    }
+
+    async fn init(&mut self) -> Result<(), SensorError> {
+        self.htu.soft_reset().await?;
+        self.htu.set_resolution(Self::RESOLUTION)?;
+        self.initialized = true;
+        Ok(())
+    }
+}
+
+impl<E> From<htu21d::Error<E>> for SensorError {
+    fn from(err: htu21d::Error<E>) -> Self {
+        match err {
+            htu21d::Error::I2c(_) => SensorError::Bus,
+            htu21d::Error::Crc => SensorError::Checksum,
+            htu21d::Error::Timeout => SensorError::Timeout,
+        }
+    }
+}
+
+impl AsyncSensor for Htu21dSensor {
+    type Error = SensorError;
+    type MeasureFuture<'a>
+        = impl Future<Output = Result<Measurements, SensorError>> + 'a
+    where
+        Self: 'a;
+
+    fn name(&self) -> &'static str {
+        "htu21d"
+    }
+
+    fn measure(&mut self, timestamp_millis: u64) -> Self::MeasureFuture<'_> {
+        async move {
+            let name = self.name();
+            if !self.initialized {
+                self.init().await?;
+            }
+            let temp = self.htu.temperature().await?;
+            let rel_hum = self.htu.relative_humidity().await?;
+
+            let mut measurements = Measurements::new();
+            measurements
+                .push(Measurement::new(
+                    name,
+                    Quantity::Temperature,
+                    temp,
+                    timestamp_millis,
+                ))
+                .ok();
+            measurements
+                .push(Measurement::new(
+                    name,
+                    Quantity::RelativeHumidity,
+                    rel_hum,
+                    timestamp_millis,
+                ))
+                .ok();
+            Ok(measurements)
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Transport of the MQTT client: a TCP socket, with TLS over it when the
+//! `tls` feature is enabled. With TLS the certificate of the server must be
+//! signed by the CA installed with the identity of the device (see
+//! `identity`), or else by the one built into the firmware (MQTT_CA), and
+//! match the name of the server. With a client identity installed the device
+//! authenticates with its certificate in the handshake.
+//!
+//! The TLS session is created for each connection and needs its own handle
+//! of the socket, so the socket is in a static RefCell and the handles
+//! borrow it for each operation. They are only used by the MQTT client,
+//! behind its mutex, so the borrows never overlap.
+//!
+//! The session decrypts a whole record at a time. The MQTT client asks
+//! `can_recv` before reading, and the socket doesn't know about the data
+//! already decrypted, so each record is read at once into a buffer of the
+//! transport and handed out from there.
+
+use core::cell::RefCell;
+use embassy_net::tcp::{State, TcpSocket};
+use embassy_net::IpEndpoint;
+use embassy_time::Duration;
+use embedded_io::{
+    asynch::{Read, Write},
+    Error, ErrorKind, Io,
+};
+use tiny_mqtt::Connection;
+
+use crate::identity::Identity;
+
+#[cfg(feature = "tls")]
+use esp_mbedtls::{
+    asynch::{AsyncConnectedSession, Session},
+    Certificates, Mode, TlsVersion, X509,
+};
+
+/// CA certificate of the MQTT server when there is none installed, PEM
+/// ended by a nul character. It is read at build time from the file given
+/// in the MQTT_CA variable.
+#[cfg(feature = "tls")]
+const MQTT_CA: &str = concat!(include_str!(env!("MQTT_CA")), "\0");
+
+/// Maximum length of the data of a TLS record
+#[cfg(feature = "tls")]
+const MAX_RECORD_LEN: usize = 16_384;
+
+/// Handle of the socket of the transport
+#[derive(Clone, Copy)]
+pub struct SocketRef(&'static RefCell<TcpSocket<'static>>);
+
+impl Io for SocketRef {
+    type Error = ErrorKind;
+}
+
+impl Read for SocketRef {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        self.0.borrow_mut().read(buf).await.map_err(|e| e.kind())
+    }
+}
+
+impl Write for SocketRef {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        self.0.borrow_mut().write(buf).await.map_err(|e| e.kind())
+    }
+
+    async fn flush(&mut self) -> Result<(), ErrorKind> {
+        self.0.borrow_mut().flush().await.map_err(|e| e.kind())
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// The TCP connection could not be opened
+    Connect(embassy_net::tcp::ConnectError),
+    /// The TLS handshake failed: the server certificate is not valid, not
+    /// signed by the CA or for another name, or the server doesn't use TLS
+    #[cfg(feature = "tls")]
+    Tls(esp_mbedtls::TlsError),
+}
+
+/// Connection with the MQTT server
+pub struct MqttTransport {
+    socket: SocketRef,
+    #[allow(dead_code)]
+    identity: Identity,
+    #[cfg(feature = "tls")]
+    tls: Option<AsyncConnectedSession<SocketRef>>,
+    // Data of the last record not read yet
+    #[cfg(feature = "tls")]
+    plaintext: Plaintext,
+}
+
+/// Data decrypted by the TLS session and not read yet
+#[cfg(feature = "tls")]
+struct Plaintext {
+    bytes: [u8; MAX_RECORD_LEN],
+    start: usize,
+    end: usize,
+}
+
+#[cfg(feature = "tls")]
+impl Plaintext {
+    const fn new() -> Self {
+        Plaintext {
+            bytes: [0; MAX_RECORD_LEN],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    fn clear(&mut self) {
+        self.start = 0;
+        self.end = 0;
+    }
+
+    /// Copies the data waiting to `buf`, as much as fits
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.end - self.start);
+        buf[..len].copy_from_slice(&self.bytes[self.start..self.start + len]);
+        self.start += len;
+        len
+    }
+}
+
+impl MqttTransport {
+    /// The identity is only used with TLS
+    pub fn new(socket: &'static RefCell<TcpSocket<'static>>, identity: Identity) -> Self {
+        MqttTransport {
+            socket: SocketRef(socket),
+            identity,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            plaintext: Plaintext::new(),
+        }
+    }
+
+    /// The connection uses TLS
+    pub const fn is_secure() -> bool {
+        cfg!(feature = "tls")
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.socket.0.borrow_mut().set_timeout(timeout);
+    }
+
+    /// Opens the connection with the server. `server_name` is the name the
+    /// server certificate must have (TLS only).
+    #[allow(unused_variables)]
+    pub async fn connect(
+        &mut self,
+        endpoint: impl Into<IpEndpoint>,
+        server_name: &str,
+    ) -> Result<(), TransportError> {
+        self.socket
+            .0
+            .borrow_mut()
+            .connect(endpoint)
+            .await
+            .map_err(TransportError::Connect)?;
+
+        #[cfg(feature = "tls")]
+        {
+            let client = self.identity.client;
+            let certificates = Certificates {
+                ca_chain: X509::pem(self.identity.ca.unwrap_or(MQTT_CA).as_bytes()).ok(),
+                certificate: client
+                    .and_then(|client| X509::pem(client.certificate.as_bytes()).ok()),
+                private_key: client
+                    .and_then(|client| X509::pem(client.private_key.as_bytes()).ok()),
+                ..Default::default()
+            };
+            let session = Session::new(
+                self.socket,
+                server_name,
+                Mode::Client,
+                TlsVersion::Tls1_2,
+                certificates,
+            )
+            .map_err(TransportError::Tls)?;
+            self.plaintext.clear();
+            self.tls = Some(session.connect().await.map_err(TransportError::Tls)?);
+        }
+        Ok(())
+    }
+
+    /// Closes the connection at once, without waiting for the server. The
+    /// socket can be connected again.
+    pub fn abort(&mut self) {
+        #[cfg(feature = "tls")]
+        {
+            self.tls = None;
+            self.plaintext.clear();
+        }
+        self.socket.0.borrow_mut().abort();
+    }
+}
+
+impl Io for MqttTransport {
+    type Error = ErrorKind;
+}
+
+impl Read for MqttTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls.as_mut() {
+            // a whole record, nothing is left in the session
+            if self.plaintext.is_empty() {
+                let len = tls
+                    .read(&mut self.plaintext.bytes)
+                    .await
+                    .map_err(|e| e.kind())?;
+                self.plaintext.start = 0;
+                self.plaintext.end = len;
+            }
+            return Ok(self.plaintext.read(buf));
+        }
+        self.socket.read(buf).await
+    }
+}
+
+impl Write for MqttTransport {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls.as_mut() {
+            return tls.write(buf).await.map_err(|e| e.kind());
+        }
+        self.socket.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), ErrorKind> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls.as_mut() {
+            return tls.flush().await.map_err(|e| e.kind());
+        }
+        self.socket.flush().await
+    }
+}
+
+impl Connection for MqttTransport {
+    // With TLS the data waiting in the socket may be an incomplete record:
+    // read() waits for the rest of it, which is already on the way.
+    fn can_recv(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if !self.plaintext.is_empty() {
+            return true;
+        }
+        self.socket.0.borrow().can_recv()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.socket.0.borrow().state() == State::Established
+    }
+
+    fn close(&mut self) {
+        #[cfg(feature = "tls")]
+        {
+            self.tls = None;
+            self.plaintext.clear();
+        }
+        self.socket.0.borrow_mut().close()
+    }
+}
@@ -0,0 +1,167 @@
+//! Simulator of the sensor firmware for Linux. It drives the state machine
+//! with the events of a script, or typed in the terminal, and prints the
+//! transitions between states. The wifi, MQTT, web server and system are
+//! the fakes of `sensor_core::sim`, the same used by the scenarios in
+//! `tests/`, and the storage is in memory, kept across simulated reboots.
+//!
+//! Usage: sensor-sim [-v] [script]
+//!
+//! A script exits with an error when an `expect` fails.
+//!
+//! Commands, one per line (`#` starts a comment):
+//!
+//! - `credentials <ssid> <psk> <host> [port]`: credentials from the portal
+//! - `wifi ok|fail`: result of the next wifi connections
+//! - `wifi-disconnected`, `mqtt-connected`, `mqtt-disconnected`
+//! - `mqtt ok|fail`: result of the next MQTT publications
+//! - `sensor <temperature> [humidity]`: a reading of the sensor
+//! - `command <text>`: a remote command from MQTT
+//! - `scan`: scan the wifi networks from the portal
+//! - `timer`: fires the timed event pending
+//! - `factory-reset`, `reboot`
+//! - `state`: prints the current state
+//! - `expect <state>`: exits with an error if not in that state
+
+use anyhow::{anyhow, bail, Result};
+use log::{LevelFilter, Metadata, Record};
+use sensor_core::config::MemoryStorage;
+use sensor_core::provisioning::DEFAULT_MQTT_PORT;
+use sensor_core::sim::{credentials, reading, Device};
+use sensor_core::transitions::{Event, State};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::mpsc;
+
+fn parse_state(name: &str) -> Result<State> {
+    let state = match name {
+        "Initial" => State::Initial,
+        "Provisioning" => State::Provisioning,
+        "Provisioned" => State::Provisioned,
+        "WifiConnected" => State::WifiConnected,
+        "ServerConnected" => State::ServerConnected,
+        "Failure" => State::Failure,
+        _ => bail!("unknown state {}", name),
+    };
+    Ok(state)
+}
+
+fn parse_outcome(word: Option<&str>) -> Result<bool> {
+    match word {
+        Some("ok") => Ok(true),
+        Some("fail") => Ok(false),
+        _ => bail!("expected ok or fail"),
+    }
+}
+
+/// Runs a command of the script
+fn execute(device: &mut Device, line: &str) -> Result<()> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(());
+    };
+    match command {
+        "credentials" => {
+            let mut value = || words.next().ok_or_else(|| anyhow!("missing credentials"));
+            let (wifi_ssid, wifi_psk, mqtt_host) = (value()?, value()?, value()?);
+            let mqtt_port = match words.next() {
+                Some(port) => port.parse()?,
+                None => DEFAULT_MQTT_PORT,
+            };
+            device.send(credentials(wifi_ssid, wifi_psk, mqtt_host, mqtt_port));
+        }
+        "wifi" => device.fsm.wifi.works = parse_outcome(words.next())?,
+        "mqtt" => device.fsm.mqtt.works = parse_outcome(words.next())?,
+        "wifi-disconnected" => {
+            device.fsm.wifi.connected = false;
+            device.send(Event::WifiDisconnected);
+        }
+        "mqtt-connected" => device.send(Event::MqttConnected),
+        "mqtt-disconnected" => device.send(Event::MqttDisconnected),
+        "sensor" => {
+            let temp = words.next().ok_or_else(|| anyhow!("missing value"))?;
+            let rel_hum = words.next().map(str::parse).transpose()?;
+            let timestamp_millis = device.fsm.system.now_millis;
+            device.send(reading(timestamp_millis, temp.parse()?, rel_hum));
+        }
+        "command" => {
+            let command = words.collect::<Vec<_>>().join(" ");
+            device.send(Event::RemoteCommand { command });
+        }
+        "scan" => {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            device.send(Event::ScanNetworks(reply_tx));
+            match reply_rx.try_recv() {
+                Ok(networks) => println!("  scan: {} networks", networks.len()),
+                Err(_) => println!("  scan: not available"),
+            }
+        }
+        "timer" => device.fire_timer()?,
+        "factory-reset" => device.send(Event::FactoryReset),
+        "reboot" => device.send(Event::Reboot),
+        "state" => println!("state {:?}", device.fsm.state),
+        "expect" => {
+            let expected = parse_state(words.next().unwrap_or_default())?;
+            if device.fsm.state != expected {
+                bail!(
+                    "expected state {:?}, it is {:?}",
+                    expected,
+                    device.fsm.state
+                );
+            }
+        }
+        _ => bail!("unknown command {}", command),
+    }
+    Ok(())
+}
+
+/// Prints the log of the state machine
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        println!("  [{}] {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+fn main() -> Result<()> {
+    let mut script = None;
+    for arg in std::env::args().skip(1) {
+        if arg == "-v" {
+            log::set_logger(&Logger)
+                .map(|()| log::set_max_level(LevelFilter::Info))
+                .ok();
+        } else {
+            script = Some(arg);
+        }
+    }
+    let input: Box<dyn BufRead> = match &script {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let mut device = Device::boot(MemoryStorage::default(), 0);
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if script.is_some() {
+            println!("> {}", line);
+        }
+        if let Err(err) = execute(&mut device, line) {
+            // a script stops at the first error, the terminal goes on
+            if script.is_some() {
+                bail!("line {}: {}", number + 1, err);
+            }
+            println!("error: {}", err);
+        }
+    }
+    Ok(())
+}
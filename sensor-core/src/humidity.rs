@@ -0,0 +1,168 @@
+//! Values derived from the temperature and the relative humidity, with the
+//! Magnus formula (Sonntag 1990 coefficients, error under 0.35 °C between
+//! -45 °C and 60 °C).
+
+use common::sensor::{Measurement, Measurements, Quantity};
+
+/// Coefficients of the Magnus formula over water
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+/// Saturation vapour pressure at 0 °C, in hPa
+const MAGNUS_C: f32 = 6.112;
+/// Specific gas constant of water vapour, scaled to get g/m³ from hPa and K
+const VAPOUR_CONSTANT: f32 = 216.7;
+
+/// Saturation vapour pressure, in hPa
+fn saturation_pressure(temp: f32) -> f32 {
+    MAGNUS_C * (MAGNUS_A * temp / (MAGNUS_B + temp)).exp()
+}
+
+/// Dew point in °C, from the temperature in °C and the relative humidity
+/// in %. None if the humidity is not above 0 %.
+pub fn dew_point(temp: f32, rel_hum: f32) -> Option<f32> {
+    if rel_hum <= 0.0 {
+        return None;
+    }
+    let gamma = (rel_hum.min(100.0) / 100.0).ln() + MAGNUS_A * temp / (MAGNUS_B + temp);
+    Some(MAGNUS_B * gamma / (MAGNUS_A - gamma))
+}
+
+/// Absolute humidity in g/m³, from the temperature in °C and the relative
+/// humidity in %
+pub fn absolute_humidity(temp: f32, rel_hum: f32) -> f32 {
+    let vapour_pressure = saturation_pressure(temp) * rel_hum.clamp(0.0, 100.0) / 100.0;
+    VAPOUR_CONSTANT * vapour_pressure / (273.15 + temp)
+}
+
+/// Adds the dew point and the absolute humidity to the measurements of a
+/// sensor that has both temperature and relative humidity. At 0 % there is
+/// no dew point: only the absolute humidity, 0 g/m³, is added.
+pub fn add_derived(measurements: &mut Measurements) {
+    let find = |quantity| measurements.iter().find(|m| m.quantity == quantity);
+    let (Some(temp), Some(rel_hum)) = (
+        find(Quantity::Temperature),
+        find(Quantity::RelativeHumidity),
+    ) else {
+        return;
+    };
+    let (sensor, timestamp_millis) = (temp.sensor, temp.timestamp_millis);
+    let (temp, rel_hum) = (temp.value, rel_hum.value);
+
+    if let Some(dew_point) = dew_point(temp, rel_hum) {
+        measurements
+            .push(Measurement::new(
+                sensor,
+                Quantity::DewPoint,
+                dew_point,
+                timestamp_millis,
+            ))
+            .ok();
+    }
+    measurements
+        .push(Measurement::new(
+            sensor,
+            Quantity::AbsoluteHumidity,
+            absolute_humidity(temp, rel_hum),
+            timestamp_millis,
+        ))
+        .ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{} not {} ± {}",
+            value,
+            expected,
+            tolerance
+        );
+    }
+
+    fn measurements(temp: f32, rel_hum: Option<f32>) -> Measurements {
+        let mut measurements = Measurements::new();
+        let mut add = |quantity, value| {
+            measurements
+                .push(Measurement::new("htu21d", quantity, value, 1_000))
+                .unwrap();
+        };
+        add(Quantity::Temperature, temp);
+        if let Some(rel_hum) = rel_hum {
+            add(Quantity::RelativeHumidity, rel_hum);
+        }
+        measurements
+    }
+
+    fn value(measurements: &Measurements, quantity: Quantity) -> Option<f32> {
+        measurements
+            .iter()
+            .find(|m| m.quantity == quantity)
+            .map(|m| m.value)
+    }
+
+    #[test]
+    fn reference_values() {
+        assert_close(dew_point(25.0, 50.0).unwrap(), 13.9, 0.05);
+        assert_close(absolute_humidity(25.0, 50.0), 11.5, 0.05);
+        assert_close(dew_point(10.0, 80.0).unwrap(), 6.7, 0.05);
+        assert_close(absolute_humidity(10.0, 80.0), 7.5, 0.05);
+        assert_close(dew_point(-10.0, 60.0).unwrap(), -16.4, 0.1);
+        assert_close(absolute_humidity(-10.0, 60.0), 1.42, 0.01);
+    }
+
+    #[test]
+    fn saturated_air() {
+        for temp in [-20.0, 0.0, 21.5, 40.0] {
+            assert_close(dew_point(temp, 100.0).unwrap(), temp, 0.001);
+        }
+        assert_close(absolute_humidity(0.0, 100.0), 4.85, 0.01);
+        // readings over 100 % are taken as saturated air
+        assert_eq!(dew_point(21.5, 103.0), dew_point(21.5, 100.0));
+        assert_eq!(
+            absolute_humidity(21.5, 103.0),
+            absolute_humidity(21.5, 100.0)
+        );
+    }
+
+    #[test]
+    fn dry_air() {
+        // the logarithm of 0 % is -inf: there is no dew point
+        assert_eq!(dew_point(25.0, 0.0), None);
+        assert_eq!(dew_point(25.0, -1.0), None);
+        assert_eq!(absolute_humidity(25.0, 0.0), 0.0);
+        assert_eq!(absolute_humidity(25.0, -1.0), 0.0);
+        assert!(dew_point(25.0, 0.1).unwrap().is_finite());
+    }
+
+    #[test]
+    fn derived_measurements() {
+        let mut with_humidity = measurements(25.0, Some(50.0));
+        add_derived(&mut with_humidity);
+        assert_eq!(with_humidity.len(), 4);
+        assert_close(
+            value(&with_humidity, Quantity::DewPoint).unwrap(),
+            13.9,
+            0.05,
+        );
+        let derived = with_humidity.last().unwrap();
+        assert_eq!(derived.quantity, Quantity::AbsoluteHumidity);
+        assert_eq!(
+            (derived.sensor, derived.timestamp_millis),
+            ("htu21d", 1_000)
+        );
+
+        // dry air: only the absolute humidity, 0 g/m³
+        let mut dry = measurements(25.0, Some(0.0));
+        add_derived(&mut dry);
+        assert_eq!(value(&dry, Quantity::DewPoint), None);
+        assert_eq!(value(&dry, Quantity::AbsoluteHumidity), Some(0.0));
+
+        // only temperature: nothing to derive
+        let mut temperature = measurements(25.0, None);
+        add_derived(&mut temperature);
+        assert_eq!(temperature.len(), 1);
+    }
+}
@@ -0,0 +1,11 @@
+//! Logic of the sensor firmware that doesn't depend on the esp-idf
+//! services: the finite state machine, provisioning and configuration.
+//! It builds for the host too, where the simulator runs it.
+
+pub mod config;
+pub mod humidity;
+pub mod machine;
+pub mod provisioning;
+pub mod reset;
+pub mod sim;
+pub mod transitions;
@@ -0,0 +1,539 @@
+//! Finite state machine of the sensor. The side effects (wifi, storage,
+//! MQTT, web server and system services) are behind traits: implemented
+//! with the esp-idf services in the device, and with fakes in the simulator.
+
+use crate::config::{erase_config, load_config, store_config, DeviceConfig, Storage};
+use crate::provisioning::{Network, ProvisioningStatus};
+use crate::transitions::{Event, State};
+use anyhow::Result;
+use common::backoff::Backoff;
+use common::buffer::{OverflowPolicy, ReadingBuffer, Stamped};
+use common::sensor::{Measurement, Quantity};
+use common::x509;
+use log::{error, info, warn};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// Number of sensor readings kept while there is no connection with the
+/// MQTT server
+const READINGS_CAPACITY: usize = 64;
+/// Keep the readings not sent in storage, so they survive a reboot
+const PERSIST_READINGS: bool = true;
+/// The readings are stored once every this many new ones, not to write the
+/// flash every sample period during a long outage. A power loss can lose
+/// the last ones; a reboot of the machine stores them first.
+const PERSIST_EVERY: usize = 10;
+/// Storage key of the readings not sent
+const READINGS_KEY: &str = "readings";
+/// Size of a stored reading: timestamp (u64) and value (f32)
+const READING_SIZE: usize = 12;
+
+type Readings = ReadingBuffer<f32, READINGS_CAPACITY>;
+
+/// Time the provisioning portal is kept after a successful connection,
+/// so it can show the result
+const PORTAL_GRACE: Duration = Duration::from_secs(5);
+
+/// Time to wait for the connection with the MQTT server
+const MQTT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delay between connection retries, doubling from the min to the max
+const RETRY_MIN_MILLIS: u64 = 5_000;
+const RETRY_MAX_MILLIS: u64 = 300_000;
+/// Failures in a row before falling back to provisioning
+const MAX_RETRIES: u32 = 6;
+/// Time in provisioning mode, after falling back with credentials stored,
+/// before rebooting to try them again
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Remote command that asks for the status of the certificate of the device
+pub const CERTIFICATE_COMMAND: &str = "certificate";
+/// Maximum size of the certificate of the device in DER format
+const MAX_CERTIFICATE_LEN: usize = 4096;
+
+/// Wifi interface
+pub trait Wifi {
+    /// Starts the access point for provisioning. The station is started
+    /// too, not connected, to scan the networks.
+    fn start_ap(&mut self) -> Result<()>;
+    /// Stops the access point, keeping the station connected
+    fn stop_ap(&mut self) -> Result<()>;
+    /// Connects the station to a network, waiting for the connection. With
+    /// `keep_ap` the access point stays active (trial during provisioning).
+    fn connect(&mut self, ssid: &str, psk: &str, keep_ap: bool) -> Result<()>;
+    fn disconnect(&mut self);
+    fn is_connected(&self) -> bool;
+    /// Networks around, the strongest first
+    fn scan(&mut self) -> Result<Vec<Network>>;
+}
+
+/// Client of the MQTT server. It sends MqttConnected and MqttDisconnected
+/// to the machine when the connection changes.
+pub trait Mqtt {
+    fn start(&mut self, config: &DeviceConfig, device_id: &str) -> Result<()>;
+    fn stop(&mut self);
+    fn is_started(&self) -> bool;
+    /// Publishes the retained "online" status and subscribes to the topics
+    /// of the device. Done on every connection: after a reconnection the
+    /// server has published the last will, and with a clean session it has
+    /// dropped the subscriptions.
+    fn announce(&mut self) -> Result<()>;
+    /// Sends a measurement to the topic of its quantity
+    fn send_measurement(&mut self, measurement: &Measurement) -> Result<()>;
+    /// Sends a reading taken while disconnected
+    fn send_reading(&mut self, reading: &Stamped<f32>) -> Result<()>;
+    /// Sends the status of the certificate of the device (JSON)
+    fn send_certificate_status(&mut self, status: &str) -> Result<()>;
+}
+
+/// Provisioning web server. It sends Credentials and ScanNetworks to the
+/// machine, and shows the provisioning status.
+pub trait Http {
+    fn start(&mut self, status: &Arc<Mutex<ProvisioningStatus>>) -> Result<()>;
+    fn stop(&mut self);
+    fn is_running(&self) -> bool;
+}
+
+/// Services of the system
+pub trait System {
+    /// Sends the event to the machine after the delay. It replaces the event
+    /// pending, if any.
+    fn send_after(&mut self, delay: Duration, event: Event);
+    /// Cancels the event pending
+    fn cancel_timer(&mut self);
+    fn reboot(&mut self);
+    fn random(&mut self) -> u32;
+}
+
+pub struct Fsm<W, S, M, H, Y> {
+    pub state: State,
+    pub tx: mpsc::Sender<Event>,
+    pub wifi: W,
+    pub storage: S,
+    pub mqtt: M,
+    pub http: H,
+    pub system: Y,
+    // Result of the provisioning, shared with the web server
+    provisioning_status: Arc<Mutex<ProvisioningStatus>>,
+    // Configuration in use (wifi and mqtt credentials)
+    config: DeviceConfig,
+    // Identifier of the device (MAC address of the wifi station)
+    device_id: String,
+    // Sensor readings waiting to be sent to the MQTT server
+    readings: Readings,
+    // Readings taken since the buffer was last stored
+    unsaved_readings: usize,
+    // Delay of the next retry after a failure
+    retry: Backoff,
+}
+
+impl<W, S, M, H, Y> Fsm<W, S, M, H, Y>
+where
+    W: Wifi,
+    S: Storage,
+    M: Mqtt,
+    H: Http,
+    Y: System,
+{
+    /// Creates the machine in the Initial state
+    pub fn new(
+        tx: mpsc::Sender<Event>,
+        wifi: W,
+        storage: S,
+        mqtt: M,
+        http: H,
+        system: Y,
+        device_id: String,
+    ) -> Self {
+        let mut readings = Readings::new(OverflowPolicy::DropOldest);
+        if PERSIST_READINGS {
+            load_readings(&storage, &mut readings);
+        }
+        let mut fsm = Self {
+            state: State::Initial,
+            tx,
+            wifi,
+            storage,
+            mqtt,
+            http,
+            system,
+            provisioning_status: Arc::new(Mutex::new(ProvisioningStatus::Waiting)),
+            config: DeviceConfig::default(),
+            device_id,
+            readings,
+            unsaved_readings: 0,
+            retry: Backoff::new(RETRY_MIN_MILLIS, RETRY_MAX_MILLIS),
+        };
+        fsm.enter_state();
+        fsm
+    }
+
+    pub fn process_event(&mut self, event: Event) {
+        // a disconnection from an earlier connection attempt
+        if let Event::WifiDisconnected = event {
+            if self.wifi.is_connected() {
+                info!("Ignoring wifi disconnection, it is connected");
+                return;
+            }
+        }
+        // handle events that keep the machine in current state
+        self.handle_event(&event);
+        // handle events that make the machine's state to change
+        if let Some(newstate) = self.state.next(event.kind()) {
+            // the timed event was for the previous state
+            self.system.cancel_timer();
+            self.state = newstate;
+            self.enter_state();
+        }
+    }
+
+    /// It handles the events that keep the machine in the same state
+    fn handle_event(&mut self, event: &Event) {
+        match (&self.state, event) {
+            (State::ServerConnected, Event::Measurements(measurements)) => {
+                self.send_readings();
+                for measurement in measurements {
+                    info!(
+                        "Sending {} sensor data to MQTT: {} {}",
+                        measurement.quantity.name(),
+                        measurement.value,
+                        measurement.unit.symbol()
+                    );
+                    if let Err(err) = self.mqtt.send_measurement(measurement) {
+                        error!("Error sending data to MQTT server: {}", err);
+                        if measurement.quantity == Quantity::Temperature {
+                            self.store_reading(measurement);
+                        }
+                    }
+                }
+            }
+            (
+                State::Initial | State::Provisioning,
+                Event::Credentials {
+                    wifi_ssid,
+                    wifi_psk,
+                    mqtt_host,
+                    mqtt_port,
+                    mqtt_user,
+                    mqtt_passwd,
+                    mqtt_ca,
+                    mqtt_cert,
+                    mqtt_key,
+                },
+            ) => {
+                info!("Recibido evento de provisionamiento");
+                // credentials to try in state Provisioned
+                self.config = DeviceConfig {
+                    wifi_ssid: wifi_ssid.clone(),
+                    wifi_psk: wifi_psk.clone(),
+                    mqtt_host: mqtt_host.clone(),
+                    mqtt_port: *mqtt_port,
+                    mqtt_user: mqtt_user.clone(),
+                    mqtt_passwd: mqtt_passwd.clone(),
+                    mqtt_ca: mqtt_ca.clone(),
+                    mqtt_cert: mqtt_cert.clone(),
+                    mqtt_key: mqtt_key.clone(),
+                };
+            }
+            (State::Provisioning, Event::ScanNetworks(reply)) => {
+                let networks = self.wifi.scan().unwrap_or_else(|err| {
+                    error!("Error scanning wifi networks: {}", err);
+                    Vec::new()
+                });
+                // the requester may have given up waiting
+                reply.send(networks).ok();
+            }
+            (State::ServerConnected, Event::RemoteCommand { command })
+                if command.trim() == CERTIFICATE_COMMAND =>
+            {
+                let status = certificate_status(&self.config);
+                if let Err(err) = self.mqtt.send_certificate_status(&status) {
+                    error!("Error sending the certificate status: {}", err);
+                }
+            }
+            (_s, Event::RemoteCommand { command }) => {
+                info!("Remote command received {}", command);
+            }
+            (State::Provisioned, Event::ClosePortal) => {
+                info!("Deactivating HTTP server");
+                self.http.stop();
+                if let Err(err) = self.wifi.stop_ap() {
+                    warn!("Error stopping wifi AP: {}", err);
+                }
+                self.tx.send(Event::WifiConnected).unwrap();
+            }
+            (_s, Event::FactoryReset) => self.factory_reset(),
+            (_s, Event::Reboot) => {
+                if self.unsaved_readings > 0 {
+                    self.save_readings();
+                }
+                warn!("Rebooting");
+                self.system.reboot();
+            }
+            (_s, Event::Measurements(measurements)) => {
+                // no connection with the server: keep the temperature for later
+                for measurement in measurements {
+                    if measurement.quantity == Quantity::Temperature {
+                        self.store_reading(measurement);
+                    }
+                }
+            }
+            (_s, _e) => {}
+        }
+    }
+
+    /// Erases the configuration and the stored readings and reboots.
+    /// Without configuration the device starts in provisioning mode.
+    fn factory_reset(&mut self) {
+        warn!("Factory reset: erasing configuration");
+        if let Err(err) = erase_config(&mut self.storage) {
+            error!("Error erasing the configuration: {}", err);
+        }
+        if let Err(err) = self.storage.remove(READINGS_KEY) {
+            error!("Error erasing the stored readings: {}", err);
+        }
+        warn!("Factory reset: rebooting");
+        self.system.reboot();
+    }
+
+    fn set_provisioning_status(&self, status: ProvisioningStatus) {
+        *self.provisioning_status.lock().unwrap() = status;
+    }
+
+    /// Keeps a reading in the buffer to be sent when connected
+    fn store_reading(&mut self, measurement: &Measurement) {
+        self.readings
+            .push(measurement.timestamp_millis, measurement.value);
+        info!(
+            "Sensor reading stored, {} waiting ({} dropped)",
+            self.readings.len(),
+            self.readings.dropped()
+        );
+        self.unsaved_readings += 1;
+        if self.unsaved_readings >= PERSIST_EVERY {
+            self.save_readings();
+        }
+    }
+
+    /// Sends the stored readings to the MQTT server, oldest first.
+    /// It stops at the first error, the rest are sent later.
+    fn send_readings(&mut self) {
+        if self.readings.is_empty() || !self.mqtt.is_started() {
+            return;
+        }
+        info!("Sending {} stored readings to MQTT", self.readings.len());
+        while let Some(reading) = self.readings.front() {
+            if let Err(err) = self.mqtt.send_reading(reading) {
+                error!("Error sending stored reading to MQTT server: {}", err);
+                break;
+            }
+            self.readings.pop();
+        }
+        self.save_readings();
+    }
+
+    /// Stores the readings waiting, if they are kept across reboots
+    fn save_readings(&mut self) {
+        if PERSIST_READINGS {
+            save_readings(&mut self.storage, &self.readings);
+        }
+        self.unsaved_readings = 0;
+    }
+
+    /// It runs the acctions needed when the machine enters a new state
+    fn enter_state(&mut self) {
+        info!("******** Entering state {:?}", self.state);
+        match self.state {
+            State::Initial => {
+                let config = load_config(&mut self.storage);
+                if config.is_provisioned() {
+                    info!("Credentials from NVS: {:?}", config);
+                    // provisioned: generate event to change state
+                    let event = Event::Credentials {
+                        wifi_ssid: config.wifi_ssid,
+                        wifi_psk: config.wifi_psk,
+                        mqtt_host: config.mqtt_host,
+                        mqtt_port: config.mqtt_port,
+                        mqtt_user: config.mqtt_user,
+                        mqtt_passwd: config.mqtt_passwd,
+                        mqtt_ca: config.mqtt_ca,
+                        mqtt_cert: config.mqtt_cert,
+                        mqtt_key: config.mqtt_key,
+                    };
+                    self.tx.send(event).unwrap();
+                } else {
+                    info!("Credentials not found in NVS.");
+                    self.tx.send(Event::NotProvisioned).unwrap();
+                }
+            }
+            State::Provisioning => {
+                // also after a failed connection, to stop the station
+                info!("Activating wifi AP.");
+                self.wifi.start_ap().expect("Error activating AP");
+                if !self.http.is_running() {
+                    info!("Activating HTTP server");
+                    self.http
+                        .start(&self.provisioning_status)
+                        .expect("Error activating HTTP server");
+                }
+                // after falling back with credentials stored, they are tried
+                // again later if nobody changes them
+                if load_config(&mut self.storage).is_provisioned() {
+                    warn!("Rebooting in {:?} if not provisioned", FALLBACK_TIMEOUT);
+                    self.system.send_after(FALLBACK_TIMEOUT, Event::Reboot);
+                }
+            }
+            State::Provisioned => {
+                let wifi_ssid = self.config.wifi_ssid.clone();
+                let wifi_psk = self.config.wifi_psk.clone();
+                info!("Trying to connect to wifi station {wifi_ssid}.");
+                // credentials from the provisioning portal (not from NVS)
+                let provisioning = self.http.is_running();
+                // connect to wifi using the credentials. During provisioning
+                // it is a trial, with the access point still active.
+                if provisioning {
+                    self.set_provisioning_status(ProvisioningStatus::Connecting);
+                }
+                if let Err(err) = self.wifi.connect(&wifi_ssid, &wifi_psk, provisioning) {
+                    error!("Error connecting to wifi {}: {}", wifi_ssid, err);
+                    self.wifi.disconnect();
+                    if provisioning {
+                        self.set_provisioning_status(ProvisioningStatus::Failed(err.to_string()));
+                        self.tx.send(Event::ProvisioningFailed).unwrap();
+                    } else {
+                        self.tx.send(Event::WifiFailed).unwrap();
+                    }
+                    return;
+                }
+
+                if provisioning {
+                    // they work: store credentials permanently in NVS
+                    if let Err(err) = store_config(&mut self.storage, &self.config) {
+                        error!("Error storing the configuration: {}", err);
+                    }
+                    self.set_provisioning_status(ProvisioningStatus::Connected);
+                    // give the portal time to show the result before stopping it
+                    self.system.send_after(PORTAL_GRACE, Event::ClosePortal);
+                    return;
+                }
+                self.tx.send(Event::WifiConnected).unwrap();
+            }
+            State::WifiConnected => {
+                info!("State WifiConnected.");
+                // MqttConnected comes from the client when it connects
+                self.system
+                    .send_after(MQTT_CONNECT_TIMEOUT, Event::MqttFailed);
+                if self.mqtt.is_started() {
+                    info!("Waiting for the MQTT client to reconnect.");
+                    return;
+                }
+                match self.mqtt.start(&self.config, &self.device_id) {
+                    Ok(()) => info!("MQTT client started."),
+                    Err(err) => {
+                        error!("Error connecting to MQTT server: {}", err);
+                        self.tx.send(Event::MqttFailed).unwrap();
+                    }
+                }
+            }
+            State::ServerConnected => {
+                info!("State ServerConnected. Start sending periodic data.");
+                self.retry.reset();
+                if let Err(err) = self.mqtt.announce() {
+                    error!("Error announcing the device to the MQTT server: {}", err);
+                }
+                self.send_readings();
+            }
+            State::Failure => {
+                error!("Current state is Failure");
+                // connect again from the start
+                self.mqtt.stop();
+                if self.retry.attempts() < MAX_RETRIES {
+                    let delay = self.retry.next_delay(self.system.random());
+                    warn!(
+                        "Retrying connection in {} ms (attempt {} of {})",
+                        delay,
+                        self.retry.attempts(),
+                        MAX_RETRIES
+                    );
+                    self.system
+                        .send_after(Duration::from_millis(delay), Event::Retry);
+                } else {
+                    error!(
+                        "Connection failed {} times, back to provisioning",
+                        MAX_RETRIES
+                    );
+                    self.retry.reset();
+                    self.tx.send(Event::GiveUp).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Stores the readings, or removes them if there are none
+fn save_readings<S: Storage>(storage: &mut S, readings: &Readings) {
+    let res = if readings.is_empty() {
+        storage.remove(READINGS_KEY)
+    } else {
+        let bytes: Vec<u8> = readings.iter().flat_map(encode_reading).collect();
+        storage.write(READINGS_KEY, &bytes)
+    };
+    if let Err(err) = res {
+        warn!("Error storing readings: {}", err);
+    }
+}
+
+/// Recovers the readings stored before the last reboot
+fn load_readings<S: Storage>(storage: &S, readings: &mut Readings) {
+    match storage.read(READINGS_KEY) {
+        Ok(Some(bytes)) => {
+            for chunk in bytes.chunks_exact(READING_SIZE) {
+                let reading = decode_reading(chunk);
+                readings.push(reading.timestamp_millis, reading.value);
+            }
+            info!("{} readings recovered from storage", readings.len());
+        }
+        Ok(None) => {}
+        Err(err) => warn!("Error reading stored readings: {}", err),
+    }
+}
+
+fn encode_reading(reading: &Stamped<f32>) -> [u8; READING_SIZE] {
+    let mut bytes = [0u8; READING_SIZE];
+    bytes[..8].copy_from_slice(&reading.timestamp_millis.to_le_bytes());
+    bytes[8..].copy_from_slice(&reading.value.to_le_bytes());
+    bytes
+}
+
+fn decode_reading(bytes: &[u8]) -> Stamped<f32> {
+    let mut timestamp = [0u8; 8];
+    let mut value = [0u8; 4];
+    timestamp.copy_from_slice(&bytes[..8]);
+    value.copy_from_slice(&bytes[8..READING_SIZE]);
+    Stamped {
+        timestamp_millis: u64::from_le_bytes(timestamp),
+        value: f32::from_le_bytes(value),
+    }
+}
+
+/// Status of the certificate of the device, as JSON: its validity period
+/// ({"not_before":..,"not_after":..,"expires":<unix time>}), or
+/// {"status":"none"} without one and {"status":"invalid"} if it can't be read
+pub fn certificate_status(config: &DeviceConfig) -> String {
+    let Some(pem) = config.mqtt_cert.as_deref() else {
+        return String::from("{\"status\":\"none\"}");
+    };
+    let mut der = vec![0u8; MAX_CERTIFICATE_LEN];
+    match x509::pem_validity(pem, &mut der) {
+        Ok(validity) => {
+            let mut json = String::new();
+            validity
+                .write_json(&mut json)
+                .expect("Error formatting the validity");
+            json
+        }
+        Err(err) => {
+            warn!("Error reading the certificate of the device: {:?}", err);
+            String::from("{\"status\":\"invalid\"}")
+        }
+    }
+}
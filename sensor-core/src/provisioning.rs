@@ -0,0 +1,538 @@
+//! Credentials sent by the provisioning form: parsing of the urlencoded
+//! body and validation of the fields.
+//! Pure functions, without dependencies on the esp-idf services.
+
+use common::net::is_valid_host;
+use common::x509;
+
+/// Port used when the form leaves the MQTT port empty
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+/// Port used when the form leaves the MQTT port empty and has a CA
+/// certificate (MQTT over TLS)
+pub const DEFAULT_MQTTS_PORT: u16 = 8883;
+
+/// Delimiters of a certificate in PEM format
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+/// End of the first line of a private key in PEM format: "PRIVATE KEY",
+/// "EC PRIVATE KEY", "RSA PRIVATE KEY"
+const PEM_KEY_BEGIN: &str = "PRIVATE KEY-----";
+
+/// Maximum size of a certificate in DER format
+const MAX_CERTIFICATE_LEN: usize = 4096;
+
+/// Fields of the provisioning form, as they were entered
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CredentialsForm {
+    pub wifi_ssid: String,
+    pub wifi_psk: String,
+    pub mqtt_host: String,
+    pub mqtt_port: String,
+    pub mqtt_user: String,
+    pub mqtt_passwd: String,
+    pub mqtt_ca: String,
+    pub mqtt_cert: String,
+    pub mqtt_key: String,
+}
+
+/// Credentials validated, ready to be used
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub wifi_ssid: String,
+    pub wifi_psk: String,
+    pub mqtt_host: String,
+    pub mqtt_port: u16,
+    pub mqtt_user: Option<String>,
+    pub mqtt_passwd: Option<String>,
+    /// CA certificate (PEM) of the MQTT server. With it the connection uses
+    /// TLS and the server certificate must be signed by this CA.
+    pub mqtt_ca: Option<String>,
+    /// Certificate and private key (PEM) of the device, for TLS client
+    /// authentication
+    pub mqtt_cert: Option<String>,
+    pub mqtt_key: Option<String>,
+}
+
+/// Field of the form
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    WifiSsid,
+    WifiPsk,
+    MqttHost,
+    MqttPort,
+    MqttUser,
+    MqttPasswd,
+    MqttCa,
+    MqttCert,
+    MqttKey,
+}
+
+impl Field {
+    /// Name of the field in the HTML form
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::WifiSsid => "wifi_ssid",
+            Field::WifiPsk => "wifi_psk",
+            Field::MqttHost => "mqtt_host",
+            Field::MqttPort => "mqtt_port",
+            Field::MqttUser => "mqtt_user",
+            Field::MqttPasswd => "mqtt_passwd",
+            Field::MqttCa => "mqtt_ca",
+            Field::MqttCert => "mqtt_cert",
+            Field::MqttKey => "mqtt_key",
+        }
+    }
+}
+
+/// Wifi network found in a scan
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+    pub ssid: String,
+    /// Signal strength in dBm
+    pub rssi: i8,
+    pub channel: u8,
+    /// Authentication method: "open", "wpa2", ...
+    pub auth: &'static str,
+}
+
+/// Result of the trial connection with the credentials of the form,
+/// shown by the provisioning portal
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProvisioningStatus {
+    /// Waiting for the credentials
+    Waiting,
+    /// Trying to connect to the wifi network
+    Connecting,
+    /// Connected, the credentials are stored
+    Connected,
+    /// The connection failed, with the reason
+    Failed(String),
+}
+
+impl ProvisioningStatus {
+    /// Status as JSON: {"status":"failed","message":"..."}
+    pub fn to_json(&self) -> String {
+        let (status, message) = match self {
+            ProvisioningStatus::Waiting => ("waiting", ""),
+            ProvisioningStatus::Connecting => ("connecting", ""),
+            ProvisioningStatus::Connected => ("connected", ""),
+            ProvisioningStatus::Failed(message) => ("failed", message.as_str()),
+        };
+        format!(
+            "{{\"status\":{},\"message\":{}}}",
+            json_string(status),
+            json_string(message)
+        )
+    }
+}
+
+/// Problem found in a field of the form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: Field,
+    pub message: &'static str,
+}
+
+impl CredentialsForm {
+    /// Value entered in a field
+    pub fn value(&self, field: Field) -> &str {
+        match field {
+            Field::WifiSsid => &self.wifi_ssid,
+            Field::WifiPsk => &self.wifi_psk,
+            Field::MqttHost => &self.mqtt_host,
+            Field::MqttPort => &self.mqtt_port,
+            Field::MqttUser => &self.mqtt_user,
+            Field::MqttPasswd => &self.mqtt_passwd,
+            Field::MqttCa => &self.mqtt_ca,
+            Field::MqttCert => &self.mqtt_cert,
+            Field::MqttKey => &self.mqtt_key,
+        }
+    }
+}
+
+/// Parses an application/x-www-form-urlencoded body.
+/// Unknown fields are ignored and missing fields are left empty.
+pub fn parse_form(body: &str) -> CredentialsForm {
+    let mut form = CredentialsForm::default();
+    for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = url_decode(value);
+        match url_decode(name).as_str() {
+            "wifi_ssid" => form.wifi_ssid = value,
+            "wifi_psk" => form.wifi_psk = value,
+            "mqtt_host" => form.mqtt_host = value,
+            "mqtt_port" => form.mqtt_port = value,
+            "mqtt_user" => form.mqtt_user = value,
+            "mqtt_passwd" => form.mqtt_passwd = value,
+            "mqtt_ca" => form.mqtt_ca = value,
+            "mqtt_cert" => form.mqtt_cert = value,
+            "mqtt_key" => form.mqtt_key = value,
+            _ => {}
+        }
+    }
+    form
+}
+
+/// Decodes a urlencoded value: '+' is a space and %XX a byte.
+/// Malformed escapes are kept as they are, and invalid utf-8 is replaced.
+pub fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(&[high, low]) if bytes[i] == b'%' => hex_byte(high, low),
+            _ => None,
+        };
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (b, None) => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_byte(high: u8, low: u8) -> Option<u8> {
+    let digit = |c: u8| (c as char).to_digit(16);
+    Some((digit(high)? * 16 + digit(low)?) as u8)
+}
+
+/// Checks the fields of the form and converts them to credentials.
+/// It returns all the problems found, to show them together.
+pub fn validate(form: &CredentialsForm) -> Result<Credentials, Vec<FieldError>> {
+    let mut errors = Vec::new();
+    let mut error = |field, message| errors.push(FieldError { field, message });
+
+    // Wifi: SSID up to 32 bytes, WPA passphrase of 8 to 63 characters
+    // (or empty for open networks)
+    if form.wifi_ssid.is_empty() {
+        error(Field::WifiSsid, "The network name is required");
+    } else if form.wifi_ssid.len() > 32 {
+        error(Field::WifiSsid, "The network name is longer than 32 bytes");
+    }
+    let psk_len = form.wifi_psk.chars().count();
+    if psk_len > 0 && !(8..=63).contains(&psk_len) {
+        error(Field::WifiPsk, "The password must have 8 to 63 characters");
+    } else if !form.wifi_psk.is_ascii() {
+        error(
+            Field::WifiPsk,
+            "The password must have only ascii characters",
+        );
+    }
+
+    if form.mqtt_host.is_empty() {
+        error(Field::MqttHost, "The MQTT server is required");
+    } else if !is_valid_host(&form.mqtt_host) {
+        error(
+            Field::MqttHost,
+            "The MQTT server is not a valid host name or address",
+        );
+    }
+
+    let mqtt_ca = form.mqtt_ca.trim();
+    if !mqtt_ca.is_empty() && !is_pem_certificate(mqtt_ca) {
+        error(Field::MqttCa, "The CA certificate must be in PEM format");
+    }
+
+    // certificate and key of the device go together, and need TLS
+    let (mqtt_cert, mqtt_key) = (form.mqtt_cert.trim(), form.mqtt_key.trim());
+    if !mqtt_cert.is_empty() {
+        let mut der = vec![0u8; MAX_CERTIFICATE_LEN];
+        if x509::pem_validity(mqtt_cert, &mut der).is_err() {
+            error(
+                Field::MqttCert,
+                "The certificate is not a valid certificate in PEM format",
+            );
+        } else if mqtt_ca.is_empty() {
+            error(
+                Field::MqttCert,
+                "A client certificate requires the CA certificate",
+            );
+        }
+    }
+    if mqtt_cert.is_empty() && !mqtt_key.is_empty() {
+        error(
+            Field::MqttCert,
+            "A certificate is required with the private key",
+        );
+    }
+    if !mqtt_cert.is_empty() && mqtt_key.is_empty() {
+        error(
+            Field::MqttKey,
+            "A private key is required with the certificate",
+        );
+    } else if !mqtt_key.is_empty() && !mqtt_key.contains(PEM_KEY_BEGIN) {
+        error(Field::MqttKey, "The private key must be in PEM format");
+    }
+
+    let mqtt_port = if form.mqtt_port.trim().is_empty() {
+        Some(if mqtt_ca.is_empty() {
+            DEFAULT_MQTT_PORT
+        } else {
+            DEFAULT_MQTTS_PORT
+        })
+    } else {
+        match form.mqtt_port.trim().parse::<u16>() {
+            Ok(port) if port > 0 => Some(port),
+            _ => {
+                error(Field::MqttPort, "The port must be a number from 1 to 65535");
+                None
+            }
+        }
+    };
+
+    // user and password go together
+    if form.mqtt_user.is_empty() && !form.mqtt_passwd.is_empty() {
+        error(Field::MqttUser, "A user is required with the password");
+    }
+    if !form.mqtt_user.is_empty() && form.mqtt_passwd.is_empty() {
+        error(Field::MqttPasswd, "A password is required with the user");
+    }
+
+    match mqtt_port {
+        Some(mqtt_port) if errors.is_empty() => Ok(Credentials {
+            wifi_ssid: form.wifi_ssid.clone(),
+            wifi_psk: form.wifi_psk.clone(),
+            mqtt_host: form.mqtt_host.clone(),
+            mqtt_port,
+            mqtt_user: non_empty(&form.mqtt_user),
+            mqtt_passwd: non_empty(&form.mqtt_passwd),
+            mqtt_ca: non_empty(mqtt_ca),
+            mqtt_cert: non_empty(mqtt_cert),
+            mqtt_key: non_empty(mqtt_key),
+        }),
+        _ => Err(errors),
+    }
+}
+
+/// One or more certificates in PEM format. The content is not decoded, the
+/// TLS library checks it when connecting.
+fn is_pem_certificate(pem: &str) -> bool {
+    match (pem.find(PEM_BEGIN), pem.rfind(PEM_END)) {
+        (Some(begin), Some(end)) => begin + PEM_BEGIN.len() < end,
+        _ => false,
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(String::from(value))
+    }
+}
+
+/// Networks to offer in the form: hidden networks are removed, and each SSID
+/// appears once (its strongest access point), from the strongest signal down
+pub fn strongest_networks(mut networks: Vec<Network>) -> Vec<Network> {
+    networks.retain(|network| !network.ssid.is_empty());
+    networks.sort_by_key(|network| std::cmp::Reverse(network.rssi));
+    let mut strongest: Vec<Network> = Vec::new();
+    for network in networks {
+        if !strongest.iter().any(|n| n.ssid == network.ssid) {
+            strongest.push(network);
+        }
+    }
+    strongest
+}
+
+/// JSON list of networks:
+/// [{"ssid":"name","rssi":-60,"channel":6,"auth":"wpa2"}, ...]
+pub fn networks_json(networks: &[Network]) -> String {
+    let items: Vec<String> = networks
+        .iter()
+        .map(|network| {
+            format!(
+                "{{\"ssid\":{},\"rssi\":{},\"channel\":{},\"auth\":{}}}",
+                json_string(&network.ssid),
+                network.rssi,
+                network.channel,
+                json_string(network.auth)
+            )
+        })
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+/// Quoted JSON string, with the special characters escaped
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_form() -> CredentialsForm {
+        CredentialsForm {
+            wifi_ssid: "home".into(),
+            wifi_psk: "password".into(),
+            mqtt_host: "mqtt.example.com".into(),
+            ..Default::default()
+        }
+    }
+
+    /// Fields with errors after validating the form
+    fn errors(form: &CredentialsForm) -> Vec<Field> {
+        match validate(form) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors.iter().map(|error| error.field).collect(),
+        }
+    }
+
+    #[test]
+    fn decodes_plus_and_escapes() {
+        assert_eq!(url_decode("my+home"), "my home");
+        assert_eq!(url_decode("a%20b%2Bc"), "a b+c");
+        assert_eq!(url_decode("%c3%b1and%C3%BA"), "ñandú");
+        assert_eq!(url_decode("100%25"), "100%");
+        assert_eq!(url_decode("%2b+"), "+ ");
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(url_decode("%"), "%");
+        assert_eq!(url_decode("50%"), "50%");
+        assert_eq!(url_decode("%4"), "%4");
+        assert_eq!(url_decode("%zz"), "%zz");
+        assert_eq!(url_decode("%g1"), "%g1");
+        assert_eq!(url_decode("%%41"), "%A");
+        // invalid utf-8 is replaced
+        assert_eq!(url_decode("a%ffb"), "a\u{fffd}b");
+    }
+
+    #[test]
+    fn parses_the_form() {
+        let form = parse_form(
+            "wifi_ssid=my+home&wifi_psk=p%26ss%3Dword&mqtt_host=10.0.0.2\
+             &mqtt_port=1884&unknown=1&mqtt_user&&mqtt_passwd=",
+        );
+        assert_eq!(form.wifi_ssid, "my home");
+        assert_eq!(form.wifi_psk, "p&ss=word");
+        assert_eq!(form.mqtt_host, "10.0.0.2");
+        assert_eq!(form.mqtt_port, "1884");
+        assert_eq!(form.mqtt_user, "");
+        assert_eq!(form.mqtt_passwd, "");
+        assert_eq!(form.mqtt_ca, "");
+        assert_eq!(parse_form(""), CredentialsForm::default());
+    }
+
+    #[test]
+    fn valid_credentials() {
+        let credentials = validate(&valid_form()).unwrap();
+        assert_eq!(credentials.wifi_ssid, "home");
+        assert_eq!(credentials.mqtt_port, DEFAULT_MQTT_PORT);
+        assert_eq!(credentials.mqtt_user, None);
+        assert_eq!(credentials.mqtt_ca, None);
+    }
+
+    #[test]
+    fn ssid_length() {
+        let mut form = valid_form();
+        form.wifi_ssid = "s".repeat(32);
+        assert!(errors(&form).is_empty());
+        form.wifi_ssid = "s".repeat(33);
+        assert_eq!(errors(&form), [Field::WifiSsid]);
+        form.wifi_ssid.clear();
+        assert_eq!(errors(&form), [Field::WifiSsid]);
+        // the limit is in bytes
+        form.wifi_ssid = "ñ".repeat(17);
+        assert_eq!(errors(&form), [Field::WifiSsid]);
+    }
+
+    #[test]
+    fn psk_length() {
+        let mut form = valid_form();
+        for (psk, valid) in [
+            (String::new(), true),
+            ("p".repeat(7), false),
+            ("p".repeat(8), true),
+            ("p".repeat(63), true),
+            ("p".repeat(64), false),
+            ("contraseña".into(), false),
+        ] {
+            form.wifi_psk = psk;
+            let expected: &[Field] = if valid { &[] } else { &[Field::WifiPsk] };
+            assert_eq!(errors(&form), expected, "{:?}", form.wifi_psk);
+        }
+    }
+
+    #[test]
+    fn host_validation() {
+        let mut form = valid_form();
+        let label = "a".repeat(63);
+        let long_name = [label.as_str(); 4].join(".");
+        for (host, valid) in [
+            ("mqtt", true),
+            ("mqtt.example.com", true),
+            ("my-broker.local", true),
+            ("192.168.1.10", true),
+            (label.as_str(), true),
+            (&long_name[..253], true),
+            (&long_name[..254], false),
+            ("a".repeat(64).as_str(), false),
+            ("", false),
+            ("-mqtt.example.com", false),
+            ("mqtt-.example.com", false),
+            ("mqtt..example.com", false),
+            ("mqtt.example.com.", false),
+            ("mqtt_broker", false),
+            ("mqtt://example.com", false),
+            ("example.com:1883", false),
+        ] {
+            form.mqtt_host = host.into();
+            let expected: &[Field] = if valid { &[] } else { &[Field::MqttHost] };
+            assert_eq!(errors(&form), expected, "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn port_validation() {
+        let mut form = valid_form();
+        form.mqtt_port = " 8884 ".into();
+        assert_eq!(validate(&form).unwrap().mqtt_port, 8884);
+        for port in ["0", "65536", "-1", "mqtt"] {
+            form.mqtt_port = port.into();
+            assert_eq!(errors(&form), [Field::MqttPort], "{:?}", port);
+        }
+    }
+
+    #[test]
+    fn user_and_password_together() {
+        let mut form = valid_form();
+        form.mqtt_user = "sensor".into();
+        assert_eq!(errors(&form), [Field::MqttPasswd]);
+        form.mqtt_passwd = "secret".into();
+        assert!(errors(&form).is_empty());
+        form.mqtt_user.clear();
+        assert_eq!(errors(&form), [Field::MqttUser]);
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let form = CredentialsForm {
+            mqtt_port: "x".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            errors(&form),
+            [Field::WifiSsid, Field::MqttHost, Field::MqttPort]
+        );
+    }
+}
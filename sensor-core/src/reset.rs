@@ -0,0 +1,91 @@
+//! Authorization of the remote factory reset. Every device has its own
+//! reset token, derived from a secret shared by the devices and the id of
+//! the device:
+//!
+//! token = hex(HMAC-SHA256(secret, device id))
+//!
+//! The secret is given at build time and never sent. The token of a device,
+//! seen by anyone subscribed to its reset topic, doesn't reset the others.
+//! To compute it:
+//! `printf %s "$DEVICE_ID" | openssl dgst -sha256 -hmac "$SENSOR_RESET_TOKEN"`
+
+use std::fmt::Write;
+
+/// Reset token of the device, in lowercase hexadecimal
+pub fn device_token(secret: &str, device_id: &str) -> String {
+    let mac = hmac_sha256::HMAC::mac(device_id, secret);
+    mac.iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// The payload of a remote reset is the token of the device. Without a
+/// secret the remote reset is disabled.
+pub fn is_authorized(secret: Option<&str>, device_id: &str, payload: &[u8]) -> bool {
+    match secret {
+        Some(secret) if !secret.is_empty() => {
+            constant_time_eq(device_token(secret, device_id).as_bytes(), payload)
+        }
+        _ => false,
+    }
+}
+
+/// Comparison that takes the same time wherever the first difference is
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            device_token("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn token_of_each_device() {
+        let token = device_token("fleet secret", "sensor-a1b2c3");
+        assert!(is_authorized(
+            Some("fleet secret"),
+            "sensor-a1b2c3",
+            token.as_bytes()
+        ));
+        // neither for other devices, nor with another secret
+        assert!(!is_authorized(
+            Some("fleet secret"),
+            "sensor-d4e5f6",
+            token.as_bytes()
+        ));
+        assert!(!is_authorized(
+            Some("other secret"),
+            "sensor-a1b2c3",
+            token.as_bytes()
+        ));
+        // not the secret itself
+        assert!(!is_authorized(
+            Some("fleet secret"),
+            "sensor-a1b2c3",
+            b"fleet secret"
+        ));
+        assert!(!is_authorized(
+            Some("fleet secret"),
+            "sensor-a1b2c3",
+            &token.as_bytes()[..63]
+        ));
+    }
+
+    #[test]
+    fn disabled_without_secret() {
+        let token = device_token("", "sensor-a1b2c3");
+        assert!(!is_authorized(None, "sensor-a1b2c3", b""));
+        assert!(!is_authorized(Some(""), "sensor-a1b2c3", b""));
+        assert!(!is_authorized(Some(""), "sensor-a1b2c3", token.as_bytes()));
+    }
+}
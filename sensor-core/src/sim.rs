@@ -0,0 +1,310 @@
+//! Fakes of the services of the device, to run the state machine in the
+//! host: in the simulator (`sensor-sim`) and in the tests. The wifi, MQTT
+//! and web server print what they do, the storage is in memory and the time
+//! only advances when the timed event pending is fired.
+
+use crate::config::{DeviceConfig, MemoryStorage};
+use crate::humidity::add_derived;
+use crate::machine::{Fsm, Http, Mqtt, System, Wifi};
+use crate::provisioning::{Network, ProvisioningStatus};
+use crate::transitions::{Event, State};
+use anyhow::{anyhow, bail, Result};
+use common::buffer::Stamped;
+use common::sensor::{Measurement, Measurements, Quantity};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+pub type SimFsm = Fsm<SimWifi, MemoryStorage, SimMqtt, SimHttp, SimSystem>;
+
+pub struct SimWifi {
+    pub connected: bool,
+    // result of the next connections
+    pub works: bool,
+}
+
+impl Wifi for SimWifi {
+    fn start_ap(&mut self) -> Result<()> {
+        self.connected = false;
+        println!("  wifi: access point started");
+        Ok(())
+    }
+
+    fn stop_ap(&mut self) -> Result<()> {
+        println!("  wifi: access point stopped");
+        Ok(())
+    }
+
+    fn connect(&mut self, ssid: &str, _psk: &str, keep_ap: bool) -> Result<()> {
+        if !self.works {
+            bail!("no network {}", ssid);
+        }
+        self.connected = true;
+        if keep_ap {
+            println!("  wifi: connected to {}, access point active", ssid);
+        } else {
+            println!("  wifi: connected to {}", ssid);
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn scan(&mut self) -> Result<Vec<Network>> {
+        Ok(vec![Network {
+            ssid: String::from("simulated"),
+            rssi: -50,
+            channel: 6,
+            auth: "wpa2",
+        }])
+    }
+}
+
+pub struct SimMqtt {
+    started: bool,
+    // result of the next publications
+    pub works: bool,
+    // times the device has announced itself: the connections
+    pub announced: u32,
+}
+
+impl SimMqtt {
+    fn publish(&self, what: &str) -> Result<()> {
+        if !self.started || !self.works {
+            bail!("not connected");
+        }
+        println!("  mqtt: published {}", what);
+        Ok(())
+    }
+}
+
+impl Mqtt for SimMqtt {
+    fn start(&mut self, config: &DeviceConfig, device_id: &str) -> Result<()> {
+        self.started = true;
+        println!(
+            "  mqtt: client {} started for {}:{}",
+            device_id, config.mqtt_host, config.mqtt_port
+        );
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.started = false;
+    }
+
+    fn is_started(&self) -> bool {
+        self.started
+    }
+
+    fn announce(&mut self) -> Result<()> {
+        self.publish("status online")?;
+        self.announced += 1;
+        Ok(())
+    }
+
+    fn send_measurement(&mut self, measurement: &Measurement) -> Result<()> {
+        self.publish(&format!(
+            "{} {}",
+            measurement.quantity.name(),
+            measurement.value
+        ))
+    }
+
+    fn send_reading(&mut self, reading: &Stamped<f32>) -> Result<()> {
+        self.publish(&format!(
+            "reading {} at {}",
+            reading.value, reading.timestamp_millis
+        ))
+    }
+
+    fn send_certificate_status(&mut self, status: &str) -> Result<()> {
+        self.publish(&format!("certificate {}", status))
+    }
+}
+
+pub struct SimHttp {
+    status: Option<Arc<Mutex<ProvisioningStatus>>>,
+}
+
+impl Http for SimHttp {
+    fn start(&mut self, status: &Arc<Mutex<ProvisioningStatus>>) -> Result<()> {
+        self.status = Some(status.clone());
+        println!("  http: portal started");
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(status) = self.status.take() {
+            println!(
+                "  http: portal stopped, status {}",
+                status.lock().unwrap().to_json()
+            );
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.status.is_some()
+    }
+}
+
+pub struct SimSystem {
+    // simulated time, it only advances when the timer fires
+    pub now_millis: u64,
+    pending: Option<(Duration, Event)>,
+    rebooting: bool,
+    seed: u32,
+}
+
+impl System for SimSystem {
+    fn send_after(&mut self, delay: Duration, event: Event) {
+        println!("  timer: {:?} in {:?}", event, delay);
+        self.pending = Some((delay, event));
+    }
+
+    fn cancel_timer(&mut self) {
+        self.pending = None;
+    }
+
+    fn reboot(&mut self) {
+        self.rebooting = true;
+    }
+
+    fn random(&mut self) -> u32 {
+        // xorshift, so the runs are reproducible
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+}
+
+/// Simulated device: the machine and the events it sends to itself
+pub struct Device {
+    pub fsm: SimFsm,
+    tx: mpsc::Sender<Event>,
+    rx: mpsc::Receiver<Event>,
+    // states entered, across reboots
+    states: Vec<State>,
+}
+
+impl Device {
+    /// Boots the device with the storage of a previous run
+    pub fn boot(storage: MemoryStorage, now_millis: u64) -> Self {
+        println!("boot");
+        let (tx, rx) = mpsc::channel();
+        let fsm = Fsm::new(
+            tx.clone(),
+            SimWifi {
+                connected: false,
+                works: true,
+            },
+            storage,
+            SimMqtt {
+                started: false,
+                works: true,
+                announced: 0,
+            },
+            SimHttp { status: None },
+            SimSystem {
+                now_millis,
+                pending: None,
+                rebooting: false,
+                seed: 0x2545_f491,
+            },
+            String::from("5151a5b0c0de"),
+        );
+        let states = vec![fsm.state];
+        let mut device = Self {
+            fsm,
+            tx,
+            rx,
+            states,
+        };
+        device.run();
+        device
+    }
+
+    /// Processes the event and the ones it generates
+    pub fn send(&mut self, event: Event) {
+        self.tx.send(event).unwrap();
+        self.run();
+    }
+
+    /// Fires the timed event pending, advancing the time to it
+    pub fn fire_timer(&mut self) -> Result<()> {
+        let (delay, event) = self
+            .fsm
+            .system
+            .pending
+            .take()
+            .ok_or_else(|| anyhow!("no timer pending"))?;
+        self.fsm.system.now_millis += delay.as_millis() as u64;
+        self.send(event);
+        Ok(())
+    }
+
+    /// States entered since the last call, Initial after each boot
+    pub fn take_states(&mut self) -> Vec<State> {
+        std::mem::take(&mut self.states)
+    }
+
+    fn run(&mut self) {
+        while let Ok(event) = self.rx.try_recv() {
+            let before = self.fsm.state;
+            let kind = event.kind();
+            self.fsm.process_event(event);
+            if self.fsm.state != before {
+                println!("{:?} --{:?}--> {:?}", before, kind, self.fsm.state);
+                self.states.push(self.fsm.state);
+            }
+            if self.fsm.system.rebooting {
+                return self.reboot();
+            }
+        }
+    }
+
+    fn reboot(&mut self) {
+        // only the storage survives
+        let now_millis = self.fsm.system.now_millis;
+        let storage = std::mem::take(&mut self.fsm.storage);
+        let mut states = std::mem::take(&mut self.states);
+        *self = Self::boot(storage, now_millis);
+        states.append(&mut self.states);
+        self.states = states;
+    }
+}
+
+/// Credentials from the provisioning portal, without user nor TLS
+pub fn credentials(ssid: &str, psk: &str, host: &str, port: u16) -> Event {
+    Event::Credentials {
+        wifi_ssid: ssid.to_string(),
+        wifi_psk: psk.to_string(),
+        mqtt_host: host.to_string(),
+        mqtt_port: port,
+        mqtt_user: None,
+        mqtt_passwd: None,
+        mqtt_ca: None,
+        mqtt_cert: None,
+        mqtt_key: None,
+    }
+}
+
+/// Reading of the sensor, with the values derived from it
+pub fn reading(timestamp_millis: u64, temperature: f32, rel_humidity: Option<f32>) -> Event {
+    let mut measurements = Measurements::new();
+    let mut add = |quantity, value| {
+        let measurement = Measurement::new("sim", quantity, value, timestamp_millis);
+        measurements.push(measurement).ok();
+    };
+    add(Quantity::Temperature, temperature);
+    if let Some(rel_humidity) = rel_humidity {
+        add(Quantity::RelativeHumidity, rel_humidity);
+    }
+    add_derived(&mut measurements);
+    Event::Measurements(measurements)
+}
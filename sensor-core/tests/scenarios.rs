@@ -0,0 +1,186 @@
+//! Scenarios of the sensor run with the fakes of the simulator, checking
+//! the states the machine goes through.
+
+use sensor_core::config::{load_config, MemoryStorage, Storage};
+use sensor_core::machine::Http;
+use sensor_core::sim::{credentials, reading, Device};
+use sensor_core::transitions::Event;
+use sensor_core::transitions::State::*;
+use std::sync::mpsc;
+
+/// Fires the timed event pending, that must be there
+fn fire_timer(device: &mut Device) {
+    device.fire_timer().expect("no timer pending");
+}
+
+/// First boot: no configuration, provisioning through the portal
+#[test]
+fn provisioning() {
+    let mut device = Device::boot(MemoryStorage::default(), 0);
+    assert_eq!(device.take_states(), [Initial, Provisioning]);
+
+    let (reply_tx, reply_rx) = mpsc::channel();
+    device.send(Event::ScanNetworks(reply_tx));
+    assert_eq!(reply_rx.try_recv().unwrap().len(), 1);
+
+    // wrong credentials: back to wait for others
+    device.fsm.wifi.works = false;
+    device.send(credentials("home", "wrongpsk", "broker.local", 1883));
+    assert_eq!(device.take_states(), [Provisioned, Provisioning]);
+
+    // the portal shows the result for a while, then it is stopped
+    device.fsm.wifi.works = true;
+    device.send(credentials("home", "secret", "broker.local", 1883));
+    assert_eq!(device.take_states(), [Provisioned]);
+    assert!(device.fsm.http.is_running());
+    fire_timer(&mut device);
+    assert_eq!(device.take_states(), [WifiConnected]);
+    assert!(!device.fsm.http.is_running());
+
+    device.send(Event::MqttConnected);
+    device.send(reading(0, 21.5, Some(48.0)));
+    assert_eq!(device.take_states(), [ServerConnected]);
+
+    // the configuration survives a reboot
+    device.send(Event::Reboot);
+    assert_eq!(device.take_states(), [Initial, Provisioned, WifiConnected]);
+    device.send(Event::MqttConnected);
+    // the status of the certificate of the device can be queried
+    device.send(Event::RemoteCommand {
+        command: String::from("certificate"),
+    });
+    assert_eq!(device.take_states(), [ServerConnected]);
+}
+
+/// Connection lost: retries with backoff, and after too many failures back
+/// to provisioning, rebooting later to try again
+#[test]
+fn retry() {
+    let mut device = Device::boot(MemoryStorage::default(), 0);
+    device.send(credentials("home", "secret", "broker.local", 8883));
+    fire_timer(&mut device);
+    device.send(Event::MqttConnected);
+    assert_eq!(
+        device.take_states(),
+        [
+            Initial,
+            Provisioning,
+            Provisioned,
+            WifiConnected,
+            ServerConnected
+        ]
+    );
+
+    assert_eq!(device.fsm.mqtt.announced, 1);
+
+    // the client reconnects by itself: the device is announced again
+    device.send(Event::MqttDisconnected);
+    device.send(Event::MqttConnected);
+    assert_eq!(device.take_states(), [WifiConnected, ServerConnected]);
+    assert_eq!(device.fsm.mqtt.announced, 2);
+
+    // the MQTT client doesn't reconnect in time
+    device.send(Event::MqttDisconnected);
+    device.send(reading(0, 20.0, None));
+    fire_timer(&mut device);
+    assert_eq!(device.take_states(), [WifiConnected, Failure]);
+    device.send(reading(1, 20.5, None));
+    fire_timer(&mut device);
+    device.send(Event::MqttConnected);
+    assert_eq!(
+        device.take_states(),
+        [Provisioned, WifiConnected, ServerConnected]
+    );
+
+    // the wifi network is gone: every retry fails
+    device.fsm.wifi.works = false;
+    device.fsm.wifi.connected = false;
+    device.send(Event::WifiDisconnected);
+    assert_eq!(device.take_states(), [Failure]);
+    for _ in 0..5 {
+        fire_timer(&mut device);
+        assert_eq!(device.take_states(), [Provisioned, Failure]);
+    }
+    fire_timer(&mut device);
+    assert_eq!(device.take_states(), [Provisioned, Failure, Provisioning]);
+
+    // the credentials are still stored: tried again after a reboot
+    device.fsm.wifi.works = true;
+    fire_timer(&mut device);
+    assert_eq!(device.take_states(), [Initial, Provisioned, WifiConnected]);
+}
+
+/// Factory reset erases the configuration: back to provisioning after reboot
+#[test]
+fn factory_reset() {
+    let mut device = Device::boot(MemoryStorage::default(), 0);
+    device.send(credentials("home", "secret", "broker.local", 1883));
+    fire_timer(&mut device);
+    device.send(Event::MqttConnected);
+    assert_eq!(
+        device.take_states(),
+        [
+            Initial,
+            Provisioning,
+            Provisioned,
+            WifiConnected,
+            ServerConnected
+        ]
+    );
+    assert!(load_config(&mut device.fsm.storage).is_provisioned());
+
+    device.send(Event::FactoryReset);
+    assert_eq!(device.take_states(), [Initial, Provisioning]);
+    assert!(!load_config(&mut device.fsm.storage).is_provisioned());
+}
+
+/// The wifi drops while the portal shows the result of the provisioning
+#[test]
+fn disconnected_before_closing_portal() {
+    let mut device = Device::boot(MemoryStorage::default(), 0);
+    device.send(credentials("home", "secret", "broker.local", 1883));
+    device.fsm.wifi.connected = false;
+    device.send(Event::WifiDisconnected);
+    assert_eq!(
+        device.take_states(),
+        [Initial, Provisioning, Provisioned, Failure]
+    );
+    // the retry is with the portal still running, the credentials stored
+    assert!(load_config(&mut device.fsm.storage).is_provisioned());
+    fire_timer(&mut device);
+    assert_eq!(device.take_states(), [Provisioned]);
+    fire_timer(&mut device);
+    assert_eq!(device.take_states(), [WifiConnected]);
+    assert!(!device.fsm.http.is_running());
+}
+
+/// The readings taken while disconnected are stored in batches, and before
+/// a reboot, so they survive it
+#[test]
+fn readings_stored_in_batches() {
+    let stored = |device: &Device| {
+        let bytes = device.fsm.storage.read("readings").unwrap();
+        bytes.map_or(0, |bytes| bytes.len() / 12)
+    };
+    let mut device = Device::boot(MemoryStorage::default(), 0);
+    for ts in 0..9 {
+        device.send(reading(ts, 20.0, None));
+    }
+    assert_eq!(stored(&device), 0);
+    device.send(reading(9, 20.0, None));
+    assert_eq!(stored(&device), 10);
+    for ts in 10..13 {
+        device.send(reading(ts, 20.0, None));
+    }
+    assert_eq!(stored(&device), 10);
+    device.send(Event::Reboot);
+    assert_eq!(stored(&device), 13);
+
+    // sent once connected, then no longer stored
+    device.send(credentials("home", "secret", "broker.local", 1883));
+    fire_timer(&mut device);
+    device.send(Event::MqttConnected);
+    device.send(reading(13, 20.5, None));
+    assert_eq!(device.take_states().last(), Some(&ServerConnected));
+    assert_eq!(stored(&device), 0);
+}
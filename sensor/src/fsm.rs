@@ -1,252 +1,84 @@
-use crate::mqtt::{send_temperature, start_mqtt_client};
-use crate::wifi::{wifi_ap_start, wifi_sta_start};
-use anyhow::Result;
-use embedded_svc::storage::RawStorage;
-use esp_idf_svc::{eventloop::EspSystemEventLoop, wifi::EspWifi};
-use esp_idf_svc::{http::server::EspHttpServer, mqtt::client::EspMqttClient, nvs::EspDefaultNvs};
-use log::{error, info, warn};
-use std::str;
+//! State machine of the sensor on the esp-idf services. The machine itself
+//! is in sensor-core; here are the system services of the device and the
+//! construction of the machine with the wifi, NVS, MQTT and web server.
+
+use crate::http::DeviceHttp;
+use crate::mqtt::DeviceMqtt;
+use crate::storage::NvsStorage;
+use crate::wifi::DeviceWifi;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use esp_idf_svc::timer::{EspTimer, EspTimerService};
+use esp_idf_svc::wifi::EspWifi;
+use log::error;
+use sensor_core::machine::{self, System};
 use std::sync::mpsc;
+use std::time::Duration;
 
-///
-/// Finite state machine states
-#[derive(Debug, PartialEq)]
-pub enum State {
-    Initial,
-    Provisioned {
-        wifi_ssid: String,
-        wifi_psk: String,
-        mqtt_host: String,
-        mqtt_user: Option<String>,
-        mqtt_passwd: Option<String>,
-    },
-    WifiConnected,
-    ServerConnected,
-    Failure,
-}
+pub use sensor_core::transitions::{Event, State};
 
-/// Events for the finite state machine. To be passed between threads
-#[derive(Debug, Clone)]
-pub enum Event {
-    Credentials {
-        wifi_ssid: String,
-        wifi_psk: String,
-        mqtt_host: String,
-        mqtt_user: Option<String>,
-        mqtt_passwd: Option<String>,
-    },
-    WifiConnected,
-    WifiDisconnected,
-    MqttConnected,
-    MqttDisconnected,
-    SensorData(f32),
-    RemoteCommand {
-        command: String,
-    },
-}
+pub type Fsm<'a> = machine::Fsm<DeviceWifi<'a>, NvsStorage, DeviceMqtt, DeviceHttp, DeviceSystem>;
 
-impl State {
-    /// Manage the changes from one state to another
-    fn next(&mut self, event: Event) -> Option<State> {
-        // println!("next, state {:?}, event {:?}", self, event);
-        match (self, event) {
-            (
-                State::Initial,
-                Event::Credentials {
-                    wifi_ssid,
-                    wifi_psk,
-                    mqtt_host,
-                    mqtt_user,
-                    mqtt_passwd,
-                },
-            ) => {
-                // println!("ssid={}, user={}, password={}", ssid, user, password);
-                info!("Recibido evento de provisionamiento");
-                Some(State::Provisioned {
-                    wifi_ssid,
-                    wifi_psk,
-                    mqtt_host,
-                    mqtt_user,
-                    mqtt_passwd,
-                })
-            }
-            (State::Provisioned { .. }, Event::WifiConnected) => Some(State::WifiConnected),
-            (State::WifiConnected, Event::MqttConnected) => Some(State::ServerConnected),
-            (State::WifiConnected, Event::RemoteCommand { command }) => {
-                info!("Remote command received {}", command);
-                Some(State::WifiConnected)
-            }
-            (_s, _e) => {
-                // error!("State {:#?}, event {:#?} not expected.", s, e);
-                None
-            }
-        }
-    }
+/// Creates the state machine of the device, in the Initial state
+pub fn new_fsm<'a>(
+    tx: mpsc::Sender<Event>,
+    sysloop: EspSystemEventLoop,
+    wifi: Box<EspWifi<'a>>,
+    nvs: EspDefaultNvs,
+) -> Fsm<'a> {
+    let wifi =
+        DeviceWifi::new(wifi, sysloop, tx.clone()).expect("Error subscribing to wifi events");
+    let device_id = wifi.mac().unwrap_or_else(|_| String::from("sensor"));
+    machine::Fsm::new(
+        tx.clone(),
+        wifi,
+        NvsStorage::new(nvs),
+        DeviceMqtt::new(tx.clone()),
+        DeviceHttp::new(tx.clone()),
+        DeviceSystem::new(tx),
+        device_id,
+    )
 }
 
-pub struct Fsm<'a> {
-    pub state: State,
-    pub tx: mpsc::Sender<Event>,
-    pub sysloop: EspSystemEventLoop,
-    pub wifi: Box<EspWifi<'a>>,
-    pub nvs: EspDefaultNvs,
-    pub httpserver: Option<EspHttpServer>,
-    pub mqttc: Option<EspMqttClient>,
-    mqtt_host: Option<String>,
-    mqtt_user: Option<String>,
-    mqtt_passwd: Option<String>,
+/// System services of the device
+pub struct DeviceSystem {
+    tx: mpsc::Sender<Event>,
+    // Event to send after a time, while in the current state
+    timer: Option<EspTimer>,
 }
 
-impl<'a> Fsm<'a> {
-    pub fn new(
-        tx: mpsc::Sender<Event>,
-        sysloop: EspSystemEventLoop,
-        wifi: Box<EspWifi<'a>>,
-        nvs: EspDefaultNvs,
-    ) -> Self {
-        let mut fsm = Self {
-            state: State::Initial,
-            tx,
-            sysloop,
-            wifi,
-            nvs,
-            httpserver: None,
-            mqttc: None,
-            mqtt_host: None,
-            mqtt_user: None,
-            mqtt_passwd: None,
-        };
-        fsm.enter_state();
-        fsm
+impl DeviceSystem {
+    pub fn new(tx: mpsc::Sender<Event>) -> Self {
+        Self { tx, timer: None }
     }
+}
 
-    pub fn process_event(&mut self, event: Event) {
-        // handle events that keep the machine in current state
-        self.handle_event(&event);
-        // handle events that make the machine's state to change
-        if let Some(newstate) = self.state.next(event) {
-            self.state = newstate;
-            self.enter_state();
+impl System for DeviceSystem {
+    /// Sends the event after the delay, unless the state changes before
+    fn send_after(&mut self, delay: Duration, event: Event) {
+        let tx = self.tx.clone();
+        let timer = EspTimerService::new()
+            .and_then(|service| {
+                service.timer(move || {
+                    tx.send(event.clone()).ok();
+                })
+            })
+            .and_then(|timer| timer.after(delay).map(|_| timer));
+        match timer {
+            Ok(timer) => self.timer = Some(timer),
+            Err(err) => error!("Error starting timer: {}", err),
         }
     }
 
-    /// It handles the events that keep the machine in the same state
-    fn handle_event(&mut self, event: &Event) {
-        match (&self.state, event) {
-            (State::ServerConnected { .. }, Event::SensorData(data)) => {
-                info!("Sending temperature sensor data to MQTT: {} °C", data);
-                let mqttc = self.mqttc.as_mut().unwrap();
-                send_temperature(mqttc, *data);
-            }
-            (_s, _e) => {}
-        }
+    fn cancel_timer(&mut self) {
+        self.timer = None;
     }
 
-    /// It runs the acctions needed when the machine enters a new state
-    fn enter_state(&mut self) {
-        info!("******** Entering state {:?}", self.state);
-        match &self.state {
-            State::Initial => {
-                let wifi_ssid = read_nvs_string(&mut self.nvs, "wifi_ssid").unwrap();
-                let wifi_psk = read_nvs_string(&mut self.nvs, "wifi_psk").unwrap();
-                let mqtt_host = read_nvs_string(&mut self.nvs, "mqtt_host").unwrap();
-                let mqtt_user = read_nvs_string(&mut self.nvs, "mqtt_user").unwrap();
-                let mqtt_passwd = read_nvs_string(&mut self.nvs, "mqtt_passwd").unwrap();
-                if let (Some(wifi_ssid), Some(wifi_psk), Some(mqtt_host)) =
-                    (wifi_ssid, wifi_psk, mqtt_host)
-                {
-                    info!(
-                        "Credentials from NVS: ssid = {}, psk = {}, mqtt = {},{:?},{:?}",
-                        wifi_ssid, wifi_psk, mqtt_host, mqtt_user, mqtt_passwd
-                    );
-                    // provisioned: generate event to change state
-                    let event = Event::Credentials {
-                        wifi_ssid,
-                        wifi_psk,
-                        mqtt_host,
-                        mqtt_user,
-                        mqtt_passwd,
-                    };
-                    self.tx.send(event).unwrap();
-                } else {
-                    info!("Credentials not found in NVS.");
-                    info!("Activating wifi AP.");
-                    wifi_ap_start(&mut self.wifi, &self.sysloop).expect("Error activating AP");
-                    info!("Activating HTTP server");
-                    self.httpserver = Some(crate::http::start_http_server(&self.tx));
-                }
-            }
-            State::Provisioned {
-                wifi_ssid,
-                wifi_psk,
-                mqtt_host,
-                mqtt_user,
-                mqtt_passwd,
-            } => {
-                info!("Trying to connect to wifi station.");
-                info!("Using credentials {wifi_ssid}, {wifi_psk}.");
-                // stop http server if running
-                if self.httpserver.is_some() {
-                    info!("Deactivating HTTP server");
-                    self.httpserver = None
-                }
-                // store mqtt credentials in Fsm (wifi credentials not stored)
-                self.mqtt_host = Some(mqtt_host.clone());
-                self.mqtt_user = mqtt_user.clone();
-                self.mqtt_passwd = mqtt_passwd.clone();
-                // store credentials permanently in NVS
-                self.nvs.set_raw("wifi_ssid", wifi_ssid.as_bytes()).unwrap();
-                self.nvs.set_raw("wifi_psk", wifi_psk.as_bytes()).unwrap();
-                self.nvs.set_raw("mqtt_host", mqtt_host.as_bytes()).unwrap();
-                if let (Some(mqtt_user), Some(mqtt_passwd)) = (mqtt_user, mqtt_passwd) {
-                    self.nvs.set_raw("mqtt_user", mqtt_user.as_bytes()).unwrap();
-                    self.nvs
-                        .set_raw("mqtt_passwd", mqtt_passwd.as_bytes())
-                        .unwrap();
-                }
-                // connect to wifi using the credentials
-                wifi_sta_start(&mut self.wifi, &self.sysloop, wifi_ssid, wifi_psk)
-                    .expect("Error activating STA");
-                self.tx.send(Event::WifiConnected).unwrap();
-            }
-            State::WifiConnected => {
-                info!("State WifiConnected.");
-                let res = start_mqtt_client(
-                    self.tx.clone(),
-                    self.mqtt_host.as_deref().unwrap(),
-                    self.mqtt_user.as_deref(),
-                    self.mqtt_passwd.as_deref(),
-                );
-                match res {
-                    Ok(mqttc) => {
-                        self.mqttc = Some(mqttc);
-                        info!("Connected to MQTT server.");
-                    }
-                    Err(err) => {
-                        error!("Error connecting to MQTT server: {}", err);
-                    }
-                }
-                self.tx.send(Event::MqttConnected).unwrap();
-            }
-            State::ServerConnected => {
-                info!("State ServerConnected. Start sending periodic data.");
-            }
-            State::Failure => {
-                error!("Current state is Failure");
-            }
-        }
+    fn reboot(&mut self) {
+        esp_idf_hal::reset::restart();
     }
-}
 
-fn read_nvs_string(nvs: &mut EspDefaultNvs, key: &str) -> Result<Option<String>, anyhow::Error> {
-    if nvs.contains(key).unwrap() {
-        let len = nvs.len(key).unwrap().unwrap();
-        let mut buf: [u8; 100] = [0; 100];
-        nvs.get_raw(key, &mut buf)?;
-        let value = String::from(str::from_utf8(&buf[0..len])?);
-        Ok(Some(value))
-    } else {
-        warn!("Key {key} not found in NVS");
-        Ok(None)
+    fn random(&mut self) -> u32 {
+        unsafe { esp_idf_sys::esp_random() }
     }
 }
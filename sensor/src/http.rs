@@ -1,30 +1,144 @@
-
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 use log::*;
 use esp_idf_svc::http::server::{Configuration, EspHttpServer};
-use embedded_svc::{http::Method, io::Write};
-use crate::fsm::Event;
+use embedded_svc::{
+    http::Method,
+    io::{Read, Write},
+};
+use sensor_core::machine::Http;
+use sensor_core::provisioning::{
+    networks_json, parse_form, validate, CredentialsForm, Field, FieldError, ProvisioningStatus,
+};
+use sensor_core::transitions::Event;
+
+/// Maximum size of the body of the provisioning form. It has room for the
+/// CA certificate, the device certificate and its key, urlencoded.
+const MAX_FORM_LEN: usize = 16384;
+
+/// Time to wait for the result of a wifi scan
+const SCAN_TIMEOUT: Duration = Duration::from_secs(15);
 
-pub fn start_http_server(tx: &mpsc::Sender<Event>) -> EspHttpServer {
+/// Fields of the provisioning form: field, label and input type
+const FORM_FIELDS: [(Field, &str, &str); 9] = [
+    (Field::WifiSsid, "Wifi network (SSID)", "text"),
+    (Field::WifiPsk, "Wifi password", "password"),
+    (Field::MqttHost, "MQTT server", "text"),
+    (Field::MqttPort, "MQTT port", "number"),
+    (Field::MqttUser, "MQTT user (optional)", "text"),
+    (Field::MqttPasswd, "MQTT password (optional)", "password"),
+    (Field::MqttCa, "MQTT server CA certificate, PEM (optional, enables TLS)", "textarea"),
+    (Field::MqttCert, "Device certificate, PEM (optional, requires the CA)", "textarea"),
+    (Field::MqttKey, "Device private key, PEM (with the certificate)", "textarea"),
+];
+
+/// Starts the provisioning web server:
+///   - GET / shows the form to enter the credentials
+///   - POST / validates them. If they are correct they are sent to the Fsm,
+///     otherwise the form is shown again with the errors.
+///   - GET /scan returns the wifi networks around as JSON
+///   - GET /status returns the result of the connection with the credentials
+pub fn start_http_server(
+    tx: &mpsc::Sender<Event>,
+    status: &Arc<Mutex<ProvisioningStatus>>,
+) -> EspHttpServer {
     let mut server = EspHttpServer::new(&Configuration::default()).unwrap();
-    let tx1 = tx.clone();
     server
-        .fn_handler("/", Method::Get, move |request| {
+        .fn_handler("/", Method::Get, |request| {
             info!("http server: recibido request /");
-            let html = index_html();
+            let html = index_html(&CredentialsForm::default(), &[]);
             let mut response = request.into_ok_response()?;
             response.write_all(html.as_bytes())?;
+            Ok(())
+        })
+        .unwrap();
+
+    let tx1 = tx.clone();
+    server
+        .fn_handler("/scan", Method::Get, move |request| {
+            info!("http server: recibido request /scan");
+            // the Fsm owns the wifi, it does the scan
+            let (reply_tx, reply_rx) = mpsc::channel();
+            if tx1.send(Event::ScanNetworks(reply_tx)).is_err() {
+                warn!("http server: the Fsm is not running, no scan");
+                request.into_status_response(503)?;
+                return Ok(());
+            }
+            let networks = reply_rx.recv_timeout(SCAN_TIMEOUT).unwrap_or_default();
+            let json = networks_json(&networks);
+            let mut response =
+                request.into_response(200, None, &[("Content-Type", "application/json")])?;
+            response.write_all(json.as_bytes())?;
+            Ok(())
+        })
+        .unwrap();
+
+    let status1 = status.clone();
+    server
+        .fn_handler("/status", Method::Get, move |request| {
+            let json = status1.lock().unwrap().to_json();
+            let mut response =
+                request.into_response(200, None, &[("Content-Type", "application/json")])?;
+            response.write_all(json.as_bytes())?;
+            Ok(())
+        })
+        .unwrap();
 
-            // The real HTML form should be parsed here
-            let event = Event::Credentials {
-                wifi_ssid: "myssid".into(),
-                wifi_psk: "mypassword".into(),
-                mqtt_host: "test.mosquitto.org".into(),
-                mqtt_user: None,
-                mqtt_passwd: None,
-            };
-            tx1.send(event).unwrap();
+    let tx1 = tx.clone();
+    let status1 = status.clone();
+    server
+        .fn_handler("/", Method::Post, move |mut request| {
+            info!("http server: recibido POST /");
+            // in the heap, too big for the stack of the server task. One
+            // byte more to know if the body is longer.
+            let mut body = vec![0u8; MAX_FORM_LEN + 1];
+            let mut len = 0;
+            while len < body.len() {
+                let read = request.read(&mut body[len..])?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+            if len > MAX_FORM_LEN {
+                // cut, a certificate or a password would be stored corrupted
+                warn!("http server: form longer than {} bytes", MAX_FORM_LEN);
+                request.into_status_response(413)?;
+                return Ok(());
+            }
+            let form = parse_form(&String::from_utf8_lossy(&body[..len]));
 
+            match validate(&form) {
+                Ok(credentials) => {
+                    *status1.lock().unwrap() = ProvisioningStatus::Connecting;
+                    let html = templated(format!(
+                        "<p>Connecting to {}...</p>\n{}",
+                        html_escape(&credentials.wifi_ssid),
+                        STATUS_SCRIPT
+                    ));
+                    let mut response = request.into_ok_response()?;
+                    response.write_all(html.as_bytes())?;
+
+                    let event = Event::Credentials {
+                        wifi_ssid: credentials.wifi_ssid,
+                        wifi_psk: credentials.wifi_psk,
+                        mqtt_host: credentials.mqtt_host,
+                        mqtt_port: credentials.mqtt_port,
+                        mqtt_user: credentials.mqtt_user,
+                        mqtt_passwd: credentials.mqtt_passwd,
+                        mqtt_ca: credentials.mqtt_ca,
+                        mqtt_cert: credentials.mqtt_cert,
+                        mqtt_key: credentials.mqtt_key,
+                    };
+                    tx1.send(event).unwrap();
+                }
+                Err(errors) => {
+                    warn!("http server: invalid credentials {:?}", errors);
+                    let html = index_html(&form, &errors);
+                    let mut response = request.into_status_response(400)?;
+                    response.write_all(html.as_bytes())?;
+                }
+            }
             Ok(())
         })
         .unwrap();
@@ -39,7 +153,9 @@ fn templated(content: impl AsRef<str>) -> String {
 <html>
     <head>
         <meta charset="utf-8">
+        <meta name="viewport" content="width=device-width, initial-scale=1">
         <title>esp-rs web server</title>
+        <style>.error {{ color: red; }}</style>
     </head>
     <body>
         {}
@@ -50,6 +166,126 @@ fn templated(content: impl AsRef<str>) -> String {
     )
 }
 
-fn index_html() -> String {
-    templated("Hello from ESP32-C3!")
+/// Provisioning form, with the values entered and the errors found in them
+fn index_html(form: &CredentialsForm, errors: &[FieldError]) -> String {
+    let mut content = String::from("<h1>Sensor provisioning</h1>\n<form method=\"post\" action=\"/\">\n");
+    for (field, label, input_type) in FORM_FIELDS {
+        // the SSID can be picked from the networks found by the scan
+        let list = if field == Field::WifiSsid {
+            " list=\"networks\""
+        } else {
+            ""
+        };
+        let input = if input_type == "textarea" {
+            format!(
+                "<textarea id=\"{name}\" name=\"{name}\" rows=\"8\" cols=\"64\">{value}</textarea>",
+                name = field.name(),
+                value = html_escape(form.value(field)),
+            )
+        } else {
+            format!(
+                "<input type=\"{input_type}\" id=\"{name}\" name=\"{name}\" value=\"{value}\"{list}>",
+                name = field.name(),
+                value = html_escape(form.value(field)),
+            )
+        };
+        content += &format!(
+            "<p><label for=\"{name}\">{label}</label><br>{input}",
+            name = field.name(),
+        );
+        for error in errors.iter().filter(|error| error.field == field) {
+            content += &format!("<br><span class=\"error\">{}</span>", error.message);
+        }
+        content += "</p>\n";
+    }
+    content += "<input type=\"submit\" value=\"Save\">\n</form>\n";
+    content += SCAN_SCRIPT;
+    templated(content)
+}
+
+/// Escapes a value to be included in an HTML attribute
+fn html_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Fills the list of networks of the SSID field with the result of /scan
+const SCAN_SCRIPT: &str = r#"<datalist id="networks"></datalist>
+<p id="scan">Scanning wifi networks...</p>
+<script>
+fetch("/scan")
+    .then((response) => response.json())
+    .then((networks) => {
+        const list = document.getElementById("networks");
+        for (const network of networks) {
+            const option = document.createElement("option");
+            option.value = network.ssid;
+            option.label = `${network.rssi} dBm, channel ${network.channel}, ${network.auth}`;
+            list.appendChild(option);
+        }
+        document.getElementById("scan").textContent = `${networks.length} wifi networks found`;
+    })
+    .catch(() => {
+        document.getElementById("scan").textContent = "Wifi scan failed";
+    });
+</script>"#;
+
+/// Shows the result of the connection, polling /status
+const STATUS_SCRIPT: &str = r#"<p id="status">Please wait.</p>
+<script>
+function poll() {
+    fetch("/status")
+        .then((response) => response.json())
+        .then((result) => {
+            const status = document.getElementById("status");
+            if (result.status == "connected") {
+                status.textContent = "Connected. The credentials are stored, the device continues with the new network.";
+            } else if (result.status == "failed") {
+                status.innerHTML = "Connection failed: ";
+                status.appendChild(document.createTextNode(result.message));
+                status.insertAdjacentHTML("beforeend", '. <a href="/">Try again</a>');
+            } else {
+                setTimeout(poll, 1000);
+            }
+        })
+        .catch(() => setTimeout(poll, 1000));
+}
+poll();
+</script>"#;
+
+/// Provisioning web server of the device for the state machine
+pub struct DeviceHttp {
+    tx: mpsc::Sender<Event>,
+    server: Option<EspHttpServer>,
+}
+
+impl DeviceHttp {
+    pub fn new(tx: mpsc::Sender<Event>) -> Self {
+        Self { tx, server: None }
+    }
+}
+
+impl Http for DeviceHttp {
+    fn start(&mut self, status: &Arc<Mutex<ProvisioningStatus>>) -> anyhow::Result<()> {
+        self.server = Some(start_http_server(&self.tx, status));
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.server = None;
+    }
+
+    fn is_running(&self) -> bool {
+        self.server.is_some()
+    }
 }
@@ -3,11 +3,15 @@
 pub mod fsm;
 pub mod http;
 pub mod mqtt;
+pub mod reset;
+pub mod sensors;
 pub mod shtc3;
+pub mod storage;
 pub mod wifi;
 
-use self::fsm::{Event, Fsm};
-use self::shtc3::start_sensor;
+use self::fsm::new_fsm;
+use self::reset::start_reset_button;
+use self::sensors::start_sensors;
 use esp_idf_hal::prelude::Peripherals;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
@@ -40,7 +44,10 @@ fn main() -> anyhow::Result<()> {
     info!("Inicialización del wifi terminada");
 
     let (tx, rx) = mpsc::channel();
-    let timer = start_sensor(peripherals.pins, peripherals.i2c0, tx.clone())?;
+    let pins = peripherals.pins;
+    let _sensor_timers = start_sensors(pins.gpio10, pins.gpio8, peripherals.i2c0, tx.clone())?;
+    // long press of the BOOT button for a factory reset
+    let _reset_timer = start_reset_button(pins.gpio9, tx.clone())?;
 
     thread::Builder::new()
         .name("threadfsm".to_string())
@@ -48,8 +55,8 @@ fn main() -> anyhow::Result<()> {
         .spawn(move || {
             info!("Thread for FSM event processing started.");
             // Option: start sensors timer here.
-            // start_sensor(peripherals.pins, peripherals.i2c0, tx.clone()).unwrap();
-            let mut fsm = Fsm::new(tx, sysloop, wifi, nvs);
+            // start_sensors(pins.gpio10, pins.gpio8, peripherals.i2c0, tx.clone()).unwrap();
+            let mut fsm = new_fsm(tx, sysloop, wifi, nvs);
             loop {
                 let event = rx.recv().unwrap();
                 info!("Event received: {:?}", event);
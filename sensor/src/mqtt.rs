@@ -1,98 +1,336 @@
-use core::time::Duration;
-use embedded_svc::mqtt::client::{Details::Complete, Event::Received, QoS};
-use std::thread;
+use embedded_svc::mqtt::client::{
+    Details::Complete,
+    Event::{Connected, Disconnected, Received},
+    QoS,
+};
 
-use crate::fsm::Event;
-use esp_idf_svc::mqtt::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration};
+use crate::reset;
+use common::buffer::Stamped;
+use common::sensor::{Measurement, Quantity};
+use common::topic::Router;
+use sensor_core::config::DeviceConfig;
+use sensor_core::machine::Mqtt;
+use sensor_core::transitions::Event;
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EspMqttMessage, LwtConfiguration, MqttClientConfiguration,
+};
+use esp_idf_svc::tls::X509;
 use esp_idf_sys::EspError;
 use log::{error, info, warn};
 use std::sync::mpsc;
 
+/// Topic to receive commands
+const COMMAND_TOPIC: &str = "/rust/command";
+
+/// Topic for the readings taken while disconnected:
+/// {"ts":<timestamp ms>,"value":<temperature>}
+const BACKLOG_TOPIC: &str = "/rust/temperature/backlog";
+
+/// Handlers of the messages received from the MQTT server, by topic filter
+type MessageRouter = Router<Box<dyn Fn(&str, &[u8]) + Send>, 4>;
+
+/// Topic with the retained status of the device: "online" or "offline"
+pub fn status_topic(device_id: &str) -> String {
+    format!("/rust/{}/status", device_id)
+}
+
+/// Topic with the status of the certificate of the device, published when
+/// the "certificate" command is received
+pub fn certificate_topic(device_id: &str) -> String {
+    format!("/rust/{}/certificate", device_id)
+}
+
+/// Topic to request a factory reset of the device. The payload must be the
+/// reset token of the device (see `sensor_core::reset`).
+pub fn reset_topic(device_id: &str) -> String {
+    format!("/rust/{}/reset", device_id)
+}
 
 /// Starts the connection to MQTT server.
-/// It uses host, user and passwd as credentials for the server.
-/// tx: queue to send commands to the FSM (when a message is received)
-///   - It publish a welcome message at /rust/test
-///   - It subscribe to /rust/command to receive commands
+/// It connects to host:port, with user and passwd as credentials. With a
+/// server CA the connection uses TLS (mqtts) and the certificate of the
+/// server must be signed by it. With a client identity (certificate and
+/// private key of the device) the device authenticates with it in the TLS
+/// handshake.
+/// tx: queue to send commands to the FSM (when a message is received) and
+/// the changes of the connection (MqttConnected, MqttDisconnected)
+///   - It sets "offline" as last will in the status topic of the device
+///   - It routes the messages of the topics with a handler (/rust/command
+///     and the reset topic), returned to subscribe to them with `announce`
 pub fn start_mqtt_client(
-    mut tx: mpsc::Sender<Event>,
+    tx: mpsc::Sender<Event>,
     host: &str,
+    port: u16,
     user: Option<&str>,
     passwd: Option<&str>,
-) -> Result<EspMqttClient, EspError> {
-    let broker_url = if let (Some(user), Some(passwd)) = (user, passwd) {
-        format!("mqtt://{}:{}@{}", user, passwd, host)
-    } else {
-        format!("mqtt://{}", host)
+    server_ca: Option<X509<'static>>,
+    identity: Option<(X509<'static>, X509<'static>)>,
+    device_id: &str,
+) -> Result<(EspMqttClient, Vec<String>), EspError> {
+    let scheme = if server_ca.is_some() { "mqtts" } else { "mqtt" };
+    let broker_url = format!("{}://{}:{}", scheme, host, port);
+    if server_ca.is_none() && passwd.is_some() {
+        warn!("MQTT password sent without TLS");
+    }
+
+    // the server publishes the last will if the connection is lost
+    let status_topic = status_topic(device_id);
+    let (client_certificate, private_key) = identity.unzip();
+    let mqtt_config = MqttClientConfiguration {
+        client_id: Some(device_id),
+        lwt: Some(LwtConfiguration {
+            topic: &status_topic,
+            payload: b"offline",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
+        username: user,
+        password: passwd,
+        server_certificate: server_ca,
+        client_certificate,
+        private_key,
+        ..Default::default()
     };
 
-    let mqtt_config = MqttClientConfiguration::default();
+    let mut router = MessageRouter::new();
+    router
+        .add(COMMAND_TOPIC, Box::new(command_handler(tx.clone())))
+        .expect("Error adding mqtt command handler");
+    router
+        .add(
+            &reset_topic(device_id),
+            Box::new(reset_handler(tx.clone(), device_id)),
+        )
+        .expect("Error adding mqtt reset handler");
+    let filters: Vec<String> = router.filters().map(String::from).collect();
 
     // connect to MQTT server
-    let mut client = EspMqttClient::new(
+    let client = EspMqttClient::new(
         broker_url,
         &mqtt_config,
         // process messages received from server
         move |message_event| match message_event {
-            Ok(Received(msg)) => process_message(msg, &mut tx),
+            Ok(Received(msg)) => process_message(msg, &router),
+            Ok(Connected(_)) => {
+                info!("Connected to MQTT server.");
+                tx.send(Event::MqttConnected).ok();
+            }
+            Ok(Disconnected) => {
+                warn!("Disconnected from MQTT server.");
+                tx.send(Event::MqttDisconnected).ok();
+            }
             _ => warn!("mqtt debug: received from mqtt client: {:?}", message_event),
         },
     )?;
 
-    info!("Sending mqtt welcome message.");
-    client.publish(
-        "/rust/test",
-        QoS::AtLeastOnce,
-        true,
-        b"Rust sensor node connected to MQTT.",
-    )?;
+    Ok((client, filters))
+}
 
-    // Subscribe to receive commands from MQTT server
-    info!("Subscribing to mqtt topic /rust/command");
-    // it is necessary ta wait a little before subscribing
-    thread::sleep(Duration::from_millis(100));
-    client.subscribe("/rust/command", QoS::AtLeastOnce)?;
-    // With error handling
-    // let res = client.subscribe("/rust/command", QoS::AtLeastOnce)?;
-    // match res {
-    //     Ok(id) => {
-    //         println!("Suscrito con id {}", id);
-    //     }
-    //     Err(error) => {
-    //         println!("Error en subscripcion {}", error);
-    //     }
-    // };
-
-    Ok(client)
+/// Publishes the birth message "online" in the status topic of the device
+/// and subscribes to the topic filters, once connected
+pub fn announce(
+    client: &mut EspMqttClient,
+    device_id: &str,
+    filters: &[String],
+) -> Result<(), EspError> {
+    let status_topic = status_topic(device_id);
+    info!("Sending mqtt birth message to {}.", status_topic);
+    client.publish(&status_topic, QoS::AtLeastOnce, true, b"online")?;
+    for filter in filters {
+        info!("Subscribing to mqtt topic {}", filter);
+        client.subscribe(filter, QoS::AtLeastOnce)?;
+    }
+    Ok(())
 }
 
-/// Handles messages received from MQTT server
-fn process_message(message: &EspMqttMessage, tx: &mut mpsc::Sender<Event>) {
+/// Handles messages received from MQTT server, passing them to the handlers
+/// of their topic
+fn process_message(message: &EspMqttMessage, router: &MessageRouter) {
     match message.details() {
         Complete => {
+            let topic = message.topic().unwrap_or_default();
             let message_data: &[u8] = message.data();
+            // not the data: the reset topic carries the reset token
             info!(
-                "Received message from MQTT server: {:?}, data: {:?}",
-                message, message_data
+                "Received message from MQTT server in {}, {} bytes",
+                topic,
+                message_data.len()
             );
-            let command = String::from(std::str::from_utf8(&message_data).unwrap());
-            let event = Event::RemoteCommand { command };
+            if router.dispatch(topic, message_data) == 0 {
+                warn!("No handler for mqtt topic {}", topic);
+            }
+        }
+        _ => error!("Could not proccess command"),
+    }
+}
+
+/// Handler that sends the commands received to the Fsm as RemoteCommand
+fn command_handler(tx: mpsc::Sender<Event>) -> impl Fn(&str, &[u8]) + Send {
+    move |_topic, data| match std::str::from_utf8(data) {
+        Ok(command) => {
+            let event = Event::RemoteCommand {
+                command: String::from(command),
+            };
             // send event to the Fsm
             tx.send(event).unwrap();
         }
-        _ => error!("Could not proccess command"),
+        Err(_) => error!("Command received is not valid utf-8"),
     }
 }
 
+/// Handler that sends FactoryReset to the Fsm if the reset is authorized
+fn reset_handler(tx: mpsc::Sender<Event>, device_id: &str) -> impl Fn(&str, &[u8]) + Send {
+    let device_id = String::from(device_id);
+    move |topic, data| {
+        if reset::is_authorized(&device_id, data) {
+            warn!("Factory reset requested in {}", topic);
+            tx.send(Event::FactoryReset).unwrap();
+        } else {
+            warn!("Factory reset in {} not authorized", topic);
+        }
+    }
+}
+
+/// Topic of a quantity measured: /rust/temperature, /rust/humidity,
+/// /rust/dew_point, ...
+pub fn measurement_topic(quantity: Quantity) -> String {
+    format!("/rust/{}", quantity.name())
+}
 
-/// Send a temperature to MQTT server
-pub fn send_temperature(mqttc: &mut EspMqttClient, temp: f32) {
+/// Send a measurement to the topic of its quantity, as a plain value.
+/// Not retained: a new subscriber would get a stale value as current. Only
+/// the status topic is retained.
+pub fn send_measurement(
+    mqttc: &mut EspMqttClient,
+    measurement: &Measurement,
+) -> Result<u32, EspError> {
     info!("Sending mqtt data.");
     mqttc.publish(
-        "/rust/temperature",
+        &measurement_topic(measurement.quantity),
+        QoS::AtLeastOnce,
+        false,
+        format!("{}", measurement.value).as_bytes(),
+    )
+}
+
+/// Send a temperature taken while disconnected, with the time it was taken
+/// (milliseconds since the epoch, or since boot if the clock is not set)
+pub fn send_reading(mqttc: &mut EspMqttClient, reading: &Stamped<f32>) -> Result<u32, EspError> {
+    mqttc.publish(
+        BACKLOG_TOPIC,
         QoS::AtLeastOnce,
-        true,
-        format!("{}", temp).as_bytes(),
+        false,
+        format!(
+            "{{\"ts\":{},\"value\":{}}}",
+            reading.timestamp_millis, reading.value
+        )
+        .as_bytes(),
     )
-    .expect("Error sending data to MQTT server.");
+}
+
+/// MQTT client of the device for the state machine
+pub struct DeviceMqtt {
+    tx: mpsc::Sender<Event>,
+    client: Option<EspMqttClient>,
+    device_id: String,
+    // Topic filters to subscribe to on every connection
+    filters: Vec<String>,
+    // The client keeps pointers to the certificates and the key, so they
+    // must live forever. Each one is leaked once and reused while the
+    // configuration has the same PEM.
+    pems: Vec<(String, &'static [u8])>,
+}
+
+impl DeviceMqtt {
+    pub fn new(tx: mpsc::Sender<Event>) -> Self {
+        Self {
+            tx,
+            client: None,
+            device_id: String::new(),
+            filters: Vec::new(),
+            pems: Vec::new(),
+        }
+    }
+
+    /// Certificate or key of the configuration, in the format of the
+    /// client: PEM ended by a nul character
+    fn pem(&mut self, pem: &str) -> X509<'static> {
+        if let Some((_, bytes)) = self.pems.iter().find(|(cached, _)| cached == pem) {
+            return X509::pem_until_nul(bytes);
+        }
+        let mut bytes = pem.as_bytes().to_vec();
+        bytes.push(0);
+        let bytes: &'static [u8] = Box::leak(bytes.into_boxed_slice());
+        self.pems.push((String::from(pem), bytes));
+        X509::pem_until_nul(bytes)
+    }
+}
+
+impl Mqtt for DeviceMqtt {
+    fn start(&mut self, config: &DeviceConfig, device_id: &str) -> anyhow::Result<()> {
+        let server_ca = config.mqtt_ca.as_deref().map(|pem| self.pem(pem));
+        let identity = match (config.mqtt_cert.as_deref(), config.mqtt_key.as_deref()) {
+            (Some(cert), Some(key)) => Some((self.pem(cert), self.pem(key))),
+            _ => None,
+        };
+        let (client, filters) = start_mqtt_client(
+            self.tx.clone(),
+            &config.mqtt_host,
+            config.mqtt_port,
+            config.mqtt_user.as_deref(),
+            config.mqtt_passwd.as_deref(),
+            server_ca,
+            identity,
+            device_id,
+        )?;
+        self.client = Some(client);
+        self.device_id = String::from(device_id);
+        self.filters = filters;
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.client = None;
+    }
+
+    fn is_started(&self) -> bool {
+        self.client.is_some()
+    }
+
+    fn announce(&mut self) -> anyhow::Result<()> {
+        let Some(client) = self.client.as_mut() else {
+            anyhow::bail!("MQTT client not started");
+        };
+        announce(client, &self.device_id, &self.filters)?;
+        Ok(())
+    }
+
+    fn send_measurement(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let Some(client) = self.client.as_mut() else {
+            anyhow::bail!("MQTT client not started");
+        };
+        send_measurement(client, measurement)?;
+        Ok(())
+    }
+
+    fn send_reading(&mut self, reading: &Stamped<f32>) -> anyhow::Result<()> {
+        let Some(client) = self.client.as_mut() else {
+            anyhow::bail!("MQTT client not started");
+        };
+        send_reading(client, reading)?;
+        Ok(())
+    }
+
+    fn send_certificate_status(&mut self, status: &str) -> anyhow::Result<()> {
+        let Some(client) = self.client.as_mut() else {
+            anyhow::bail!("MQTT client not started");
+        };
+        client.publish(
+            &certificate_topic(&self.device_id),
+            QoS::AtLeastOnce,
+            false,
+            status.as_bytes(),
+        )?;
+        Ok(())
+    }
 }
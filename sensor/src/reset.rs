@@ -0,0 +1,110 @@
+//! Factory reset: the configuration is erased and the device reboots into
+//! provisioning mode. It is requested by holding the BOOT button (GPIO9)
+//! or with an authorized message in the reset topic of the device.
+
+use anyhow::Result;
+use esp_idf_hal::gpio::{Gpio9, PinDriver, Pull};
+use esp_idf_svc::timer::*;
+use log::{info, warn};
+use sensor_core::transitions::Event;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Time the BOOT button must be held to reset the device
+const RESET_PRESS: Duration = Duration::from_secs(5);
+/// Period to read the button
+const BUTTON_POLL: Duration = Duration::from_millis(100);
+/// Secret to derive the reset token of the device, given at build time.
+/// The remote reset is disabled when it is not set.
+const RESET_SECRET: Option<&str> = option_env!("SENSOR_RESET_TOKEN");
+
+/// Progress of a long press
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressEvent {
+    /// Seconds left to complete the press
+    Countdown(u64),
+    /// Released before the time
+    Cancelled,
+    /// Held for the whole time (once per press)
+    Triggered,
+}
+
+/// Detection of a long press from the state of a button read periodically
+pub struct LongPress {
+    hold: Duration,
+    pressed_since: Option<Duration>,
+    countdown: Option<u64>,
+    triggered: bool,
+}
+
+impl LongPress {
+    pub fn new(hold: Duration) -> Self {
+        LongPress {
+            hold,
+            pressed_since: None,
+            countdown: None,
+            triggered: false,
+        }
+    }
+
+    /// Updates with the state of the button at the time `now` (monotonic).
+    /// It returns an event when the countdown changes, ends or is cancelled.
+    pub fn update(&mut self, pressed: bool, now: Duration) -> Option<PressEvent> {
+        if !pressed {
+            let released = self.pressed_since.take().is_some() && !self.triggered;
+            self.countdown = None;
+            self.triggered = false;
+            return released.then_some(PressEvent::Cancelled);
+        }
+
+        let since = *self.pressed_since.get_or_insert(now);
+        if self.triggered {
+            return None;
+        }
+        let held = now.saturating_sub(since);
+        if held >= self.hold {
+            self.triggered = true;
+            return Some(PressEvent::Triggered);
+        }
+        // seconds left, rounded up
+        let left = ((self.hold - held).as_millis() as u64 + 999) / 1000;
+        if self.countdown == Some(left) {
+            None
+        } else {
+            self.countdown = Some(left);
+            Some(PressEvent::Countdown(left))
+        }
+    }
+}
+
+/// Watches the BOOT button, sending FactoryReset to the Fsm after a long
+/// press. The countdown is shown in the log.
+pub fn start_reset_button(pin: Gpio9, tx: mpsc::Sender<Event>) -> Result<EspTimer> {
+    info!("Starting factory reset button");
+    // the BOOT button connects GPIO9 to ground
+    let mut button = PinDriver::input(pin)?;
+    button.set_pull(Pull::Up)?;
+
+    let start = Instant::now();
+    let mut press = LongPress::new(RESET_PRESS);
+    let timer = EspTimerService::new()?.timer(move || {
+        match press.update(button.is_low(), start.elapsed()) {
+            Some(PressEvent::Countdown(left)) => {
+                warn!("Factory reset in {} s, release the button to cancel", left)
+            }
+            Some(PressEvent::Cancelled) => info!("Factory reset cancelled"),
+            Some(PressEvent::Triggered) => {
+                warn!("Factory reset requested with the button");
+                tx.send(Event::FactoryReset).unwrap();
+            }
+            None => {}
+        }
+    })?;
+    timer.every(BUTTON_POLL)?;
+    Ok(timer)
+}
+
+/// The payload of a remote reset has the reset token of the device
+pub fn is_authorized(device_id: &str, payload: &[u8]) -> bool {
+    sensor_core::reset::is_authorized(RESET_SECRET, device_id, payload)
+}
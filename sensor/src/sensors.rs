@@ -0,0 +1,153 @@
+//! Sensors of the device. Each one is registered in `SENSORS`, with how
+//! often it is read, and has a driver implementing `Sensor`. To add a
+//! sensor: write its driver, add it to `SensorKind` and `Driver`, and
+//! register it in `SENSORS`.
+
+use crate::shtc3::Shtc3Sensor;
+use common::sensor::{Measurements, Sensor};
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use esp_idf_hal::gpio::{Gpio10, Gpio8};
+use esp_idf_hal::i2c::{I2cConfig, I2cDriver, I2cError, I2C0};
+use esp_idf_hal::prelude::*;
+use esp_idf_svc::timer::{EspTimer, EspTimerService};
+use log::{error, info};
+use sensor_core::humidity::add_derived;
+use sensor_core::transitions::Event;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorKind {
+    /// Temperature and humidity sensor on board
+    Shtc3,
+}
+
+/// Sensor to read and how often
+#[derive(Debug, Clone, Copy)]
+pub struct SensorConfig {
+    pub kind: SensorKind,
+    pub period: Duration,
+}
+
+/// Sensors of the device. Each one is read by its own timer.
+pub const SENSORS: [SensorConfig; 1] = [SensorConfig {
+    kind: SensorKind::Shtc3,
+    period: Duration::from_secs(5),
+}];
+
+/// The i2c bus, shared by the sensors. Pins GPIO10 SDA, GPIO8 SCL.
+#[derive(Clone)]
+pub struct SharedI2c(Arc<Mutex<I2cDriver<'static>>>);
+
+impl Read for SharedI2c {
+    type Error = I2cError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        Read::read(&mut *self.0.lock().unwrap(), address, buffer)
+    }
+}
+
+impl Write for SharedI2c {
+    type Error = I2cError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        Write::write(&mut *self.0.lock().unwrap(), address, bytes)
+    }
+}
+
+impl WriteRead for SharedI2c {
+    type Error = I2cError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        WriteRead::write_read(&mut *self.0.lock().unwrap(), address, bytes, buffer)
+    }
+}
+
+/// Driver of any of the sensors
+pub enum Driver {
+    Shtc3(Shtc3Sensor<SharedI2c>),
+}
+
+impl Driver {
+    /// Driver for a sensor of the kind given, connected to the bus
+    pub fn new(kind: SensorKind, i2c: SharedI2c) -> anyhow::Result<Self> {
+        let driver = match kind {
+            SensorKind::Shtc3 => Driver::Shtc3(Shtc3Sensor::new(i2c)),
+        };
+        Ok(driver)
+    }
+}
+
+impl Sensor for Driver {
+    type Error = anyhow::Error;
+
+    fn name(&self) -> &'static str {
+        match self {
+            Driver::Shtc3(sensor) => sensor.name(),
+        }
+    }
+
+    fn measure(&mut self, timestamp_millis: u64) -> anyhow::Result<Measurements> {
+        match self {
+            Driver::Shtc3(sensor) => sensor
+                .measure(timestamp_millis)
+                .map_err(|err| anyhow::anyhow!("{:?}", err)),
+        }
+    }
+}
+
+/// Starts the periodic readings of the sensors in SENSORS. Their
+/// measurements, with the values derived from them, are sent to the Fsm.
+/// The readings stop when the timers returned are dropped.
+pub fn start_sensors(
+    sda: Gpio10,
+    scl: Gpio8,
+    i2c: I2C0,
+    tx: mpsc::Sender<Event>,
+) -> anyhow::Result<Vec<EspTimer>> {
+    let config = I2cConfig::new().baudrate(100.kHz().into());
+    let i2c = I2cDriver::new(i2c, sda, scl, &config)?;
+    let i2c = SharedI2c(Arc::new(Mutex::new(i2c)));
+
+    let service = EspTimerService::new()?;
+    let mut timers = Vec::new();
+    for config in SENSORS {
+        info!("Starting sensor {:?}", config.kind);
+        let mut driver = match Driver::new(config.kind, i2c.clone()) {
+            Ok(driver) => driver,
+            Err(err) => {
+                error!("Error starting sensor {:?}: {}", config.kind, err);
+                continue;
+            }
+        };
+        let tx = tx.clone();
+        let timer = service.timer(move || match driver.measure(now_millis()) {
+            Ok(mut measurements) => {
+                add_derived(&mut measurements);
+                for measurement in &measurements {
+                    info!(
+                        "{} {} reading: {} {}",
+                        measurement.sensor,
+                        measurement.quantity.name(),
+                        measurement.value,
+                        measurement.unit.symbol()
+                    );
+                }
+                tx.send(Event::Measurements(measurements)).unwrap();
+            }
+            Err(err) => error!("Error reading sensor {}: {}", driver.name(), err),
+        })?;
+        info!("Starting measurements every {:?}", config.period);
+        timer.every(config.period)?;
+        timers.push(timer);
+    }
+    Ok(timers)
+}
+
+/// Milliseconds since the epoch, or since boot if the clock is not set
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis() as u64)
+        .unwrap_or_default()
+}
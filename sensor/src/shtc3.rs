@@ -1,45 +1,60 @@
-use crate::Event;
-use anyhow::Result;
-use esp_idf_hal::gpio::Pins;
-use esp_idf_hal::i2c::I2C0;
-use esp_idf_hal::{
-    delay,
-    i2c::{I2cConfig, I2cDriver},
-    prelude::*,
-};
-use esp_idf_svc::timer::*;
-use log::info;
+//! Temperature and humidity sensor SHTC3
+
+use common::sensor::{Measurement, Measurements, Quantity, Sensor};
+use embedded_hal::blocking::i2c::{Read, Write};
+use esp_idf_hal::delay;
 use shtcx::sensor_class::Sht2Gen;
 use shtcx::{self, shtc3, PowerMode, ShtCx};
-use std::sync::mpsc;
-use std::time::*;
+use std::fmt::Debug;
 
-pub struct ShtcSensor<'a> {
-    pub sensor: ShtCx<Sht2Gen, I2cDriver<'a>>,
+pub struct Shtc3Sensor<I2C> {
+    sensor: ShtCx<Sht2Gen, I2C>,
+    delay: delay::Ets,
 }
 
-pub fn start_sensor(pins: Pins, i2c: I2C0, tx: mpsc::Sender<Event>) -> Result<EspTimer> {
-    info!("Starting sensor shtc3");
-
-    let sda = pins.gpio10;
-    let scl = pins.gpio8;
-    let config = I2cConfig::new().baudrate(100.kHz().into());
-    let i2c = I2cDriver::new(i2c, sda, scl, &config)?;
-    let mut temp_sensor = shtc3(i2c);
+impl<I2C, E> Shtc3Sensor<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Shtc3Sensor {
+            sensor: shtc3(i2c),
+            delay: delay::Ets,
+        }
+    }
+}
 
-    let mut delay = delay::Ets;
+impl<I2C, E> Sensor for Shtc3Sensor<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+    E: Debug,
+{
+    type Error = shtcx::Error<E>;
 
-    let periodic_timer = EspTimerService::new()?.timer(move || {
-        let temp = temp_sensor
-            .measure_temperature(PowerMode::NormalMode, &mut delay)
-            .unwrap()
-            .as_degrees_celsius();
-        info!("Temperature reading: {} °C", temp);
-        let event = Event::SensorData(temp);
-        tx.send(event).unwrap();
-    })?;
+    fn name(&self) -> &'static str {
+        "shtc3"
+    }
 
-    info!("Starting measurements every 5 seconds ");
-    periodic_timer.every(Duration::from_secs(5))?;
-    Ok(periodic_timer)
+    fn measure(&mut self, timestamp_millis: u64) -> Result<Measurements, Self::Error> {
+        // both values in one measurement
+        let measurement = self
+            .sensor
+            .measure(PowerMode::NormalMode, &mut self.delay)?;
+        let values = [
+            (
+                Quantity::Temperature,
+                measurement.temperature.as_degrees_celsius(),
+            ),
+            (
+                Quantity::RelativeHumidity,
+                measurement.humidity.as_percent(),
+            ),
+        ];
+        let mut measurements = Measurements::new();
+        for (quantity, value) in values {
+            let measurement = Measurement::new(self.name(), quantity, value, timestamp_millis);
+            measurements.push(measurement).ok();
+        }
+        Ok(measurements)
+    }
 }
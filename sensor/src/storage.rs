@@ -0,0 +1,39 @@
+//! NVS backend of the configuration storage
+
+use sensor_core::config::Storage;
+use embedded_svc::storage::RawStorage;
+use esp_idf_svc::nvs::EspDefaultNvs;
+
+/// Blobs stored in a namespace of the default NVS partition
+pub struct NvsStorage {
+    nvs: EspDefaultNvs,
+}
+
+impl NvsStorage {
+    pub fn new(nvs: EspDefaultNvs) -> Self {
+        NvsStorage { nvs }
+    }
+}
+
+impl Storage for NvsStorage {
+    fn read(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.nvs.len(key)? {
+            Some(len) => {
+                let mut value = vec![0u8; len];
+                let value = self.nvs.get_raw(key, &mut value)?.map(|value| value.to_vec());
+                Ok(value)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.nvs.set_raw(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        self.nvs.remove(key)?;
+        Ok(())
+    }
+}
@@ -1,14 +1,20 @@
 use anyhow::bail;
 use embedded_svc::wifi::*;
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
+    eventloop::{EspSubscription, EspSystemEventLoop, System},
     netif::{EspNetif, EspNetifWait},
-    wifi::{EspWifi, WifiWait},
+    wifi::{EspWifi, WifiEvent, WifiWait},
 };
 use log::info;
+use sensor_core::machine::Wifi;
+use sensor_core::provisioning::{strongest_networks, Network};
+use sensor_core::transitions::Event;
 use std::net::Ipv4Addr;
+use std::sync::mpsc;
 use std::time::Duration;
 
+/// Name of the access point used for provisioning
+const AP_SSID: &str = "aptest";
 
 pub fn wifi_sta_start(
     wifi: &mut Box<EspWifi>,
@@ -16,16 +22,59 @@ pub fn wifi_sta_start(
     ssid: &str,
     password: &str,
 ) -> anyhow::Result<()> {
+    let configuration = Configuration::Client(client_configuration(ssid, password));
+    wifi_sta_connect(wifi, sysloop, &configuration)
+}
 
-    wifi.set_configuration(&embedded_svc::wifi::Configuration::Client(
-        embedded_svc::wifi::ClientConfiguration {
-            ssid: ssid.into(),
-            password: password.into(),
-            // channel: Some(1), //channel,
-            ..Default::default()
-        },
-    ))
-    .expect("Error configuring wifi sta");
+/// Trial connection to a wifi station during provisioning. The access point
+/// is kept active, so the provisioning portal can show the result.
+pub fn wifi_sta_trial(
+    wifi: &mut Box<EspWifi>,
+    sysloop: &EspSystemEventLoop,
+    ssid: &str,
+    password: &str,
+) -> anyhow::Result<()> {
+    let configuration =
+        Configuration::Mixed(client_configuration(ssid, password), ap_configuration());
+    wifi_sta_connect(wifi, sysloop, &configuration)
+}
+
+/// Stops the provisioning access point, keeping the station connected
+pub fn wifi_ap_stop(wifi: &mut Box<EspWifi>) -> anyhow::Result<()> {
+    let client = wifi
+        .get_configuration()?
+        .as_client_conf_ref()
+        .cloned()
+        .unwrap_or_default();
+    wifi.set_configuration(&Configuration::Client(client))?;
+    Ok(())
+}
+
+fn client_configuration(ssid: &str, password: &str) -> ClientConfiguration {
+    ClientConfiguration {
+        ssid: ssid.into(),
+        password: password.into(),
+        // channel: Some(1), //channel,
+        ..Default::default()
+    }
+}
+
+fn ap_configuration() -> AccessPointConfiguration {
+    AccessPointConfiguration {
+        ssid: AP_SSID.into(),
+        channel: 1,
+        ..Default::default()
+    }
+}
+
+/// Connects the station with the configuration, waiting for the connection
+/// and the DHCP lease (20 s each at most)
+fn wifi_sta_connect(
+    wifi: &mut Box<EspWifi>,
+    sysloop: &EspSystemEventLoop,
+    configuration: &Configuration,
+) -> anyhow::Result<()> {
+    wifi.set_configuration(configuration)?;
 
     wifi.start()?;
 
@@ -59,13 +108,12 @@ pub fn wifi_sta_start(
     Ok(())
 }
 
+/// Starts the access point for provisioning. The station is also started
+/// (AP+STA mode, not connected) so it can scan the wifi networks.
 pub fn wifi_ap_start(wifi: &mut Box<EspWifi>, sysloop: &EspSystemEventLoop) -> anyhow::Result<()> {
-    wifi.set_configuration(&embedded_svc::wifi::Configuration::AccessPoint(
-        embedded_svc::wifi::AccessPointConfiguration {
-            ssid: "aptest".into(),
-            channel: 1,
-            ..Default::default()
-        },
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        ap_configuration(),
     ))
     .expect("Error configurando wifi ap");
 
@@ -80,3 +128,96 @@ pub fn wifi_ap_start(wifi: &mut Box<EspWifi>, sysloop: &EspSystemEventLoop) -> a
     }
     Ok(())
 }
+
+/// Scans the wifi networks around, with the station in AP+STA mode
+pub fn wifi_scan(wifi: &mut Box<EspWifi>) -> anyhow::Result<Vec<Network>> {
+    info!("Scanning wifi networks...");
+    let networks = wifi
+        .scan()?
+        .into_iter()
+        .map(|ap| Network {
+            ssid: ap.ssid.as_str().into(),
+            rssi: ap.signal_strength,
+            channel: ap.channel,
+            auth: auth_name(ap.auth_method),
+        })
+        .collect();
+    Ok(strongest_networks(networks))
+}
+
+fn auth_name(auth_method: AuthMethod) -> &'static str {
+    match auth_method {
+        AuthMethod::None => "open",
+        AuthMethod::WEP => "wep",
+        AuthMethod::WPA => "wpa",
+        AuthMethod::WPA2Personal => "wpa2",
+        AuthMethod::WPAWPA2Personal => "wpa/wpa2",
+        AuthMethod::WPA2Enterprise => "wpa2-enterprise",
+        AuthMethod::WPA3Personal => "wpa3",
+        AuthMethod::WPA2WPA3Personal => "wpa2/wpa3",
+        AuthMethod::WAPIPersonal => "wapi",
+    }
+}
+
+/// Wifi of the device for the state machine. It sends WifiDisconnected to
+/// the machine when the station loses the connection.
+pub struct DeviceWifi<'a> {
+    wifi: Box<EspWifi<'a>>,
+    sysloop: EspSystemEventLoop,
+    _subscription: EspSubscription<System>,
+}
+
+impl<'a> DeviceWifi<'a> {
+    pub fn new(
+        wifi: Box<EspWifi<'a>>,
+        sysloop: EspSystemEventLoop,
+        tx: mpsc::Sender<Event>,
+    ) -> anyhow::Result<Self> {
+        let _subscription = sysloop.subscribe(move |event: &WifiEvent| {
+            if let WifiEvent::StaDisconnected = event {
+                tx.send(Event::WifiDisconnected).ok();
+            }
+        })?;
+        Ok(Self {
+            wifi,
+            sysloop,
+            _subscription,
+        })
+    }
+
+    /// MAC address of the station, in hex
+    pub fn mac(&self) -> anyhow::Result<String> {
+        let mac = self.wifi.sta_netif().get_mac()?;
+        Ok(mac.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+impl<'a> Wifi for DeviceWifi<'a> {
+    fn start_ap(&mut self) -> anyhow::Result<()> {
+        wifi_ap_start(&mut self.wifi, &self.sysloop)
+    }
+
+    fn stop_ap(&mut self) -> anyhow::Result<()> {
+        wifi_ap_stop(&mut self.wifi)
+    }
+
+    fn connect(&mut self, ssid: &str, psk: &str, keep_ap: bool) -> anyhow::Result<()> {
+        if keep_ap {
+            wifi_sta_trial(&mut self.wifi, &self.sysloop, ssid, psk)
+        } else {
+            wifi_sta_start(&mut self.wifi, &self.sysloop, ssid, psk)
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.wifi.disconnect().ok();
+    }
+
+    fn is_connected(&self) -> bool {
+        self.wifi.is_connected().unwrap_or(false)
+    }
+
+    fn scan(&mut self) -> anyhow::Result<Vec<Network>> {
+        wifi_scan(&mut self.wifi)
+    }
+}
@@ -0,0 +1,1035 @@
+//! Small MQTT 3.1.1 client for the embsens firmware: QoS 0, 1 and 2
+//! publishes, subscriptions routed by topic filter, last will and keep
+//! alive. It runs over any transport with the async Read and Write traits of
+//! embedded-io plus [`Connection`]: the TCP socket of embassy-net, a TLS
+//! session, or a pipe in memory for the tests on the host.
+#![no_std]
+
+use common::mqtt::{Awaiting, FrameBuffer, FrameError, InFlight};
+use common::topic::{Router, RouterError, MAX_FILTER_LEN};
+use core::cell::Cell;
+use embassy_futures::yield_now;
+use embedded_io::{
+    asynch::{Read, Write},
+    Error, ErrorKind,
+};
+use heapless::{Deque, String, Vec};
+use log::{info, warn};
+use mqttrust::{
+    encoding::v4::{
+        decode_slice, encode_slice, Connack, Connect, ConnectReturnCode, LastWill, Pid, Protocol,
+    },
+    Mqtt, MqttError, Packet, Publish, QoS, Subscribe, SubscribeTopic,
+};
+
+/// Maximum number of QoS 1 and 2 publishes waiting for acknowledgement at
+/// the same time
+const MAX_IN_FLIGHT: usize = 4;
+/// Maximum number of incoming QoS 2 publishes waiting for PUBREL
+const MAX_INCOMING: usize = 4;
+/// Default time to wait for an acknowledgement before resending the packet
+const DEFAULT_RETRANSMIT_MILLIS: u64 = 10_000;
+/// Default number of resends before giving up a publish
+const DEFAULT_MAX_RETRIES: u8 = 3;
+/// Maximum number of handlers for received publishes
+const MAX_ROUTES: usize = 8;
+/// Maximum number of topic filters subscribed
+const MAX_SUBSCRIPTIONS: usize = 4;
+/// Maximum size of a received packet, including the fixed header
+const MAX_PACKET_SIZE: usize = 1024;
+
+/// State of the connection used to transport MQTT packets.
+/// Besides this, the transport must implement the async Read and Write
+/// traits of embedded-io.
+pub trait Connection {
+    /// True if there are data waiting to be read, so that read() won't block
+    fn can_recv(&self) -> bool;
+    /// True while the connection with the server is open
+    fn is_connected(&self) -> bool;
+    /// Closes the connection with the server
+    fn close(&mut self);
+}
+
+/// Handler of the publishes received in a topic: it gets the topic and the
+/// payload
+pub type Handler<'a> = &'a dyn Fn(&str, &[u8]);
+
+#[derive(Debug)]
+pub enum TinyMqttError {
+    MqttError(MqttError),
+    /// Error reading from or writing to the transport
+    TransportError(ErrorKind),
+    /// The server did not acknowledge the publishes with these pids after all
+    /// the retries. The packets are discarded.
+    NotAcknowledged(Vec<Pid, MAX_IN_FLIGHT>),
+    /// A received packet is bigger than MAX_PACKET_SIZE. It is skipped.
+    PacketTooLarge(usize),
+    /// The remaining length of a received packet is malformed. The data
+    /// received is discarded because the packet boundaries are lost.
+    InvalidPacket,
+    /// CONNACK not received before the timeout
+    ConnackTimeout,
+    /// Connection refused: the server doesn't support MQTT 3.1.1
+    UnacceptableProtocolVersion,
+    /// Connection refused: client identifier not allowed by the server
+    IdentifierRejected,
+    /// Connection refused: the MQTT service is unavailable
+    ServerUnavailable,
+    /// Connection refused: bad user name or password
+    BadUsernamePassword,
+    /// Connection refused: the client is not authorized to connect
+    NotAuthorized,
+    /// The transport has been closed
+    ConnectionClosed,
+    /// PINGRESP not received in time after a PINGREQ
+    PingTimeout,
+    /// Nothing received from the server in 1.5 times the keep alive time
+    KeepAliveExpired,
+}
+
+impl From<MqttError> for TinyMqttError {
+    fn from(e: MqttError) -> Self {
+        TinyMqttError::MqttError(e)
+    }
+}
+
+impl From<FrameError> for TinyMqttError {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::MalformedLength => TinyMqttError::InvalidPacket,
+            FrameError::TooLarge(len) => TinyMqttError::PacketTooLarge(len),
+        }
+    }
+}
+
+impl TinyMqttError {
+    fn transport<E: Error>(e: E) -> Self {
+        TinyMqttError::TransportError(e.kind())
+    }
+
+    /// True for the errors that leave the connection unusable. The client
+    /// must connect again.
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(
+            self,
+            TinyMqttError::TransportError(_)
+                | TinyMqttError::InvalidPacket
+                | TinyMqttError::ConnectionClosed
+                | TinyMqttError::PingTimeout
+                | TinyMqttError::KeepAliveExpired
+        )
+    }
+
+    /// Error for each return code of a refused connection
+    fn from_return_code(code: ConnectReturnCode) -> Option<Self> {
+        match code {
+            ConnectReturnCode::Accepted => None,
+            ConnectReturnCode::RefusedProtocolVersion => {
+                Some(TinyMqttError::UnacceptableProtocolVersion)
+            }
+            ConnectReturnCode::RefusedIdentifierRejected => Some(TinyMqttError::IdentifierRejected),
+            ConnectReturnCode::ServerUnavailable => Some(TinyMqttError::ServerUnavailable),
+            ConnectReturnCode::BadUsernamePassword => Some(TinyMqttError::BadUsernamePassword),
+            ConnectReturnCode::NotAuthorized => Some(TinyMqttError::NotAuthorized),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PacketBuffer {
+    bytes: [u8; 1024],
+}
+
+impl PacketBuffer {
+    pub fn new(packet: Packet<'_>) -> PacketBuffer {
+        let mut buf = [0u8; 1024];
+        let len = encode_slice(&packet, &mut buf);
+        if let (
+            Ok(len),
+            Packet::Publish(Publish {
+                pid: Some(pid),
+                payload,
+                ..
+            }),
+        ) = (len, packet)
+        {
+            write_publish_pid(&mut buf, len - payload.len(), pid);
+        }
+        PacketBuffer { bytes: buf }
+    }
+
+    pub fn parsed(&self) -> Packet<'_> {
+        decode_slice(&self.bytes).unwrap().unwrap()
+    }
+}
+
+/// Writes the packet identifier of an encoded PUBLISH packet.
+/// encode_slice doesn't fill in the pid for publish packets, it leaves it
+/// as 0 just before the payload.
+fn write_publish_pid(buf: &mut [u8], payload_idx: usize, pid: Pid) {
+    let pid: u16 = pid.into();
+    let idx = payload_idx - 2;
+    buf[idx] = ((pid & 0xff00) >> 8) as u8;
+    buf[idx + 1] = (pid & 0xff) as u8;
+}
+
+pub struct TinyMqtt<'a, T> {
+    client_id: &'a str,
+    // Transport for the MQTT connection, usually a TCP socket
+    pub socket: T,
+    // Queue of encoded packages to send
+    queue: core::cell::RefCell<Deque<(usize, [u8; 1024]), 10>>,
+    // Bytes received and not processed yet: an incomplete packet at most
+    recv_buffer: core::cell::RefCell<FrameBuffer<MAX_PACKET_SIZE>>,
+    // Queue of encoded packages to receive
+    recv_queue: core::cell::RefCell<Deque<PacketBuffer, 10>>,
+    // Publishes not acknowledged yet by the server, by packet identifier
+    in_flight: core::cell::RefCell<InFlight<Pid, MAX_IN_FLIGHT, 1024>>,
+    // QoS 2 publishes delivered to the application and waiting for PUBREL.
+    // Used to discard the duplicates resent by the server.
+    incoming: core::cell::RefCell<Vec<Pid, MAX_INCOMING>>,
+    timeout_secs: u16,
+    last_sent_millis: u64,
+    last_received_millis: u64,
+    // Time of the PINGREQ waiting for its PINGRESP
+    ping_sent_millis: Cell<Option<u64>>,
+    current_millis_fn: fn() -> u64,
+    // Handlers of the received publishes by topic filter
+    router: Router<Handler<'a>, MAX_ROUTES>,
+    // Topic filters to subscribe to on every connection
+    subscriptions: Vec<(String<MAX_FILTER_LEN>, QoS), MAX_SUBSCRIPTIONS>,
+    // Message published by the server if the connection is lost
+    last_will: Option<LastWill<'a>>,
+    // Last packet identifier given by next_pid()
+    pid: Cell<Pid>,
+    // CONNACK received and not yet processed by connect()
+    connack: Cell<Option<Connack>>,
+    // Set when the server has accepted the connection
+    pub ready: bool,
+}
+
+impl<'a, T> TinyMqtt<'a, T>
+where
+    T: Read + Write + Connection,
+{
+    pub fn new(client_id: &'a str, socket: T, current_millis_fn: fn() -> u64) -> TinyMqtt<'a, T> {
+        TinyMqtt {
+            client_id,
+            socket,
+            queue: core::cell::RefCell::new(Deque::new()),
+            recv_buffer: core::cell::RefCell::new(FrameBuffer::new()),
+            recv_queue: core::cell::RefCell::new(Deque::new()),
+            in_flight: core::cell::RefCell::new(InFlight::new(
+                DEFAULT_RETRANSMIT_MILLIS,
+                DEFAULT_MAX_RETRIES,
+            )),
+            incoming: core::cell::RefCell::new(Vec::new()),
+            timeout_secs: 0,
+            last_sent_millis: 0,
+            last_received_millis: 0,
+            ping_sent_millis: Cell::new(None),
+            current_millis_fn,
+            router: Router::new(),
+            subscriptions: Vec::new(),
+            last_will: None,
+            pid: Cell::new(Pid::new()),
+            connack: Cell::new(None),
+            ready: false,
+        }
+    }
+
+    /// Sets how long to wait for an acknowledgement (PUBACK, PUBREC or
+    /// PUBCOMP) before resending the packet, and how many times to resend it
+    /// before giving up.
+    pub fn set_retransmit(&mut self, retransmit_millis: u64, max_retries: u8) {
+        self.in_flight
+            .borrow_mut()
+            .set_retransmit(retransmit_millis, max_retries);
+    }
+
+    /// Registers a handler for the publishes received in the topics matching
+    /// the filter, which may contain `+` and `#` wildcards. The subscription
+    /// to the filter is done with subscribe().
+    pub fn add_route(&mut self, filter: &str, handler: Handler<'a>) -> Result<(), RouterError> {
+        self.router.add(filter, handler)
+    }
+
+    /// Sets the last will and testament sent in the CONNECT packet. The
+    /// server publishes it when the connection is lost without DISCONNECT.
+    pub fn set_last_will(&mut self, last_will: Option<LastWill<'a>>) {
+        self.last_will = last_will;
+    }
+
+    /// Returns a new packet identifier for a QoS 1 or 2 publish
+    pub fn next_pid(&self) -> Pid {
+        let pid = self.pid.get() + 1;
+        self.pid.set(pid);
+        pid
+    }
+
+    /// Sends a CONNECT packet and waits for the CONNACK of the server, at
+    /// most `timeout_millis`. If the server accepts the connection the client
+    /// is marked as ready and it returns the session present flag.
+    /// It can be called again after losing the connection. The session is a
+    /// clean one, so the state of the previous one is cleared, the
+    /// subscriptions are sent again and the publishes not acknowledged are
+    /// sent again as new ones.
+    pub async fn connect(
+        &mut self,
+        keep_alive_secs: u16,
+        username: Option<&'a str>,
+        password: Option<&'a [u8]>,
+        timeout_millis: u64,
+    ) -> Result<bool, TinyMqttError> {
+        self.ready = false;
+        self.connack.set(None);
+        self.ping_sent_millis.set(None);
+        self.queue.borrow_mut().clear();
+        self.recv_queue.borrow_mut().clear();
+        self.recv_buffer.get_mut().clear();
+        self.incoming.borrow_mut().clear();
+        self.timeout_secs = keep_alive_secs;
+        let connect = Packet::Connect(Connect {
+            protocol: Protocol::MQTT311,
+            keep_alive: keep_alive_secs,
+            client_id: "",
+            clean_session: true,
+            last_will: self.last_will.clone(),
+            username,
+            password,
+        });
+        self.send(connect)?;
+        self.last_sent_millis = (self.current_millis_fn)();
+
+        let deadline = self.last_sent_millis + timeout_millis;
+        loop {
+            self.send_internal().await?;
+            self.receive_internal().await?;
+            if let Some(connack) = self.connack.take() {
+                if let Some(e) = TinyMqttError::from_return_code(connack.code) {
+                    return Err(e);
+                }
+                self.ready = true;
+                self.last_received_millis = (self.current_millis_fn)();
+                if !connack.session_present {
+                    self.resubscribe()?;
+                }
+                // the server has dropped the previous session with its
+                // packet identifiers
+                self.in_flight.borrow_mut().new_session();
+                return Ok(connack.session_present);
+            }
+            if (self.current_millis_fn)() > deadline {
+                return Err(TinyMqttError::ConnackTimeout);
+            }
+            // let other tasks run while the CONNACK arrives
+            yield_now().await;
+        }
+    }
+
+    pub fn disconnect(&mut self) -> Result<(), TinyMqttError> {
+        self.ready = false;
+        self.socket.close();
+        Ok(())
+    }
+
+    /// Queues a publish packet. Publishes with QoS 1 or 2 and a pid are kept
+    /// in the in-flight table until the server completes the acknowledgement:
+    /// PUBACK for QoS 1, PUBREC/PUBREL/PUBCOMP for QoS 2.
+    pub fn publish_with_pid(
+        &self,
+        pid: Option<Pid>,
+        topic_name: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), MqttError> {
+        let packet = Packet::Publish(Publish {
+            dup: false,
+            qos,
+            pid: None,
+            retain,
+            topic_name,
+            payload,
+        });
+
+        let mut buf = [0u8; 1024];
+        let len = encode_slice(&packet, &mut buf).unwrap();
+
+        if let Some(pid) = pid {
+            write_publish_pid(&mut buf, len - payload.len(), pid);
+        }
+
+        let awaiting = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => Some(Awaiting::Puback),
+            QoS::ExactlyOnce => Some(Awaiting::Pubrec),
+        };
+        let tracked = pid.zip(awaiting);
+        if tracked.is_some() && self.in_flight.borrow().is_full() {
+            return Err(MqttError::Full);
+        }
+
+        // tracked only once queued, so that a publish refused here is not
+        // resent later and the caller can publish it again
+        self.queue
+            .borrow_mut()
+            .push_back((len, buf))
+            .map_err(|_| MqttError::Full)?;
+        if let Some((pid, awaiting)) = tracked {
+            let now = (self.current_millis_fn)();
+            // there is room, checked above
+            self.in_flight
+                .borrow_mut()
+                .insert(pid, awaiting, &buf[..len], now)
+                .ok();
+        }
+        Ok(())
+    }
+
+    /// Subscribes to the topics. They are remembered and subscribed again
+    /// each time the client connects, so it can be called before connect().
+    pub fn subscribe(
+        &mut self,
+        _pid: Option<Pid>,
+        topics: &[SubscribeTopic<'_>],
+    ) -> Result<(), MqttError> {
+        for topic in topics {
+            let known = self
+                .subscriptions
+                .iter()
+                .any(|(filter, _)| filter == topic.topic_path);
+            if !known {
+                let mut filter = String::new();
+                filter
+                    .push_str(topic.topic_path)
+                    .map_err(|_| MqttError::Overflow)?;
+                self.subscriptions
+                    .push((filter, topic.qos))
+                    .map_err(|_| MqttError::Full)?;
+            }
+        }
+
+        if self.ready {
+            let subscribe = Subscribe::new(topics);
+            let packet = Packet::Subscribe(subscribe);
+            self.send(packet)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a subscribe packet with all the topics subscribed
+    fn resubscribe(&self) -> Result<(), MqttError> {
+        if self.subscriptions.is_empty() {
+            return Ok(());
+        }
+        let topics: Vec<SubscribeTopic<'_>, MAX_SUBSCRIPTIONS> = self
+            .subscriptions
+            .iter()
+            .map(|(filter, qos)| SubscribeTopic {
+                topic_path: filter.as_str(),
+                qos: *qos,
+            })
+            .collect();
+        self.send(Packet::Subscribe(Subscribe::new(&topics)))
+    }
+
+    /// Polls the connection: sends the queued packets, handles the packets
+    /// received and checks the keep alive. On the errors that lose the
+    /// connection the transport is closed and the client is no longer ready.
+    pub async fn poll(&mut self) -> Result<(), TinyMqttError> {
+        let result = self.poll_internal(true).await;
+        if let Err(e) = &result {
+            if e.is_connection_lost() {
+                warn!("MQTT connection lost: {:?}", e);
+                self.disconnect().ok();
+            }
+        }
+        result
+    }
+
+    async fn poll_internal(&mut self, drain_receive_queue: bool) -> Result<(), TinyMqttError> {
+        if !self.socket.is_connected() {
+            return Err(TinyMqttError::ConnectionClosed);
+        }
+
+        let time = (self.current_millis_fn)();
+        let keep_alive_millis = self.timeout_secs as u64 * 1000;
+
+        if let Some(ping_sent_millis) = self.ping_sent_millis.get() {
+            if time > ping_sent_millis + keep_alive_millis / 2 {
+                return Err(TinyMqttError::PingTimeout);
+            }
+        }
+        if keep_alive_millis > 0 && time > self.last_received_millis + keep_alive_millis * 3 / 2 {
+            return Err(TinyMqttError::KeepAliveExpired);
+        }
+
+        if time > self.last_sent_millis + keep_alive_millis / 2
+            && self.ping_sent_millis.get().is_none()
+        {
+            // ping
+            info!("ping");
+            self.send(Packet::Pingreq)?;
+            self.last_sent_millis = time;
+            self.ping_sent_millis.set(Some(time));
+        }
+
+        // the publishes given up are reported once the rest is done
+        let retransmitted = self.retransmit();
+
+        self.send_internal().await?;
+
+        self.receive_internal().await?;
+
+        // deliver the received publishes to their handlers
+        if drain_receive_queue {
+            while let Some(received) = self.recv_queue.borrow_mut().pop_front() {
+                if let Packet::Publish(publish) = received.parsed() {
+                    let called = self.router.dispatch(publish.topic_name, publish.payload);
+                    if called == 0 {
+                        warn!("No handler for topic {}", publish.topic_name);
+                    }
+                }
+            }
+        }
+
+        retransmitted
+    }
+
+    /// Reads the data available in the socket and processes every complete
+    /// packet in it. A packet can arrive split in several reads and a read can
+    /// contain several packets, so the bytes of an incomplete packet are kept
+    /// in `recv_buffer` until the rest arrives.
+    async fn receive_internal(&mut self) -> Result<(), TinyMqttError> {
+        loop {
+            if self.socket.can_recv() {
+                // socket.read() won't block, there are data waiting to be read.
+            } else {
+                // nothing received in the socket, if read() is called it will
+                // block until something arrives.
+                return Ok(());
+            }
+            let len = self
+                .socket
+                .read(self.recv_buffer.get_mut().spare())
+                .await
+                .map_err(TinyMqttError::transport)?;
+            if len == 0 {
+                return Ok(());
+            }
+
+            self.recv_buffer.get_mut().commit(len);
+            self.process_frames()?;
+        }
+    }
+
+    /// Processes the complete packets in `recv_buffer`. The bytes of an
+    /// incomplete one are kept there until the rest arrives.
+    fn process_frames(&mut self) -> Result<(), TinyMqttError> {
+        let mut received = false;
+        let result = self
+            .recv_buffer
+            .borrow_mut()
+            .frames(|frame| match decode_slice(frame) {
+                Ok(Some(packet)) => {
+                    info!("Packet received: {:?}", packet);
+                    received = true;
+                    self.handle_packet(packet);
+                }
+                _ => warn!("Error decoding mqtt package"),
+            });
+        if received {
+            self.last_received_millis = (self.current_millis_fn)();
+        }
+        if let Err(FrameError::TooLarge(len)) = result {
+            warn!("Discarding mqtt packet of {} bytes", len);
+        }
+        result.map_err(TinyMqttError::from)
+    }
+
+    /// Processes a packet received from the server. Acknowledgements advance
+    /// the in-flight table and incoming publishes are acknowledged. Publishes
+    /// are queued for the application, except QoS 2 duplicates.
+    fn handle_packet(&self, packet: Packet<'_>) {
+        match packet {
+            Packet::Connack(connack) => self.connack.set(Some(connack)),
+            Packet::Pingresp => self.ping_sent_millis.set(None),
+            Packet::Puback(pid) | Packet::Pubcomp(pid) => {
+                // delivery completed, stop resending it
+                self.in_flight.borrow_mut().complete(&pid);
+            }
+            Packet::Pubrec(pid) => {
+                // the server has the QoS 2 publish, from now on resend PUBREL
+                let mut pubrel = [0u8; 4];
+                if let Ok(len) = encode_slice(&Packet::Pubrel(pid), &mut pubrel) {
+                    let now = (self.current_millis_fn)();
+                    self.in_flight
+                        .borrow_mut()
+                        .release(&pid, &pubrel[..len], now);
+                }
+                self.send(Packet::Pubrel(pid)).ok();
+            }
+            Packet::Pubrel(pid) => {
+                // the server won't resend this QoS 2 publish anymore
+                self.incoming.borrow_mut().retain(|p| *p != pid);
+                self.send(Packet::Pubcomp(pid)).ok();
+            }
+            Packet::Publish(Publish {
+                qos: QoS::AtLeastOnce,
+                pid: Some(pid),
+                ..
+            }) => {
+                if self.enqueue_received(packet) {
+                    self.send(Packet::Puback(pid)).ok();
+                }
+            }
+            Packet::Publish(Publish {
+                qos: QoS::ExactlyOnce,
+                pid: Some(pid),
+                ..
+            }) => {
+                if self.incoming.borrow().contains(&pid) {
+                    info!("Discarding duplicated publish with pid {}", pid.get());
+                } else if self.incoming.borrow().is_full() || !self.enqueue_received(packet) {
+                    // without PUBREC the server will send it again later
+                    return;
+                } else {
+                    self.incoming.borrow_mut().push(pid).ok();
+                }
+                self.send(Packet::Pubrec(pid)).ok();
+            }
+            _ => {
+                self.enqueue_received(packet);
+            }
+        }
+    }
+
+    /// Queues a received packet to be processed by poll.
+    /// Returns false if the queue is full and the packet was dropped.
+    fn enqueue_received(&self, packet: Packet<'_>) -> bool {
+        self.recv_queue
+            .borrow_mut()
+            .push_back(PacketBuffer::new(packet))
+            .is_ok()
+    }
+
+    /// Queues again the packets whose acknowledgement has not arrived in
+    /// time, with the DUP flag set for publishes. The packets that exhaust
+    /// their retries are removed from the in-flight table, all of them in
+    /// the same call, and reported together as `NotAcknowledged`.
+    fn retransmit(&self) -> Result<(), TinyMqttError> {
+        let now = (self.current_millis_fn)();
+        let mut expired = Vec::new();
+
+        self.in_flight.borrow_mut().retransmit(
+            now,
+            |pid, packet| {
+                info!("resending packet with pid {}", pid.get());
+                let mut buf = [0u8; 1024];
+                buf[..packet.len()].copy_from_slice(packet);
+                self.queue
+                    .borrow_mut()
+                    .push_back((packet.len(), buf))
+                    .is_ok()
+            },
+            |pid| {
+                warn!("publish with pid {} not acknowledged, discarded", pid.get());
+                // there are at most MAX_IN_FLIGHT
+                expired.push(pid).ok();
+            },
+        );
+
+        if expired.is_empty() {
+            Ok(())
+        } else {
+            Err(TinyMqttError::NotAcknowledged(expired))
+        }
+    }
+
+    /// Writes all the queued packets to the transport
+    async fn send_internal(&mut self) -> Result<(), TinyMqttError> {
+        loop {
+            let dq = self.queue.borrow_mut().pop_front();
+            match dq {
+                Some((len, buffer)) => self
+                    .socket
+                    .write_all(&buffer[..len])
+                    .await
+                    .map_err(TinyMqttError::transport)?,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<'a, T> Mqtt for TinyMqtt<'a, T> {
+    fn send(&self, packet: mqttrust::Packet<'_>) -> Result<(), mqttrust::MqttError> {
+        let mut buf = [0u8; 1024];
+        let len = encode_slice(&packet, &mut buf).unwrap();
+
+        self.queue.borrow_mut().push_back((len, buf)).ok();
+        Ok(())
+    }
+
+    fn client_id(&self) -> &str {
+        self.client_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use common::mqtt::DUP_FLAG;
+    use core::convert::Infallible;
+    use embassy_futures::block_on;
+    use embedded_io::Io;
+    use std::collections::VecDeque;
+
+    /// Bytes returned by each read, so the packets arrive split
+    const READ_CHUNK: usize = 5;
+
+    std::thread_local! {
+        static NOW: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn now() -> u64 {
+        NOW.with(Cell::get)
+    }
+
+    fn advance(millis: u64) {
+        NOW.with(|now| now.set(now.get() + millis));
+    }
+
+    /// Transport in memory: the broker is scripted by the test, which queues
+    /// the bytes it sends and takes the ones written by the client
+    struct Pipe {
+        to_client: VecDeque<u8>,
+        from_client: std::vec::Vec<u8>,
+        open: bool,
+    }
+
+    impl Pipe {
+        fn new() -> Self {
+            Pipe {
+                to_client: VecDeque::new(),
+                from_client: std::vec::Vec::new(),
+                open: true,
+            }
+        }
+
+        fn broker_sends(&mut self, bytes: &[u8]) {
+            self.to_client.extend(bytes);
+        }
+
+        fn take_sent(&mut self) -> std::vec::Vec<u8> {
+            core::mem::take(&mut self.from_client)
+        }
+    }
+
+    impl Io for Pipe {
+        type Error = Infallible;
+    }
+
+    impl Read for Pipe {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let len = buf.len().min(self.to_client.len()).min(READ_CHUNK);
+            for (byte, received) in buf.iter_mut().zip(self.to_client.drain(..len)) {
+                *byte = received;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Pipe {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.from_client.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    impl Connection for Pipe {
+        fn can_recv(&self) -> bool {
+            !self.to_client.is_empty()
+        }
+
+        fn is_connected(&self) -> bool {
+            self.open
+        }
+
+        fn close(&mut self) {
+            self.open = false;
+        }
+    }
+
+    const CONNACK_ACCEPTED: [u8; 4] = [0x20, 0x02, 0x00, 0x00];
+
+    /// Client connected to the scripted broker
+    fn connected<'a>() -> TinyMqtt<'a, Pipe> {
+        let mut mqtt = TinyMqtt::new("esp32", Pipe::new(), now);
+        mqtt.socket.broker_sends(&CONNACK_ACCEPTED);
+        let session_present = block_on(mqtt.connect(60, Some("user"), None, 1_000)).unwrap();
+        assert!(!session_present);
+        assert!(mqtt.ready);
+        mqtt
+    }
+
+    fn puback(pid: Pid) -> [u8; 4] {
+        let pid: u16 = pid.into();
+        let pid = pid.to_be_bytes();
+        [0x40, 0x02, pid[0], pid[1]]
+    }
+
+    /// Acknowledgement of a QoS 1 or 2 flow, with its packet type
+    fn ack(packet_type: u8, pid: Pid) -> [u8; 4] {
+        let mut ack = puback(pid);
+        ack[0] = packet_type;
+        ack
+    }
+
+    #[test]
+    fn connect_waits_for_connack() {
+        let mut mqtt = connected();
+        let sent = mqtt.socket.take_sent();
+        match decode_slice(&sent) {
+            Ok(Some(Packet::Connect(connect))) => {
+                assert_eq!(connect.keep_alive, 60);
+                assert_eq!(connect.username, Some("user"));
+                assert!(connect.clean_session);
+            }
+            other => panic!("CONNECT expected, sent {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connect_refused() {
+        let mut mqtt = TinyMqtt::new("esp32", Pipe::new(), now);
+        // return code 4: bad user name or password
+        mqtt.socket.broker_sends(&[0x20, 0x02, 0x00, 0x04]);
+        let result = block_on(mqtt.connect(60, Some("user"), Some(b"bad"), 1_000));
+        assert!(matches!(result, Err(TinyMqttError::BadUsernamePassword)));
+        assert!(!mqtt.ready);
+    }
+
+    #[test]
+    fn publish_acknowledged() {
+        let mut mqtt = connected();
+        mqtt.socket.take_sent();
+
+        let pid = mqtt.next_pid();
+        mqtt.publish_with_pid(
+            Some(pid),
+            "/embsens/temperature",
+            b"21.5",
+            QoS::AtLeastOnce,
+            false,
+        )
+        .unwrap();
+        block_on(mqtt.poll()).unwrap();
+        let sent = mqtt.socket.take_sent();
+        match decode_slice(&sent) {
+            Ok(Some(Packet::Publish(publish))) => {
+                assert_eq!(publish.topic_name, "/embsens/temperature");
+                assert_eq!(publish.payload, b"21.5");
+                assert_eq!(publish.pid, Some(pid));
+                assert!(!publish.dup);
+            }
+            other => panic!("PUBLISH expected, sent {:?}", other),
+        }
+
+        mqtt.socket.broker_sends(&puback(pid));
+        block_on(mqtt.poll()).unwrap();
+        // acknowledged, it is not resent
+        advance(DEFAULT_RETRANSMIT_MILLIS);
+        block_on(mqtt.poll()).unwrap();
+        assert!(mqtt.socket.take_sent().is_empty());
+    }
+
+    #[test]
+    fn publish_resent_with_dup_until_given_up() {
+        let mut mqtt = connected();
+        mqtt.set_retransmit(100, 1);
+        mqtt.socket.take_sent();
+
+        let pid = mqtt.next_pid();
+        mqtt.publish_with_pid(
+            Some(pid),
+            "/embsens/temperature",
+            b"21.5",
+            QoS::AtLeastOnce,
+            false,
+        )
+        .unwrap();
+        block_on(mqtt.poll()).unwrap();
+        let first = mqtt.socket.take_sent();
+        assert_eq!(first[0] & DUP_FLAG, 0);
+
+        advance(100);
+        block_on(mqtt.poll()).unwrap();
+        let resent = mqtt.socket.take_sent();
+        assert_eq!(resent[0], first[0] | DUP_FLAG);
+        assert_eq!(resent[1..], first[1..]);
+
+        advance(100);
+        match block_on(mqtt.poll()) {
+            Err(TinyMqttError::NotAcknowledged(pids)) => assert_eq!(pids[..], [pid]),
+            other => panic!("NotAcknowledged expected, got {:?}", other),
+        }
+        // the connection is still usable
+        assert!(mqtt.ready);
+    }
+
+    #[test]
+    fn publishes_sent_as_new_after_reconnecting() {
+        let mut mqtt = connected();
+        mqtt.socket.take_sent();
+
+        let acked = mqtt.next_pid();
+        let released = mqtt.next_pid();
+        for (pid, qos) in [(acked, QoS::AtLeastOnce), (released, QoS::ExactlyOnce)] {
+            mqtt.publish_with_pid(Some(pid), "/embsens/temperature", b"21.5", qos, false)
+                .unwrap();
+        }
+        block_on(mqtt.poll()).unwrap();
+        mqtt.socket.take_sent();
+        // the server got the QoS 2 one, then the connection is lost
+        mqtt.socket.broker_sends(&ack(0x50, released));
+        block_on(mqtt.poll()).unwrap();
+        mqtt.socket.take_sent();
+
+        mqtt.socket.broker_sends(&CONNACK_ACCEPTED);
+        block_on(mqtt.connect(60, Some("user"), None, 1_000)).unwrap();
+        block_on(mqtt.poll()).unwrap();
+        let sent = mqtt.socket.take_sent();
+        // CONNECT, then only the QoS 1 publish, without DUP and no PUBREL
+        let connect_len = sent[1] as usize + 2;
+        let resent = &sent[connect_len..];
+        match decode_slice(resent) {
+            Ok(Some(Packet::Publish(publish))) => {
+                assert_eq!(publish.pid, Some(acked));
+                assert!(!publish.dup);
+                assert_eq!(resent.len(), 2 + resent[1] as usize);
+            }
+            other => panic!("PUBLISH expected, sent {:?}", other),
+        }
+    }
+
+    #[test]
+    fn publish_exactly_once() {
+        let mut mqtt = connected();
+        mqtt.set_retransmit(100, 3);
+        mqtt.socket.take_sent();
+
+        let pid = mqtt.next_pid();
+        mqtt.publish_with_pid(
+            Some(pid),
+            "/embsens/temperature",
+            b"21.5",
+            QoS::ExactlyOnce,
+            false,
+        )
+        .unwrap();
+        block_on(mqtt.poll()).unwrap();
+        match decode_slice(&mqtt.socket.take_sent()) {
+            Ok(Some(Packet::Publish(publish))) => {
+                assert_eq!(publish.qos, QoS::ExactlyOnce);
+                assert_eq!(publish.pid, Some(pid));
+            }
+            other => panic!("PUBLISH expected, sent {:?}", other),
+        }
+
+        // PUBREC: the client answers PUBREL
+        mqtt.socket.broker_sends(&ack(0x50, pid));
+        block_on(mqtt.poll()).unwrap();
+        block_on(mqtt.poll()).unwrap();
+        assert_eq!(mqtt.socket.take_sent(), ack(0x62, pid));
+
+        // the PUBREL, not the publish, is resent until PUBCOMP
+        advance(100);
+        block_on(mqtt.poll()).unwrap();
+        assert_eq!(mqtt.socket.take_sent(), ack(0x62, pid));
+        mqtt.socket.broker_sends(&ack(0x70, pid));
+        block_on(mqtt.poll()).unwrap();
+        advance(100);
+        block_on(mqtt.poll()).unwrap();
+        assert!(mqtt.socket.take_sent().is_empty());
+    }
+
+    #[test]
+    fn received_exactly_once() {
+        let received = core::cell::RefCell::new(std::vec::Vec::new());
+        let handler = |_: &str, payload: &[u8]| received.borrow_mut().push(payload.to_vec());
+        let mut mqtt = connected();
+        mqtt.add_route("/embsens/+", &handler).unwrap();
+        mqtt.socket.take_sent();
+
+        // PUBLISH QoS 2 to /embsens/command, pid 7, payload "reset"
+        let mut publish = std::vec![0x34, 0];
+        publish.extend_from_slice(&[0, 16]);
+        publish.extend_from_slice(b"/embsens/command");
+        publish.extend_from_slice(&[0, 7]);
+        publish.extend_from_slice(b"reset");
+        publish[1] = (publish.len() - 2) as u8;
+        let pid = Pid::try_from(7).unwrap();
+
+        mqtt.socket.broker_sends(&publish);
+        block_on(mqtt.poll()).unwrap();
+        block_on(mqtt.poll()).unwrap();
+        assert_eq!(mqtt.socket.take_sent(), ack(0x50, pid));
+
+        // resent with DUP before the PUBREL: acknowledged, not delivered
+        publish[0] |= DUP_FLAG;
+        mqtt.socket.broker_sends(&publish);
+        block_on(mqtt.poll()).unwrap();
+        block_on(mqtt.poll()).unwrap();
+        assert_eq!(mqtt.socket.take_sent(), ack(0x50, pid));
+        assert_eq!(received.borrow().len(), 1);
+
+        // PUBREL: PUBCOMP, and the pid can be used for a new publish
+        mqtt.socket.broker_sends(&ack(0x62, pid));
+        block_on(mqtt.poll()).unwrap();
+        block_on(mqtt.poll()).unwrap();
+        assert_eq!(mqtt.socket.take_sent(), ack(0x70, pid));
+        publish[0] &= !DUP_FLAG;
+        mqtt.socket.broker_sends(&publish);
+        block_on(mqtt.poll()).unwrap();
+        assert_eq!(
+            received.borrow()[..],
+            [b"reset".to_vec(), b"reset".to_vec()]
+        );
+    }
+
+    #[test]
+    fn received_publish_routed_and_acknowledged() {
+        let received = core::cell::RefCell::new(std::vec::Vec::new());
+        let handler = |topic: &str, payload: &[u8]| {
+            received
+                .borrow_mut()
+                .push((std::string::String::from(topic), payload.to_vec()));
+        };
+        let mut mqtt = connected();
+        mqtt.add_route("/embsens/+", &handler).unwrap();
+        mqtt.socket.take_sent();
+
+        // PUBLISH QoS 1 to /embsens/command, pid 9, payload "reset"
+        let mut publish = std::vec![0x32, 0];
+        publish.extend_from_slice(&[0, 16]);
+        publish.extend_from_slice(b"/embsens/command");
+        publish.extend_from_slice(&[0, 9]);
+        publish.extend_from_slice(b"reset");
+        publish[1] = (publish.len() - 2) as u8;
+        mqtt.socket.broker_sends(&publish);
+        block_on(mqtt.poll()).unwrap();
+        // the PUBACK queued while receiving goes out in the next poll
+        block_on(mqtt.poll()).unwrap();
+
+        assert_eq!(
+            received.borrow()[..],
+            [("/embsens/command".into(), b"reset".to_vec())]
+        );
+        assert_eq!(mqtt.socket.take_sent(), [0x40, 0x02, 0x00, 0x09]);
+    }
+}